@@ -0,0 +1,478 @@
+// アクセスログ（Common / Combined / JSON Lines）
+//
+// レスポンスボディの送信が終わった時点で1行書き出す。ファイル出力ではサイズまたは時間でローテーションする。
+
+use axum::{
+    body::Body,
+    extract::{Request, State},
+    http::{header, HeaderMap},
+    middleware::Next,
+    response::Response,
+};
+use hyper::body::{Body as HttpBody, Bytes, Frame, SizeHint};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{self, File, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::{mpsc, Arc, Mutex},
+    task::{Context, Poll},
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+use tracing::warn;
+
+use crate::{logging::X_REQUEST_ID, redact, AppState};
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct AccessLogConfig {
+    #[serde(default)]
+    pub format: AccessLogFormat,
+    // 出力先ファイル（省略時は標準出力）
+    #[serde(default)]
+    pub path: Option<String>,
+    // このサイズ（MB）を超えたらローテーション
+    #[serde(default)]
+    pub max_size_mb: Option<u64>,
+    // 時間でのローテーション
+    #[serde(default)]
+    pub rotate: RotatePeriod,
+    // 残しておくローテーション済みファイルの数
+    #[serde(default = "default_max_files")]
+    pub max_files: usize,
+}
+
+fn default_max_files() -> usize {
+    7
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum AccessLogFormat {
+    Common,
+    #[default]
+    Combined,
+    Json,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum RotatePeriod {
+    #[default]
+    Never,
+    Hourly,
+    Daily,
+}
+
+impl RotatePeriod {
+    // 現在時刻が属する期間の番号（変わったらローテーション）
+    fn current(self) -> Option<u64> {
+        let secs = unix_now();
+        match self {
+            RotatePeriod::Never => None,
+            RotatePeriod::Hourly => Some(secs / 3600),
+            RotatePeriod::Daily => Some(secs / 86400),
+        }
+    }
+}
+
+// ハンドラーが記録する転送先の情報
+#[derive(Debug, Default, Clone)]
+struct UpstreamInfo {
+    target: Option<String>,
+    upstream_uri: Option<String>,
+    rewrite: Option<&'static str>,
+}
+
+// リクエストの拡張に載せて、ハンドラーから転送先や書き換えの有無を書き込んでもらう
+// （アクセスログとメトリクスで共有する）
+#[derive(Clone, Default)]
+pub struct AccessNote(Arc<Mutex<UpstreamInfo>>);
+
+impl AccessNote {
+    // すでに付与されていればそれを使い、なければ新しく付与する
+    pub fn attach(req: &mut Request) -> AccessNote {
+        if let Some(note) = req.extensions().get::<AccessNote>() {
+            return note.clone();
+        }
+        let note = AccessNote::default();
+        req.extensions_mut().insert(note.clone());
+        note
+    }
+
+    pub fn from_request(req: &Request) -> AccessNote {
+        req.extensions().get::<AccessNote>().cloned().unwrap_or_default()
+    }
+
+    pub fn upstream(&self, target: &str, upstream_uri: &str) {
+        let mut info = self.0.lock().unwrap();
+        info.target = Some(target.to_string());
+        info.upstream_uri = Some(redact::get().text(upstream_uri).into_owned());
+    }
+
+    // 書き換えた種類（html / css / js）を記録する
+    pub fn rewrite(&self, kind: &'static str) {
+        self.0.lock().unwrap().rewrite = Some(kind);
+    }
+
+    pub fn target(&self) -> Option<String> {
+        self.0.lock().unwrap().target.clone()
+    }
+
+    fn snapshot(&self) -> UpstreamInfo {
+        self.0.lock().unwrap().clone()
+    }
+}
+
+pub struct AccessLog {
+    format: AccessLogFormat,
+    sender: mpsc::Sender<String>,
+}
+
+impl AccessLog {
+    // 書き込み用のスレッドを起動する
+    pub fn start(config: &AccessLogConfig) -> Result<AccessLog, String> {
+        let mut sink: Box<dyn FnMut(&str) + Send> = match &config.path {
+            Some(path) => {
+                let mut file = RotatingFile::open(
+                    PathBuf::from(path),
+                    config.max_size_mb.map(|mb| mb * 1024 * 1024),
+                    config.rotate,
+                    config.max_files,
+                )
+                .map_err(|e| format!("{}: {}", path, e))?;
+                Box::new(move |line| {
+                    if let Err(err) = file.write_line(line) {
+                        warn!("アクセスログの書き込みに失敗しました: {}", err);
+                    }
+                })
+            }
+            None => Box::new(|line| println!("{}", line)),
+        };
+
+        let (sender, receiver) = mpsc::channel::<String>();
+        std::thread::spawn(move || {
+            for line in receiver {
+                sink(&line);
+            }
+        });
+
+        Ok(AccessLog {
+            format: config.format,
+            sender,
+        })
+    }
+
+    fn write(&self, entry: &Entry) {
+        let line = match self.format {
+            AccessLogFormat::Common => entry.common(),
+            AccessLogFormat::Combined => entry.combined(),
+            AccessLogFormat::Json => serde_json::to_string(entry).unwrap_or_default(),
+        };
+        let _ = self.sender.send(line);
+    }
+}
+
+// アクセスログ1行分
+#[derive(Serialize)]
+struct Entry {
+    time: String,
+    #[serde(skip)]
+    clf_time: String,
+    request_id: Option<String>,
+    client: String,
+    method: String,
+    uri: String,
+    protocol: String,
+    status: u16,
+    bytes: u64,
+    // レスポンスヘッダーまでの時間
+    latency_ms: u64,
+    // ボディ送信完了までの時間
+    duration_ms: u64,
+    target: Option<String>,
+    upstream_uri: Option<String>,
+    rewrite_applied: bool,
+    rewrite: Option<&'static str>,
+    referer: Option<String>,
+    user_agent: Option<String>,
+}
+
+impl Entry {
+    fn common(&self) -> String {
+        format!(
+            "{} - - [{}] \"{} {} {}\" {} {}",
+            self.client,
+            self.clf_time,
+            self.method,
+            self.uri,
+            self.protocol,
+            self.status,
+            if self.bytes == 0 { "-".to_string() } else { self.bytes.to_string() }
+        )
+    }
+
+    fn combined(&self) -> String {
+        format!(
+            "{} \"{}\" \"{}\"",
+            self.common(),
+            self.referer.as_deref().unwrap_or("-").replace('"', "\\\""),
+            self.user_agent.as_deref().unwrap_or("-").replace('"', "\\\"")
+        )
+    }
+}
+
+// アクセスログを記録するミドルウェア
+pub async fn middleware(State(state): State<AppState>, mut req: Request, next: Next) -> Response {
+    let Some(access_log) = state.access_log.clone() else {
+        return next.run(req).await;
+    };
+
+    let note = AccessNote::attach(&mut req);
+
+    let started = Instant::now();
+    let header_str = |headers: &HeaderMap, name| {
+        headers
+            .get(name)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.to_string())
+    };
+    let client = state
+        .forwarding
+        .client_ip(&req)
+        .map(|ip| ip.to_string())
+        .unwrap_or_else(|| "-".to_string());
+    let (clf_time, time) = format_times(SystemTime::now());
+    let method = req.method().to_string();
+    let redactor = redact::get();
+    let uri = redactor.text(&req.uri().to_string()).into_owned();
+    let protocol = format!("{:?}", req.version());
+    let request_id = header_str(req.headers(), X_REQUEST_ID);
+    let referer = header_str(req.headers(), header::REFERER).map(|r| redactor.text(&r).into_owned());
+    let user_agent = header_str(req.headers(), header::USER_AGENT);
+
+    let response = next.run(req).await;
+    let latency_ms = started.elapsed().as_millis() as u64;
+    let status = response.status().as_u16();
+
+    let entry = Entry {
+        time,
+        clf_time,
+        request_id,
+        client,
+        method,
+        uri,
+        protocol,
+        status,
+        bytes: 0,
+        latency_ms,
+        duration_ms: 0,
+        target: None,
+        upstream_uri: None,
+        rewrite_applied: false,
+        rewrite: None,
+        referer,
+        user_agent,
+    };
+
+    response.map(|body| {
+        Body::new(LoggedBody {
+            inner: body,
+            bytes: 0,
+            started,
+            pending: Some((entry, note, access_log)),
+        })
+    })
+}
+
+// 送信したバイト数を数え、ボディが破棄されたときにログを書き出すボディ
+struct LoggedBody {
+    inner: Body,
+    bytes: u64,
+    started: Instant,
+    pending: Option<(Entry, AccessNote, Arc<AccessLog>)>,
+}
+
+impl HttpBody for LoggedBody {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let poll = Pin::new(&mut self.inner).poll_frame(cx);
+        if let Poll::Ready(Some(Ok(frame))) = &poll {
+            if let Some(data) = frame.data_ref() {
+                self.bytes += data.len() as u64;
+            }
+        }
+        poll
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+impl Drop for LoggedBody {
+    fn drop(&mut self) {
+        let Some((mut entry, note, access_log)) = self.pending.take() else {
+            return;
+        };
+        let info = note.snapshot();
+        entry.bytes = self.bytes;
+        entry.duration_ms = self.started.elapsed().as_millis() as u64;
+        entry.target = info.target;
+        entry.upstream_uri = info.upstream_uri;
+        entry.rewrite_applied = info.rewrite.is_some();
+        entry.rewrite = info.rewrite;
+        access_log.write(&entry);
+    }
+}
+
+// サイズまたは時間でローテーションするファイル
+struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    max_size: Option<u64>,
+    rotate: RotatePeriod,
+    period: Option<u64>,
+    max_files: usize,
+}
+
+impl RotatingFile {
+    fn open(path: PathBuf, max_size: Option<u64>, rotate: RotatePeriod, max_files: usize) -> std::io::Result<RotatingFile> {
+        if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+        Ok(RotatingFile {
+            path,
+            file,
+            size,
+            max_size,
+            rotate,
+            period: rotate.current(),
+            max_files,
+        })
+    }
+
+    fn write_line(&mut self, line: &str) -> std::io::Result<()> {
+        let size_exceeded = self
+            .max_size
+            .is_some_and(|max| self.size > 0 && self.size + line.len() as u64 + 1 > max);
+        let period_changed = self.rotate.current() != self.period;
+        if size_exceeded || period_changed {
+            self.rotate_file()?;
+        }
+        writeln!(self.file, "{}", line)?;
+        self.size += line.len() as u64 + 1;
+        Ok(())
+    }
+
+    // 現在のファイルを日時付きの名前に変えて新しいファイルを開く
+    fn rotate_file(&mut self) -> std::io::Result<()> {
+        let (_, stamp) = format_times(SystemTime::now());
+        let stamp: String = stamp.chars().filter(|c| c.is_ascii_digit()).take(14).collect();
+        let mut rotated = rotated_name(&self.path, &stamp);
+        let mut n = 1;
+        while rotated.exists() {
+            rotated = rotated_name(&self.path, &format!("{}-{}", stamp, n));
+            n += 1;
+        }
+        fs::rename(&self.path, &rotated)?;
+        self.file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        self.size = 0;
+        self.period = self.rotate.current();
+        self.prune();
+        Ok(())
+    }
+
+    // 古いローテーション済みファイルを削除する
+    fn prune(&self) {
+        let (Some(dir), Some(name)) = (self.path.parent(), self.path.file_name()) else {
+            return;
+        };
+        let dir = if dir.as_os_str().is_empty() { Path::new(".") } else { dir };
+        let prefix = format!("{}.", name.to_string_lossy());
+        let Ok(entries) = fs::read_dir(dir) else {
+            return;
+        };
+        let mut rotated: Vec<PathBuf> = entries
+            .filter_map(|e| e.ok())
+            .map(|e| e.path())
+            .filter(|p| {
+                p.file_name()
+                    .is_some_and(|n| n.to_string_lossy().starts_with(&prefix))
+            })
+            .collect();
+        rotated.sort();
+        while rotated.len() > self.max_files {
+            let _ = fs::remove_file(rotated.remove(0));
+        }
+    }
+}
+
+fn rotated_name(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".");
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+// Common Log Format用（10/Oct/2000:13:55:36 +0000）とRFC 3339（UTC）の時刻文字列
+pub fn format_times(time: SystemTime) -> (String, String) {
+    const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+    let duration = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = duration.as_secs();
+    let (year, month, day) = civil_from_days((secs / 86400) as i64);
+    let (hour, minute, second) = ((secs % 86400) / 3600, (secs % 3600) / 60, secs % 60);
+    let clf = format!(
+        "{:02}/{}/{}:{:02}:{:02}:{:02} +0000",
+        day,
+        MONTHS[(month - 1) as usize],
+        year,
+        hour,
+        minute,
+        second
+    );
+    let rfc3339 = format!(
+        "{}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        hour,
+        minute,
+        second,
+        duration.subsec_millis()
+    );
+    (clf, rfc3339)
+}
+
+// 1970-01-01からの日数を年月日に変換する（Howard Hinnantのアルゴリズム）
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
@@ -0,0 +1,597 @@
+// LANに公開するときの認証（Basic認証、共有リンクでのログイン、接続元アドレスの制限）
+//
+// [auth] が全体の設定、[targets.auth] はそのターゲットへ転送するリクエストだけ項目ごとに上書きする。
+// ループバックからのアクセスは既定でログイン不要（trusted で変更できる）。
+
+use axum::{
+    extract::{Query, Request, State},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Redirect, Response},
+};
+use ring::{digest, hmac, pbkdf2, rand::{SecureRandom, SystemRandom}};
+use serde::Deserialize;
+use std::{
+    collections::{HashMap, HashSet},
+    io::{BufRead, IsTerminal},
+    net::IpAddr,
+    num::NonZeroU32,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tracing::{info, warn};
+
+use crate::{forwarded, har, routes, AppState, Config};
+
+const HASH_PREFIX: &str = "pbkdf2-sha256";
+const HASH_ITERATIONS: u32 = 100_000;
+const SESSION_COOKIE_PREFIX: &str = "portrooter_session_";
+
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct AuthConfig {
+    // ターゲットで false にすると認証なしで公開する
+    #[serde(default)]
+    pub enabled: Option<bool>,
+    // Basic認証のユーザー（password_hash は --hash-password で作る）
+    #[serde(default)]
+    pub users: Option<Vec<UserConfig>>,
+    // 共有リンク（/__portrooter/login?token=...）のシークレット
+    #[serde(default)]
+    pub login_token: Option<String>,
+    // 共有リンクでログインしたセッションの有効期間（時間）
+    #[serde(default)]
+    pub session_hours: Option<u64>,
+    // 接続を許可するアドレス（CIDR、省略時はすべて）
+    #[serde(default)]
+    pub allow: Option<Vec<String>>,
+    // ログインなしで通すアドレス（CIDR、省略時はループバック）
+    #[serde(default)]
+    pub trusted: Option<Vec<String>>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct UserConfig {
+    pub name: String,
+    pub password_hash: String,
+}
+
+impl AuthConfig {
+    // ターゲットの設定で全体の設定を上書きする
+    fn merged(&self, overrides: &AuthConfig) -> AuthConfig {
+        AuthConfig {
+            enabled: overrides.enabled.or(self.enabled),
+            users: overrides.users.clone().or_else(|| self.users.clone()),
+            login_token: overrides.login_token.clone().or_else(|| self.login_token.clone()),
+            session_hours: overrides.session_hours.or(self.session_hours),
+            allow: overrides.allow.clone().or_else(|| self.allow.clone()),
+            trusted: overrides.trusted.clone().or_else(|| self.trusted.clone()),
+        }
+    }
+}
+
+// CIDR表記のアドレス範囲
+#[derive(Debug, Clone, Copy)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn parse(text: &str) -> Result<Cidr, String> {
+        let (addr, prefix) = match text.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (text, None),
+        };
+        let addr: IpAddr = addr
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .parse()
+            .map_err(|_| format!("不正なアドレスです: {}", text))?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .parse::<u8>()
+                .ok()
+                .filter(|p| *p <= max)
+                .ok_or_else(|| format!("不正なプレフィックス長です: {}", text))?,
+            None => max,
+        };
+        Ok(Cidr { addr, prefix })
+    }
+
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        // IPv4射影アドレス（::ffff:192.168.0.1）はIPv4として比べる
+        match (self.addr, addr.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(addr)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(addr) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(addr)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(addr) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+pub fn parse_cidrs(list: &[String]) -> Result<Vec<Cidr>, String> {
+    list.iter().map(|c| Cidr::parse(c)).collect()
+}
+
+// 全体またはターゲットごとの認証ポリシー
+#[derive(Debug)]
+struct Policy {
+    users: Vec<User>,
+    login_token: Option<String>,
+    session: Duration,
+    allow: Option<Vec<Cidr>>,
+    trusted: Vec<Cidr>,
+    // 検証済みの Authorization ヘッダー（PBKDF2 を毎回計算しないように SHA-256 で覚えておく）
+    verified: Mutex<HashSet<Vec<u8>>>,
+}
+
+#[derive(Debug)]
+struct User {
+    name: String,
+    iterations: u32,
+    salt: Vec<u8>,
+    hash: Vec<u8>,
+}
+
+enum Decision {
+    Allow,
+    Forbidden,
+    Unauthorized,
+}
+
+impl Policy {
+    fn compile(config: &AuthConfig) -> Result<Option<Policy>, String> {
+        if config.enabled == Some(false) {
+            return Ok(None);
+        }
+        let users = config
+            .users
+            .iter()
+            .flatten()
+            .map(|user| {
+                parse_hash(&user.password_hash)
+                    .map(|(iterations, salt, hash)| User {
+                        name: user.name.clone(),
+                        iterations,
+                        salt,
+                        hash,
+                    })
+                    .map_err(|e| format!("ユーザー {}: {}", user.name, e))
+            })
+            .collect::<Result<_, String>>()?;
+        let login_token = config.login_token.clone().filter(|t| !t.is_empty());
+        if login_token.as_ref().is_some_and(|t| t.len() < 16) {
+            return Err("login_token は16文字以上にしてください".to_string());
+        }
+        let trusted = match &config.trusted {
+            Some(list) => parse_cidrs(list)?,
+            None => vec![Cidr::parse("127.0.0.0/8")?, Cidr::parse("::1")?],
+        };
+        Ok(Some(Policy {
+            users,
+            login_token,
+            session: Duration::from_secs(config.session_hours.unwrap_or(24 * 7) * 3600),
+            allow: config.allow.as_deref().map(parse_cidrs).transpose()?,
+            trusted,
+            verified: Mutex::new(HashSet::new()),
+        }))
+    }
+
+    fn requires_login(&self) -> bool {
+        !self.users.is_empty() || self.login_token.is_some()
+    }
+
+    fn check(&self, peer: Option<IpAddr>, headers: &HeaderMap) -> Decision {
+        if let Some(allow) = &self.allow {
+            if !peer.is_some_and(|ip| allow.iter().any(|c| c.contains(ip))) {
+                return Decision::Forbidden;
+            }
+        }
+        if !self.requires_login() || peer.is_some_and(|ip| self.trusted.iter().any(|c| c.contains(ip))) {
+            return Decision::Allow;
+        }
+        if self.check_basic(headers) || self.check_session(headers) {
+            Decision::Allow
+        } else {
+            Decision::Unauthorized
+        }
+    }
+
+    fn check_basic(&self, headers: &HeaderMap) -> bool {
+        let Some(value) = basic_credentials(headers) else {
+            return false;
+        };
+        let fingerprint = digest::digest(&digest::SHA256, value.as_bytes()).as_ref().to_vec();
+        if self.verified.lock().unwrap().contains(&fingerprint) {
+            return true;
+        }
+        let Some((name, password)) = har::base64_decode(value)
+            .and_then(|decoded| String::from_utf8(decoded).ok())
+            .and_then(|decoded| decoded.split_once(':').map(|(n, p)| (n.to_string(), p.to_string())))
+        else {
+            return false;
+        };
+        let ok = self.users.iter().any(|user| {
+            user.name == name
+                && pbkdf2::verify(
+                    pbkdf2::PBKDF2_HMAC_SHA256,
+                    NonZeroU32::new(user.iterations).unwrap_or(NonZeroU32::MIN),
+                    &user.salt,
+                    password.as_bytes(),
+                    &user.hash,
+                )
+                .is_ok()
+        });
+        if ok {
+            self.verified.lock().unwrap().insert(fingerprint);
+        } else {
+            warn!("Basic認証に失敗しました: ユーザー {}", name);
+        }
+        ok
+    }
+
+    fn check_session(&self, headers: &HeaderMap) -> bool {
+        let Some(token) = &self.login_token else {
+            return false;
+        };
+        let name = session_cookie_name(token);
+        cookies(headers)
+            .filter(|(n, _)| *n == name)
+            .any(|(_, value)| verify_session(token, value))
+    }
+}
+
+fn basic_credentials(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Basic ").or_else(|| v.strip_prefix("basic ")))
+        .map(str::trim)
+}
+
+fn cookies(headers: &HeaderMap) -> impl Iterator<Item = (&str, &str)> {
+    headers
+        .get_all(header::COOKIE)
+        .into_iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(';'))
+        .filter_map(|pair| pair.trim().split_once('='))
+}
+
+// 共有リンクのシークレットごとにクッキー名を分ける（ターゲットごとに別のシークレットを使えるように）
+fn session_cookie_name(token: &str) -> String {
+    let digest = digest::digest(&digest::SHA256, token.as_bytes());
+    format!("{}{}", SESSION_COOKIE_PREFIX, hex(&digest.as_ref()[..4]))
+}
+
+fn session_key(token: &str) -> hmac::Key {
+    hmac::Key::new(hmac::HMAC_SHA256, format!("portrooter-session:{}", token).as_bytes())
+}
+
+// 有効期限（UNIX秒）とその署名。シークレットを変えると既存のセッションは無効になる
+fn new_session(token: &str, session: Duration) -> String {
+    let expires = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .saturating_add(session)
+        .as_secs()
+        .to_string();
+    let tag = hmac::sign(&session_key(token), expires.as_bytes());
+    format!("{}.{}", expires, hex(tag.as_ref()))
+}
+
+fn verify_session(token: &str, value: &str) -> bool {
+    let Some((expires, tag)) = value.split_once('.') else {
+        return false;
+    };
+    let Some(tag) = unhex(tag) else {
+        return false;
+    };
+    if hmac::verify(&session_key(token), expires.as_bytes(), &tag).is_err() {
+        return false;
+    }
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    expires.parse::<u64>().is_ok_and(|expires| expires > now)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn unhex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+// pbkdf2-sha256$反復回数$ソルト$ハッシュ（ソルトとハッシュはBase64）
+fn parse_hash(text: &str) -> Result<(u32, Vec<u8>, Vec<u8>), String> {
+    let parts: Vec<&str> = text.split('$').collect();
+    let [HASH_PREFIX, iterations, salt, hash] = parts.as_slice() else {
+        return Err(format!("password_hash は {}$反復回数$ソルト$ハッシュ の形式にしてください（--hash-password で作れます）", HASH_PREFIX));
+    };
+    let iterations = iterations
+        .parse::<u32>()
+        .ok()
+        .filter(|i| *i > 0)
+        .ok_or_else(|| format!("不正な反復回数です: {}", iterations))?;
+    let salt = har::base64_decode(salt).ok_or("ソルトがBase64ではありません")?;
+    let hash = har::base64_decode(hash).ok_or("ハッシュがBase64ではありません")?;
+    Ok((iterations, salt, hash))
+}
+
+// --hash-password: 標準入力のパスワードから password_hash を作って表示する
+pub fn print_password_hash() {
+    eprintln!("パスワードを入力してください:");
+    let mut password = String::new();
+    if std::io::stdin().lock().read_line(&mut password).is_err() {
+        eprintln!("パスワードを読み込めませんでした");
+        std::process::exit(1);
+    }
+    let password = password.trim_end_matches(['\r', '\n']);
+    let mut salt = [0u8; 16];
+    SystemRandom::new().fill(&mut salt).expect("乱数を生成できませんでした");
+    let mut hash = [0u8; digest::SHA256_OUTPUT_LEN];
+    pbkdf2::derive(
+        pbkdf2::PBKDF2_HMAC_SHA256,
+        NonZeroU32::new(HASH_ITERATIONS).unwrap(),
+        &salt,
+        password.as_bytes(),
+        &mut hash,
+    );
+    println!(
+        "{}${}${}${}",
+        HASH_PREFIX,
+        HASH_ITERATIONS,
+        har::base64_encode(&salt),
+        har::base64_encode(&hash)
+    );
+}
+
+// 全体とターゲットごとのポリシー
+#[derive(Debug, Default)]
+pub struct Auth {
+    global: Option<Arc<Policy>>,
+    // 上書きのあるターゲット（None は認証なし）
+    targets: HashMap<String, Option<Arc<Policy>>>,
+}
+
+impl Auth {
+    pub fn is_enabled(&self) -> bool {
+        self.global.is_some() || self.targets.values().any(Option::is_some)
+    }
+
+    fn policy_for(&self, target: Option<&str>) -> Option<&Policy> {
+        match target.and_then(|t| self.targets.get(t)) {
+            Some(policy) => policy.as_deref(),
+            None => self.global.as_deref(),
+        }
+    }
+
+    // 選択画面と管理画面（/__portrooter/*）はすべてのターゲットの情報を扱うので、
+    // 全体の設定がなければ、ターゲットごとの設定のどれかを満たしたときだけ通す
+    fn check_admin(&self, peer: Option<IpAddr>, headers: &HeaderMap) -> Option<(Decision, &Policy)> {
+        if let Some(global) = self.global.as_deref() {
+            return Some((global.check(peer, headers), global));
+        }
+        let mut policies: Vec<(&String, &Policy)> = self
+            .targets
+            .iter()
+            .filter_map(|(name, policy)| Some((name, policy.as_deref()?)))
+            .collect();
+        policies.sort_by_key(|(name, _)| *name);
+        // どれも満たさなければ、ログインを求められるポリシーを優先して返す
+        let mut denied = None;
+        for (_, policy) in policies {
+            match policy.check(peer, headers) {
+                Decision::Allow => return Some((Decision::Allow, policy)),
+                Decision::Unauthorized => {
+                    if !matches!(denied, Some((Decision::Unauthorized, _))) {
+                        denied = Some((Decision::Unauthorized, policy));
+                    }
+                }
+                Decision::Forbidden => {
+                    if denied.is_none() {
+                        denied = Some((Decision::Forbidden, policy));
+                    }
+                }
+            }
+        }
+        denied
+    }
+
+    fn policy_with_token(&self, token: &str) -> Option<&Policy> {
+        self.global
+            .iter()
+            .chain(self.targets.values().flatten())
+            .map(Arc::as_ref)
+            .find(|policy| policy.login_token.as_deref().is_some_and(|t| constant_time_eq(t.as_bytes(), token.as_bytes())))
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+// 起動時にすべての設定を検証して組み立てる
+pub fn compile(config: &Config) -> Result<Auth, String> {
+    let global_config = config.auth.clone();
+    let global = match &global_config {
+        Some(auth) => Policy::compile(auth)?.map(Arc::new),
+        None => None,
+    };
+    let mut targets = HashMap::new();
+    for target in config.targets.iter().filter(|t| t.is_http()) {
+        let Some(overrides) = &target.auth else {
+            continue;
+        };
+        let merged = global_config.clone().unwrap_or_default().merged(overrides);
+        let policy = Policy::compile(&merged).map_err(|e| format!("{}: {}", target.name, e))?;
+        targets.insert(target.name.clone(), policy.map(Arc::new));
+    }
+    let auth = Auth { global, targets };
+    let tokens: HashSet<&String> = auth
+        .global
+        .iter()
+        .chain(auth.targets.values().flatten())
+        .filter_map(|policy| policy.login_token.as_ref())
+        .collect();
+    // ログでは token が伏せ字になるので、リンクは端末にだけ直接表示する（ファイルやジャーナルには残さない）
+    if std::io::stderr().is_terminal() {
+        for token in tokens {
+            eprintln!(
+                "共有リンクでログイン: http://<このマシンのアドレス>:{}/__portrooter/login?token={}",
+                config.router_port,
+                urlencoding::encode(token)
+            );
+        }
+    } else if !tokens.is_empty() {
+        info!(
+            "共有リンクでログイン: http://<このマシンのアドレス>:{}/__portrooter/login?token=<config.toml の login_token>",
+            config.router_port
+        );
+    }
+    Ok(auth)
+}
+
+// 選択画面と管理画面（インスペクター・障害注入・ミラーリングや再生のレポートなど）
+fn is_admin_path(path: &str) -> bool {
+    path == "/" || path.starts_with("/__portrooter/")
+}
+
+// リクエストの転送先ターゲット（ターゲットごとの設定を選ぶため、各ハンドラーと同じ判定をする）
+pub fn target_of(state: &AppState, req: &Request) -> Option<String> {
+    let path = req.uri().path();
+    if let Some(rest) = path.strip_prefix("/proxy/") {
+        let name = rest.split('/').next().unwrap_or("");
+        return urlencoding::decode(name).ok().map(|n| n.into_owned());
+    }
+    if is_admin_path(path) {
+        return None;
+    }
+    if let Some(target) = routes::target_for(&state.routes, req) {
+        return Some(target.to_string());
+    }
+    crate::fallback_target_name(&state.config, req.headers())
+}
+
+// 選択画面・プロキシ・フォールバック・管理画面の前で認証するミドルウェア
+pub async fn middleware(State(state): State<AppState>, mut req: Request, next: Next) -> Response {
+    if !state.auth.is_enabled() || req.uri().path() == "/__portrooter/login" {
+        return next.run(req).await;
+    }
+    // Unix ドメインソケットの接続元は分からないので、ループバックとしても trusted / allow としても扱わない
+    let peer = match req.extensions().get::<forwarded::UnixPeer>() {
+        Some(_) => None,
+        None => state.forwarding.client_ip(&req),
+    };
+    let checked = if is_admin_path(req.uri().path()) {
+        state.auth.check_admin(peer, req.headers())
+    } else {
+        let target = target_of(&state, &req);
+        state
+            .auth
+            .policy_for(target.as_deref())
+            .map(|policy| (policy.check(peer, req.headers()), policy))
+    };
+    let Some((decision, policy)) = checked else {
+        return next.run(req).await;
+    };
+    match decision {
+        Decision::Allow => {
+            strip_credentials(policy, req.headers_mut());
+            next.run(req).await
+        }
+        Decision::Forbidden => {
+            warn!("許可されていないアドレスからのアクセス: {:?} {}", peer, req.uri());
+            (
+                StatusCode::FORBIDDEN,
+                format!(
+                    "このアドレス（{}）からのアクセスは許可されていません",
+                    peer.map(|ip| ip.to_string()).unwrap_or_else(|| "不明".to_string())
+                ),
+            )
+                .into_response()
+        }
+        Decision::Unauthorized => unauthorized(policy),
+    }
+}
+
+fn unauthorized(policy: &Policy) -> Response {
+    let mut message = String::from("PortRooter へのアクセスにはログインが必要です。\n");
+    if policy.login_token.is_some() {
+        message.push_str("共有されたログイン用リンク（/__portrooter/login?token=...）を開いてください。\n");
+    }
+    let mut response = (StatusCode::UNAUTHORIZED, message).into_response();
+    if !policy.users.is_empty() {
+        response.headers_mut().insert(
+            header::WWW_AUTHENTICATE,
+            HeaderValue::from_static("Basic realm=\"PortRooter\", charset=\"UTF-8\""),
+        );
+    }
+    response
+}
+
+// PortRooter 用の資格情報はバックエンドへ送らない
+fn strip_credentials(policy: &Policy, headers: &mut HeaderMap) {
+    if !policy.users.is_empty() && basic_credentials(headers).is_some() {
+        headers.remove(header::AUTHORIZATION);
+    }
+    let has_session = cookies(headers).any(|(name, _)| name.starts_with(SESSION_COOKIE_PREFIX));
+    if !has_session {
+        return;
+    }
+    let rest: Vec<String> = cookies(headers)
+        .filter(|(name, _)| !name.starts_with(SESSION_COOKIE_PREFIX))
+        .map(|(name, value)| format!("{}={}", name, value))
+        .collect();
+    headers.remove(header::COOKIE);
+    if let Ok(value) = HeaderValue::try_from(rest.join("; ")) {
+        if !rest.is_empty() {
+            headers.insert(header::COOKIE, value);
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LoginQuery {
+    token: String,
+    // ログイン後に移動するパス
+    #[serde(default)]
+    next: Option<String>,
+}
+
+// /__portrooter/login?token=...: 共有リンクのシークレットが正しければセッションクッキーを発行する
+pub async fn login_handler(State(state): State<AppState>, Query(query): Query<LoginQuery>) -> Response {
+    let Some(policy) = state.auth.policy_with_token(&query.token) else {
+        warn!("共有リンクのシークレットが一致しません");
+        return (StatusCode::FORBIDDEN, "ログイン用リンクが正しくありません").into_response();
+    };
+    let token = policy.login_token.as_deref().unwrap_or_default();
+    let cookie = format!(
+        "{}={}; Path=/; Max-Age={}; HttpOnly; SameSite=Lax",
+        session_cookie_name(token),
+        new_session(token, policy.session),
+        policy.session.as_secs()
+    );
+    // 外部サイトへのリダイレクトには使わせない
+    let next = query
+        .next
+        .filter(|n| n.starts_with('/') && !n.starts_with("//") && !n.starts_with("/\\"))
+        .unwrap_or_else(|| "/".to_string());
+    let mut response = Redirect::to(&next).into_response();
+    if let Ok(cookie) = HeaderValue::try_from(cookie) {
+        response.headers_mut().insert(header::SET_COOKIE, cookie);
+    }
+    response
+}
@@ -0,0 +1,233 @@
+// ターゲットごとのCORSポリシー
+//
+// [targets.cors] を設定したターゲットは、プリフライト（OPTIONS）にバックエンドへ転送せずに答え、
+// 通常のレスポンスの Access-Control-* ヘッダーをポリシーの値に置き換える。
+// Origin ヘッダーはそのまま転送する（spoof_origin = true のターゲットだけバックエンドのオリジンに書き換える）。
+
+use axum::{
+    extract::{Request, State},
+    http::{header, HeaderMap, HeaderName, HeaderValue, Method, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use regex::Regex;
+use serde::Deserialize;
+use std::collections::HashMap;
+use tracing::{debug, warn};
+
+use crate::{auth, routes, AppState, Config};
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct CorsConfig {
+    // 許可するオリジン（"*" ですべて、"http://*.localhost:3015" のように * も使える）
+    pub allow_origins: Vec<String>,
+    // Cookie などの資格情報付きのリクエストを許可する
+    #[serde(default)]
+    pub allow_credentials: bool,
+    // 許可するメソッド（省略時はプリフライトで要求されたメソッド）
+    #[serde(default)]
+    pub allow_methods: Vec<String>,
+    // 許可するリクエストヘッダー（省略時はプリフライトで要求されたヘッダー）
+    #[serde(default)]
+    pub allow_headers: Vec<String>,
+    // JavaScriptから読めるようにするレスポンスヘッダー
+    #[serde(default)]
+    pub expose_headers: Vec<String>,
+    // プリフライトの結果をブラウザがキャッシュする秒数
+    #[serde(default = "default_max_age_secs")]
+    pub max_age_secs: u64,
+}
+
+fn default_max_age_secs() -> u64 {
+    600
+}
+
+#[derive(Debug)]
+enum OriginMatcher {
+    Any,
+    Exact(String),
+    Pattern(Regex),
+}
+
+#[derive(Debug)]
+pub struct Policy {
+    origins: Vec<OriginMatcher>,
+    credentials: bool,
+    methods: Option<HeaderValue>,
+    headers: Option<HeaderValue>,
+    expose: Option<HeaderValue>,
+    max_age: HeaderValue,
+}
+
+impl Policy {
+    fn compile(config: &CorsConfig) -> Result<Policy, String> {
+        if config.allow_origins.is_empty() {
+            return Err("allow_origins を指定してください".to_string());
+        }
+        let origins = config
+            .allow_origins
+            .iter()
+            .map(|origin| {
+                let origin = origin.trim_end_matches('/');
+                Ok(if origin == "*" {
+                    OriginMatcher::Any
+                } else if origin.contains('*') {
+                    let pattern = format!("(?i){}$", routes::glob_to_regex(origin));
+                    OriginMatcher::Pattern(Regex::new(&pattern).map_err(|e| format!("{}: {}", origin, e))?)
+                } else {
+                    OriginMatcher::Exact(origin.to_ascii_lowercase())
+                })
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+        // どのサイトからでも資格情報付きで読めてしまうため、"*" と allow_credentials は一緒に使えない
+        if config.allow_credentials && origins.iter().any(|m| matches!(m, OriginMatcher::Any)) {
+            return Err("allow_origins = [\"*\"] と allow_credentials = true は同時に指定できません。許可するオリジンを列挙してください".to_string());
+        }
+        Ok(Policy {
+            origins,
+            credentials: config.allow_credentials,
+            methods: header_list(&config.allow_methods, "allow_methods")?,
+            headers: header_list(&config.allow_headers, "allow_headers")?,
+            expose: header_list(&config.expose_headers, "expose_headers")?,
+            max_age: HeaderValue::from(config.max_age_secs),
+        })
+    }
+
+    fn allows(&self, origin: &str) -> bool {
+        let lower = origin.to_ascii_lowercase();
+        self.origins.iter().any(|matcher| match matcher {
+            OriginMatcher::Any => true,
+            OriginMatcher::Exact(allowed) => *allowed == lower,
+            OriginMatcher::Pattern(pattern) => pattern.is_match(origin),
+        })
+    }
+
+    // すべてのオリジンを許可する場合は "*"（資格情報とは併用できない）、それ以外はオリジンをそのまま返す
+    fn allow_origin_value(&self, origin: &HeaderValue) -> HeaderValue {
+        let any = self.origins.iter().any(|m| matches!(m, OriginMatcher::Any));
+        if any {
+            HeaderValue::from_static("*")
+        } else {
+            origin.clone()
+        }
+    }
+
+    // 通常のレスポンスとプリフライトに共通のヘッダー
+    fn insert_common(&self, origin: &HeaderValue, headers: &mut HeaderMap) {
+        headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, self.allow_origin_value(origin));
+        if self.credentials {
+            headers.insert(header::ACCESS_CONTROL_ALLOW_CREDENTIALS, HeaderValue::from_static("true"));
+        }
+        headers.append(header::VARY, HeaderValue::from_static("Origin"));
+    }
+
+    // プリフライトに答える
+    fn preflight(&self, target: &str, origin: &HeaderValue, request: &HeaderMap) -> Response {
+        let origin_text = origin.to_str().unwrap_or("");
+        if !self.allows(origin_text) {
+            warn!("CORS: 許可されていないオリジンからのプリフライト: {} -> {}", origin_text, target);
+            return (
+                StatusCode::FORBIDDEN,
+                format!("CORS: オリジン {} から {} へのリクエストは許可されていません（allow_origins）", origin_text, target),
+            )
+                .into_response();
+        }
+        let mut response = StatusCode::NO_CONTENT.into_response();
+        let headers = response.headers_mut();
+        self.insert_common(origin, headers);
+        let methods = self
+            .methods
+            .clone()
+            .or_else(|| request.get(header::ACCESS_CONTROL_REQUEST_METHOD).cloned());
+        if let Some(methods) = methods {
+            headers.insert(header::ACCESS_CONTROL_ALLOW_METHODS, methods);
+        }
+        let allowed_headers = self
+            .headers
+            .clone()
+            .or_else(|| request.get(header::ACCESS_CONTROL_REQUEST_HEADERS).cloned());
+        if let Some(allowed_headers) = allowed_headers {
+            headers.insert(header::ACCESS_CONTROL_ALLOW_HEADERS, allowed_headers);
+        }
+        headers.insert(header::ACCESS_CONTROL_MAX_AGE, self.max_age.clone());
+        headers.append(header::VARY, HeaderValue::from_static("Access-Control-Request-Method"));
+        headers.append(header::VARY, HeaderValue::from_static("Access-Control-Request-Headers"));
+        debug!("CORS: プリフライトに応答しました: {} -> {}", origin_text, target);
+        response
+    }
+
+    // バックエンドの Access-Control-* ヘッダーをポリシーの値に置き換える
+    fn apply(&self, origin: &HeaderValue, headers: &mut HeaderMap) {
+        let names: Vec<HeaderName> = headers
+            .keys()
+            .filter(|name| name.as_str().starts_with("access-control-"))
+            .cloned()
+            .collect();
+        for name in names {
+            headers.remove(name);
+        }
+        if !self.allows(origin.to_str().unwrap_or("")) {
+            return;
+        }
+        self.insert_common(origin, headers);
+        if let Some(expose) = &self.expose {
+            headers.insert(header::ACCESS_CONTROL_EXPOSE_HEADERS, expose.clone());
+        }
+    }
+}
+
+// 設定のリストを "a, b" 形式のヘッダー値にする（空なら None）
+fn header_list(values: &[String], field: &str) -> Result<Option<HeaderValue>, String> {
+    if values.is_empty() {
+        return Ok(None);
+    }
+    HeaderValue::try_from(values.join(", "))
+        .map(Some)
+        .map_err(|_| format!("{} に使えない文字が含まれています", field))
+}
+
+#[derive(Debug, Default)]
+pub struct Cors {
+    targets: HashMap<String, Policy>,
+}
+
+impl Cors {
+    fn policy(&self, target: &str) -> Option<&Policy> {
+        self.targets.get(target)
+    }
+}
+
+// 起動時にすべてのポリシーを検証して組み立てる
+pub fn compile(config: &Config) -> Result<Cors, String> {
+    let mut targets = HashMap::new();
+    for target in config.targets.iter().filter(|t| t.is_http()) {
+        let Some(cors) = &target.cors else {
+            continue;
+        };
+        let policy = Policy::compile(cors).map_err(|e| format!("{}: {}", target.name, e))?;
+        targets.insert(target.name.clone(), policy);
+    }
+    Ok(Cors { targets })
+}
+
+// プリフライトに答え、レスポンスにCORSヘッダーを付けるミドルウェア（プリフライトは資格情報を送らないため認証より前で処理する）
+pub async fn middleware(State(state): State<AppState>, req: Request, next: Next) -> Response {
+    if state.cors.targets.is_empty() {
+        return next.run(req).await;
+    }
+    let Some(origin) = req.headers().get(header::ORIGIN).cloned() else {
+        return next.run(req).await;
+    };
+    let Some(target) = auth::target_of(&state, &req) else {
+        return next.run(req).await;
+    };
+    let Some(policy) = state.cors.policy(&target) else {
+        return next.run(req).await;
+    };
+    if req.method() == Method::OPTIONS && req.headers().contains_key(header::ACCESS_CONTROL_REQUEST_METHOD) {
+        return policy.preflight(&target, &origin, req.headers());
+    }
+    let mut response = next.run(req).await;
+    policy.apply(&origin, response.headers_mut());
+    response
+}
@@ -0,0 +1,373 @@
+// Content-Security-Policy の扱い（ターゲットごとに strip / preserve / rewrite）
+//
+// rewrite はバックエンドのオリジンを許可しているソースをプロキシ上の場所（/proxy/{name}/）に対応させ、
+// 挿入する <base> と、PortRooter が書き換えたインラインスクリプトを nonce で許可する。
+// nonce を付けるのは、書き換える前の内容を元のポリシーが許可していたスクリプトだけ。
+
+use axum::http::{header, HeaderMap, HeaderName, HeaderValue};
+use ring::{
+    digest,
+    rand::{SecureRandom, SystemRandom},
+};
+use serde::Deserialize;
+use tracing::debug;
+
+use crate::har;
+
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum CspMode {
+    // CSPヘッダーと meta タグを削除する
+    #[default]
+    Strip,
+    // バックエンドのCSPをそのまま返す
+    Preserve,
+    // プロキシ経由で動くようにCSPを書き換える
+    Rewrite,
+}
+
+// 取得系のディレクティブ（バックエンドのオリジンを指すソースをプロキシ上の場所に対応させる）
+const FETCH_DIRECTIVES: &[&str] = &[
+    "default-src",
+    "script-src",
+    "script-src-elem",
+    "script-src-attr",
+    "style-src",
+    "style-src-elem",
+    "style-src-attr",
+    "img-src",
+    "font-src",
+    "connect-src",
+    "media-src",
+    "object-src",
+    "frame-src",
+    "child-src",
+    "worker-src",
+    "manifest-src",
+    "form-action",
+];
+
+// 書き換えに使う値
+pub struct CspRewrite {
+    // ブラウザが指定したプロキシのホスト（localhost:3015 など）
+    host: String,
+    // /proxy/{name}
+    prefix: String,
+    // バックエンドのポート
+    port: u16,
+    // 書き換えたインラインスクリプトに付ける nonce（HTMLのレスポンスだけ）
+    nonce: Option<String>,
+    // 書き換える前のレスポンスヘッダーのポリシー
+    policies: Vec<String>,
+}
+
+impl CspRewrite {
+    pub fn new(host: &str, prefix: &str, port: u16, html: bool) -> CspRewrite {
+        CspRewrite {
+            host: host.to_string(),
+            prefix: prefix.to_string(),
+            port,
+            nonce: html.then(new_nonce),
+            policies: Vec::new(),
+        }
+    }
+
+    // バックエンドのオリジンを指すソースなら、プロキシ上の対応するソースを返す
+    // （スキームを省くと http のページでは https / ws / wss にも一致する）
+    fn proxy_source(&self, source: &str) -> Option<String> {
+        let rest = source.split_once("://").map(|(_, rest)| rest).unwrap_or(source);
+        let port = format!(":{}", self.port);
+        let (origin, path) = match rest.find('/') {
+            Some(pos) => (&rest[..pos], &rest[pos..]),
+            None => (rest, ""),
+        };
+        let host = origin.strip_suffix(port.as_str())?;
+        if !matches!(host, "localhost" | "127.0.0.1" | "[::1]") {
+            return None;
+        }
+        // パスの指定がなければバックエンド全体を許可していたので、フォールバックで届くルート直下も含めて許可する
+        Some(if path.is_empty() || path == "/" {
+            self.host.clone()
+        } else {
+            format!("{}{}{}", self.host, self.prefix, path)
+        })
+    }
+}
+
+// 推測されないよう OS の乱数から作る
+fn new_nonce() -> String {
+    let mut bytes = [0u8; 16];
+    SystemRandom::new().fill(&mut bytes).expect("乱数を生成できませんでした");
+    har::base64_encode(&bytes)
+}
+
+// ポリシーを書き換える
+fn rewrite_policy(policy: &str, rewrite: &CspRewrite) -> String {
+    let mut directives: Vec<Vec<String>> = policy
+        .split(';')
+        .map(|d| d.split_ascii_whitespace().map(str::to_string).collect::<Vec<_>>())
+        .filter(|d| !d.is_empty())
+        .collect();
+    let has = |directives: &[Vec<String>], name: &str| directives.iter().any(|d| d[0].eq_ignore_ascii_case(name));
+    // <script> 要素に適用されるディレクティブ
+    let script_directive = ["script-src-elem", "script-src", "default-src"]
+        .into_iter()
+        .find(|name| has(&directives, name))
+        .map(str::to_string);
+
+    for directive in &mut directives {
+        let name = directive[0].to_ascii_lowercase();
+        if FETCH_DIRECTIVES.contains(&name.as_str()) {
+            let added: Vec<String> = directive[1..].iter().filter_map(|s| rewrite.proxy_source(s)).collect();
+            for source in added {
+                if !directive.contains(&source) {
+                    directive.push(source);
+                }
+            }
+        }
+        if name == "base-uri" {
+            // 挿入する <base href="/proxy/{name}/"> を許可する
+            let allowed = directive[1..].iter().any(|s| s == "'self'" || s == "*");
+            if !allowed {
+                directive.retain(|s| s != "'none'");
+                directive.push(format!("{}{}/", rewrite.host, rewrite.prefix));
+            }
+        }
+        let is_script = script_directive.as_deref() == Some(name.as_str())
+            || (name == "script-src" && script_directive.as_deref() == Some("script-src-elem"));
+        if is_script {
+            if let Some(nonce) = &rewrite.nonce {
+                if needs_nonce(&directive[1..]) {
+                    directive.push(format!("'nonce-{}'", nonce));
+                }
+            }
+        }
+    }
+
+    directives.iter().map(|d| d.join(" ")).collect::<Vec<_>>().join("; ")
+}
+
+// 'unsafe-inline' だけでインラインスクリプトを許可しているポリシーに nonce を加えると、
+// 'unsafe-inline' が無視されて元のスクリプトが動かなくなるため加えない
+fn needs_nonce(sources: &[String]) -> bool {
+    if sources.iter().any(|s| s == "'none'") {
+        return false;
+    }
+    let unsafe_inline = sources.iter().any(|s| s.eq_ignore_ascii_case("'unsafe-inline'"));
+    let nonce_or_hash = sources.iter().any(|s| is_nonce_or_hash(s));
+    !unsafe_inline || nonce_or_hash
+}
+
+fn is_nonce_or_hash(source: &str) -> bool {
+    ["'nonce-", "'sha256-", "'sha384-", "'sha512-"]
+        .iter()
+        .any(|prefix| source.starts_with(prefix))
+}
+
+// 元のポリシーが、この内容のインラインスクリプト（nonce 属性なし）の実行を許可していたか
+fn allows_inline_script(policy: &str, content: &str) -> bool {
+    let directives: Vec<Vec<&str>> = policy
+        .split(';')
+        .map(|d| d.split_ascii_whitespace().collect::<Vec<_>>())
+        .filter(|d| !d.is_empty())
+        .collect();
+    let Some(sources) = ["script-src-elem", "script-src", "default-src"]
+        .into_iter()
+        .find_map(|name| directives.iter().find(|d| d[0].eq_ignore_ascii_case(name)))
+        .map(|d| &d[1..])
+    else {
+        return true;
+    };
+    if sources.contains(&"'none'") {
+        return false;
+    }
+    // nonce かハッシュがあれば 'unsafe-inline' は無視されるので、ハッシュが一致したものだけ
+    if !sources.iter().any(|s| is_nonce_or_hash(s)) {
+        return sources.iter().any(|s| s.eq_ignore_ascii_case("'unsafe-inline'"));
+    }
+    [("sha256", &digest::SHA256), ("sha384", &digest::SHA384), ("sha512", &digest::SHA512)]
+        .into_iter()
+        .map(|(name, algorithm)| {
+            let hash = digest::digest(algorithm, content.as_bytes());
+            format!("'{}-{}'", name, har::base64_encode(hash.as_ref()))
+        })
+        .any(|hash| sources.contains(&hash.as_str()))
+}
+
+fn policy_headers() -> [HeaderName; 2] {
+    [
+        header::CONTENT_SECURITY_POLICY,
+        HeaderName::from_static("content-security-policy-report-only"),
+    ]
+}
+
+// レスポンスヘッダーのCSPを扱う（rewrite では書き換える前のポリシーを rewrite に残す）
+pub fn apply_headers(mode: CspMode, headers: &mut HeaderMap, rewrite: &mut CspRewrite) {
+    for name in policy_headers() {
+        match mode {
+            CspMode::Strip => {
+                headers.remove(&name);
+            }
+            CspMode::Preserve => {}
+            CspMode::Rewrite => {
+                let original: Vec<String> = headers
+                    .get_all(&name)
+                    .iter()
+                    .filter_map(|v| v.to_str().ok())
+                    .map(str::to_string)
+                    .collect();
+                let values: Vec<String> = original.iter().map(|policy| rewrite_policy(policy, rewrite)).collect();
+                rewrite.policies.extend(original);
+                if values.is_empty() {
+                    continue;
+                }
+                headers.remove(&name);
+                for value in values {
+                    debug!("CSPを書き換えました: {}", value);
+                    if let Ok(value) = HeaderValue::try_from(value) {
+                        headers.append(name.clone(), value);
+                    }
+                }
+            }
+        }
+    }
+}
+
+// HTML内の <meta http-equiv="Content-Security-Policy"> を扱い、書き換えたインラインスクリプトに nonce を付ける
+// （original はパスを書き換える前のHTML）
+pub fn apply_html(mode: CspMode, original: &str, html: String, rewrite: &CspRewrite) -> String {
+    let html = match mode {
+        CspMode::Preserve => return html,
+        CspMode::Strip => edit_meta_tags(html, |_| None),
+        CspMode::Rewrite => edit_meta_tags(html, |tag| Some(rewrite_meta(tag, rewrite))),
+    };
+    let Some(nonce) = rewrite.nonce.as_deref().filter(|_| mode == CspMode::Rewrite) else {
+        return html;
+    };
+    let mut policies = rewrite.policies.clone();
+    policies.extend(
+        meta_tags(&original.to_ascii_lowercase())
+            .into_iter()
+            .filter_map(|(start, end)| meta_policy(&original[start..end]))
+            .map(|(policy, _, _, _)| policy),
+    );
+    add_script_nonces(html, original, &policies, nonce)
+}
+
+// CSPの meta タグの範囲
+fn meta_tags(lower: &str) -> Vec<(usize, usize)> {
+    let mut tags = Vec::new();
+    let mut search = 0;
+    while let Some(pos) = lower[search..].find("<meta") {
+        let start = search + pos;
+        let Some(len) = lower[start..].find('>') else {
+            break;
+        };
+        let end = start + len + 1;
+        search = end;
+        let tag = &lower[start..end];
+        if tag.contains("http-equiv=\"content-security-policy\"") || tag.contains("http-equiv='content-security-policy'") {
+            tags.push((start, end));
+        }
+    }
+    tags
+}
+
+// CSPの meta タグごとに edit を呼び、None なら削除、Some なら置き換える
+fn edit_meta_tags(html: String, edit: impl Fn(&str) -> Option<String>) -> String {
+    let mut out = String::with_capacity(html.len());
+    let mut last = 0;
+    for (start, end) in meta_tags(&html.to_ascii_lowercase()) {
+        out.push_str(&html[last..start]);
+        if let Some(replacement) = edit(&html[start..end]) {
+            out.push_str(&replacement);
+        }
+        last = end;
+    }
+    out.push_str(&html[last..]);
+    out
+}
+
+// meta タグの content 属性のポリシーと、その値の範囲と引用符
+fn meta_policy(tag: &str) -> Option<(String, usize, usize, char)> {
+    let lower = tag.to_ascii_lowercase();
+    let value_start = lower.find("content=")? + "content=".len();
+    let quote = tag[value_start..].chars().next().filter(|c| *c == '"' || *c == '\'')?;
+    let value_end = value_start + 1 + tag[value_start + 1..].find(quote)?;
+    let policy = html_escape::decode_html_entities(&tag[value_start + 1..value_end]).into_owned();
+    Some((policy, value_start, value_end, quote))
+}
+
+// meta タグの content 属性のポリシーを書き換える
+fn rewrite_meta(tag: &str, rewrite: &CspRewrite) -> String {
+    let Some((policy, value_start, value_end, quote)) = meta_policy(tag) else {
+        return tag.to_string();
+    };
+    let policy = rewrite_policy(&policy, rewrite);
+    let policy = if quote == '"' {
+        html_escape::encode_double_quoted_attribute(&policy).into_owned()
+    } else {
+        html_escape::encode_single_quoted_attribute(&policy).into_owned()
+    };
+    format!("{}{}{}", &tag[..value_start + 1], policy, &tag[value_end..])
+}
+
+// <script> 要素の開始タグの範囲と中身の終わり
+struct Script {
+    start: usize,
+    tag_end: usize,
+    content_end: usize,
+}
+
+fn scripts(lower: &str) -> Vec<Script> {
+    let mut scripts = Vec::new();
+    let mut search = 0;
+    while let Some(pos) = lower[search..].find("<script") {
+        let start = search + pos;
+        let Some(len) = lower[start..].find('>') else {
+            break;
+        };
+        let tag_end = start + len + 1;
+        let content_end = lower[tag_end..].find("</script").map(|p| tag_end + p).unwrap_or(lower.len());
+        search = content_end;
+        scripts.push(Script {
+            start,
+            tag_end,
+            content_end,
+        });
+    }
+    scripts
+}
+
+// パスを書き換えたインラインスクリプト（src 属性と nonce 属性のないもの）のうち、
+// 書き換える前の内容をすべてのポリシーが許可していたものに nonce を付ける。
+// 元のポリシーで止められていたスクリプトは、プロキシ経由でも止められたままにする
+fn add_script_nonces(html: String, original: &str, policies: &[String], nonce: &str) -> String {
+    let lower = html.to_ascii_lowercase();
+    let after = scripts(&lower);
+    let before = scripts(&original.to_ascii_lowercase());
+    // 書き換えでスクリプトの数は変わらないので、順番で元のスクリプトと対応させる
+    if after.len() != before.len() {
+        return html;
+    }
+    let mut out = String::with_capacity(html.len());
+    let mut last = 0;
+    for (script, old) in after.iter().zip(&before) {
+        let tag = &lower[script.start..script.tag_end];
+        if tag.contains(" src=") || tag.contains(" nonce=") {
+            continue;
+        }
+        let content = &original[old.tag_end..old.content_end];
+        if html[script.tag_end..script.content_end] == *content
+            || !policies.iter().all(|policy| allows_inline_script(policy, content))
+        {
+            continue;
+        }
+        let name_end = script.start + "<script".len();
+        out.push_str(&html[last..name_end]);
+        out.push_str(&format!(" nonce=\"{}\"", nonce));
+        last = name_end;
+    }
+    out.push_str(&html[last..]);
+    out
+}
@@ -0,0 +1,404 @@
+// 障害注入（ローディング表示やリトライ処理のテスト用）
+//
+// ターゲットとパスのパターンごとに、遅延（ゆらぎ付き）、一定割合のエラーステータス、
+// ボディ途中での切断、帯域制限を加える。管理API（/__portrooter/api/faults）で実行中に切り替えられる。
+
+use axum::{
+    body::Body,
+    extract::{Path, Request, State},
+    http::{HeaderName, HeaderValue, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Json, Response},
+};
+use hyper::body::{Body as HttpBody, Bytes, Frame, SizeHint};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::{
+    collections::HashMap,
+    future::Future,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, RwLock,
+    },
+    task::{ready, Context, Poll},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::time::Sleep;
+use tracing::{debug, info};
+
+use crate::{routes, AppState, Config};
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct FaultConfig {
+    // パスのパターン（glob、** はスラッシュをまたぐ）
+    #[serde(default = "default_path")]
+    pub path: String,
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+    // 追加する遅延（ミリ秒）
+    #[serde(default)]
+    pub latency_ms: u64,
+    // 遅延のゆらぎ（±ミリ秒）
+    #[serde(default)]
+    pub jitter_ms: u64,
+    // error_status で応答する割合（%）
+    #[serde(default)]
+    pub error_rate: f64,
+    #[serde(default = "default_error_status")]
+    pub error_status: u16,
+    // ボディの途中で接続を切る割合（%）
+    #[serde(default)]
+    pub abort_rate: f64,
+    // レスポンスの帯域（KB/秒）
+    #[serde(default)]
+    pub throttle_kbps: Option<u64>,
+}
+
+fn default_path() -> String {
+    "/**".to_string()
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_error_status() -> u16 {
+    503
+}
+
+impl FaultConfig {
+    // 選択画面に表示する説明
+    fn describe(&self) -> String {
+        let mut parts = Vec::new();
+        if self.latency_ms > 0 || self.jitter_ms > 0 {
+            parts.push(format!("遅延 {}±{}ms", self.latency_ms, self.jitter_ms));
+        }
+        if self.error_rate > 0.0 {
+            parts.push(format!("エラー {}% ({})", self.error_rate, self.error_status));
+        }
+        if self.abort_rate > 0.0 {
+            parts.push(format!("切断 {}%", self.abort_rate));
+        }
+        if let Some(kbps) = self.throttle_kbps {
+            parts.push(format!("帯域 {}KB/s", kbps));
+        }
+        format!("{} {}", self.path, parts.join("・"))
+    }
+}
+
+struct Fault {
+    config: FaultConfig,
+    regex: Regex,
+}
+
+impl Fault {
+    fn compile(config: FaultConfig) -> Result<Fault, String> {
+        let regex = Regex::new(&routes::glob_to_regex(&config.path)).map_err(|e| e.to_string())?;
+        if StatusCode::from_u16(config.error_status).is_err() {
+            return Err(format!("不正なステータスです: {}", config.error_status));
+        }
+        if !(0.0..=100.0).contains(&config.error_rate) || !(0.0..=100.0).contains(&config.abort_rate) {
+            return Err("error_rate / abort_rate は 0〜100 で指定してください".to_string());
+        }
+        if config.throttle_kbps == Some(0) {
+            return Err("throttle_kbps は 1 以上で指定してください".to_string());
+        }
+        Ok(Fault { config, regex })
+    }
+}
+
+// ターゲットごとの障害注入ルール（管理APIから書き換えられる）
+#[derive(Default)]
+pub struct Faults {
+    targets: RwLock<HashMap<String, Vec<Fault>>>,
+}
+
+impl Faults {
+    // 有効なルールの説明（選択画面用）
+    pub fn active(&self, target: &str) -> Vec<String> {
+        self.targets
+            .read()
+            .unwrap()
+            .get(target)
+            .map(|faults| {
+                faults
+                    .iter()
+                    .filter(|f| f.config.enabled)
+                    .map(|f| f.config.describe())
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    // パスに一致する最初の有効なルール
+    fn find(&self, target: &str, path: &str) -> Option<FaultConfig> {
+        self.targets
+            .read()
+            .unwrap()
+            .get(target)?
+            .iter()
+            .find(|f| f.config.enabled && f.regex.is_match(path))
+            .map(|f| f.config.clone())
+    }
+
+    fn set(&self, target: &str, configs: Vec<FaultConfig>) -> Result<(), String> {
+        let faults = configs.into_iter().map(Fault::compile).collect::<Result<Vec<_>, _>>()?;
+        self.targets.write().unwrap().insert(target.to_string(), faults);
+        Ok(())
+    }
+
+    // index 番目のルールを書き換える（同時に更新されても失われないよう、読み出しから書き戻しまで同じロックの中で行う）。
+    // ルールがなければ None
+    fn update(
+        &self,
+        target: &str,
+        index: usize,
+        apply: impl FnOnce(&FaultConfig) -> Result<FaultConfig, String>,
+    ) -> Option<Result<FaultConfig, String>> {
+        let mut targets = self.targets.write().unwrap();
+        let fault = targets.get_mut(target)?.get_mut(index)?;
+        Some(apply(&fault.config).and_then(|config| {
+            *fault = Fault::compile(config)?;
+            Ok(fault.config.clone())
+        }))
+    }
+
+    fn configs(&self) -> HashMap<String, Vec<FaultConfig>> {
+        self.targets
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(name, faults)| (name.clone(), faults.iter().map(|f| f.config.clone()).collect()))
+            .collect()
+    }
+}
+
+pub fn compile(config: &Config) -> Result<Faults, String> {
+    let faults = Faults::default();
+    for target in config.targets.iter().filter(|t| t.is_http() && !t.faults.is_empty()) {
+        faults
+            .set(&target.name, target.faults.clone())
+            .map_err(|e| format!("{}: {}", target.name, e))?;
+    }
+    Ok(faults)
+}
+
+// レスポンスボディに加える障害（ハンドラーの書き換え後、ブラウザへ送る直前に適用する）
+#[derive(Debug, Clone, Copy, Default)]
+struct BodyFault {
+    abort: bool,
+    throttle_kbps: Option<u64>,
+}
+
+#[derive(Clone, Default)]
+struct FaultNote(Arc<Mutex<Option<BodyFault>>>);
+
+// バックエンドへ送る前に遅延とエラーを加える。エラーにする場合はそのレスポンスを返す
+pub async fn before_upstream(faults: &Faults, target: &str, req: &mut Request) -> Option<Response> {
+    let fault = faults.find(target, req.uri().path())?;
+
+    let jitter = if fault.jitter_ms > 0 {
+        (random() * 2.0 - 1.0) * fault.jitter_ms as f64
+    } else {
+        0.0
+    };
+    let delay = (fault.latency_ms as f64 + jitter).max(0.0);
+    if delay > 0.0 {
+        debug!("障害注入: 遅延 {:.0}ms {}", delay, req.uri().path());
+        tokio::time::sleep(Duration::from_secs_f64(delay / 1000.0)).await;
+    }
+
+    if random() * 100.0 < fault.error_rate {
+        debug!("障害注入: {} {}", fault.error_status, req.uri().path());
+        let status = StatusCode::from_u16(fault.error_status).unwrap_or(StatusCode::SERVICE_UNAVAILABLE);
+        let mut response = (status, format!("PortRooter 障害注入: {} ({})", status, fault.path)).into_response();
+        response
+            .headers_mut()
+            .insert(HeaderName::from_static("x-portrooter-fault"), HeaderValue::from_static("error"));
+        return Some(response);
+    }
+
+    let body_fault = BodyFault {
+        abort: random() * 100.0 < fault.abort_rate,
+        throttle_kbps: fault.throttle_kbps.filter(|kbps| *kbps > 0),
+    };
+    if body_fault.abort || body_fault.throttle_kbps.is_some() {
+        if let Some(note) = req.extensions().get::<FaultNote>() {
+            *note.0.lock().unwrap() = Some(body_fault);
+        }
+    }
+    None
+}
+
+// ボディへの障害（切断・帯域制限）を最終的なレスポンスに適用するミドルウェア
+pub async fn middleware(mut req: Request, next: Next) -> Response {
+    let note = FaultNote::default();
+    req.extensions_mut().insert(note.clone());
+
+    let response = next.run(req).await;
+    let Some(fault) = note.0.lock().unwrap().take() else {
+        return response;
+    };
+
+    // 切断はボディの長さが分かればその半分、分からなければ 1KB 送ったところで行う
+    let abort_after = fault.abort.then(|| {
+        response
+            .body()
+            .size_hint()
+            .exact()
+            .map(|len| len / 2)
+            .unwrap_or(1024)
+    });
+    // 帯域制限は100ミリ秒分ずつ送る
+    let chunk_size = fault
+        .throttle_kbps
+        .map(|kbps| ((kbps.saturating_mul(1024) / 10) as usize).max(1))
+        .unwrap_or(usize::MAX);
+    response.map(|body| {
+        Body::new(FaultBody {
+            inner: body,
+            buffered: Bytes::new(),
+            sent: 0,
+            abort_after,
+            throttle_kbps: fault.throttle_kbps,
+            chunk_size,
+            sleep: None,
+        })
+    })
+}
+
+struct FaultBody {
+    inner: Body,
+    // バックエンドから受け取り、まだ送っていないデータ
+    buffered: Bytes,
+    sent: u64,
+    abort_after: Option<u64>,
+    throttle_kbps: Option<u64>,
+    chunk_size: usize,
+    sleep: Option<Pin<Box<Sleep>>>,
+}
+
+impl FaultBody {
+    fn aborted() -> axum::Error {
+        axum::Error::new("PortRooter 障害注入: 接続を切断しました")
+    }
+}
+
+impl HttpBody for FaultBody {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        loop {
+            if let Some(sleep) = self.sleep.as_mut() {
+                ready!(sleep.as_mut().poll(cx));
+                self.sleep = None;
+            }
+
+            if !self.buffered.is_empty() {
+                let mut len = self.buffered.len().min(self.chunk_size);
+                if let Some(limit) = self.abort_after {
+                    if self.sent >= limit {
+                        return Poll::Ready(Some(Err(Self::aborted())));
+                    }
+                    len = len.min((limit - self.sent) as usize);
+                }
+                let chunk = self.buffered.split_to(len);
+                self.sent += len as u64;
+                if let Some(kbps) = self.throttle_kbps {
+                    let delay = Duration::from_secs_f64(len as f64 / (kbps as f64 * 1024.0));
+                    self.sleep = Some(Box::pin(tokio::time::sleep(delay)));
+                }
+                return Poll::Ready(Some(Ok(Frame::data(chunk))));
+            }
+
+            match ready!(Pin::new(&mut self.inner).poll_frame(cx)) {
+                Some(Ok(frame)) => match frame.into_data() {
+                    Ok(data) => self.buffered = data,
+                    Err(frame) => return Poll::Ready(Some(Ok(frame))),
+                },
+                Some(Err(err)) => return Poll::Ready(Some(Err(err))),
+                // 切断する予定だったボディは、最後まで届けずに切る
+                None if self.abort_after.is_some() => return Poll::Ready(Some(Err(Self::aborted()))),
+                None => return Poll::Ready(None),
+            }
+        }
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.abort_after.is_none() && self.buffered.is_empty() && self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+// 0以上1未満の乱数（splitmix64）
+pub fn random() -> f64 {
+    static STATE: AtomicU64 = AtomicU64::new(0);
+    let seed = STATE.fetch_add(0x9e3779b97f4a7c15, Ordering::Relaxed)
+        ^ SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or_default();
+    let mut z = seed;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^= z >> 31;
+    (z >> 11) as f64 / (1u64 << 53) as f64
+}
+
+// 管理API: すべてのターゲットのルール
+pub async fn list_handler(State(state): State<AppState>) -> Json<serde_json::Value> {
+    Json(json!(state.faults.configs()))
+}
+
+// 管理API: ターゲットのルールをまとめて置き換える（空配列ですべて解除）
+pub async fn replace_handler(
+    State(state): State<AppState>,
+    Path(target): Path<String>,
+    Json(configs): Json<Vec<FaultConfig>>,
+) -> Response {
+    if state.config.http_target(&target).is_none() {
+        return (StatusCode::NOT_FOUND, format!("HTTPターゲット '{}' が見つかりません", target)).into_response();
+    }
+    match state.faults.set(&target, configs) {
+        Ok(()) => {
+            info!("障害注入の設定を更新しました: {}", target);
+            Json(json!(state.faults.configs().remove(&target).unwrap_or_default())).into_response()
+        }
+        Err(err) => (StatusCode::BAD_REQUEST, err).into_response(),
+    }
+}
+
+// 管理API: ルールの一部の項目だけを変更する（例: {"enabled": false}）
+pub async fn update_handler(
+    State(state): State<AppState>,
+    Path((target, index)): Path<(String, usize)>,
+    Json(patch): Json<serde_json::Value>,
+) -> Response {
+    let Some(patch) = patch.as_object() else {
+        return (StatusCode::BAD_REQUEST, "JSONオブジェクトで指定してください").into_response();
+    };
+    let updated = state.faults.update(&target, index, |current| {
+        let mut merged = serde_json::to_value(current).map_err(|e| e.to_string())?;
+        for (key, value) in patch {
+            merged[key] = value.clone();
+        }
+        serde_json::from_value(merged).map_err(|e| e.to_string())
+    });
+    match updated {
+        Some(Ok(updated)) => {
+            info!("障害注入の設定を更新しました: {} [{}] {}", target, index, updated.describe());
+            Json(json!(updated)).into_response()
+        }
+        None => (StatusCode::NOT_FOUND, format!("{} の {} 番目のルールはありません", target, index)).into_response(),
+        Some(Err(err)) => (StatusCode::BAD_REQUEST, err).into_response(),
+    }
+}
@@ -0,0 +1,61 @@
+// HTTP以外のフォワーディング（TCP/UDP）で共有する統計情報と表示用ヘルパー
+
+use std::{
+    collections::HashMap,
+    sync::{atomic::AtomicU64, Arc},
+};
+
+use crate::{Config, Target, TargetKind};
+
+// ターゲットごとの接続（UDPではセッション）統計
+#[derive(Default)]
+pub struct ForwardStats {
+    pub active: AtomicU64,
+    pub total: AtomicU64,
+    pub bytes_in: AtomicU64,
+    pub bytes_out: AtomicU64,
+}
+
+pub type ForwardStatsMap = Arc<HashMap<String, Arc<ForwardStats>>>;
+
+// HTTP以外のターゲットについて空の統計を用意する
+pub fn stats_for(config: &Config) -> ForwardStatsMap {
+    Arc::new(
+        config
+            .targets
+            .iter()
+            .filter(|t| !t.is_http())
+            .map(|t| (t.name.clone(), Arc::new(ForwardStats::default())))
+            .collect(),
+    )
+}
+
+// バイト数を人間が読みやすい形式にする
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KB", "MB", "GB", "TB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} {}", bytes, UNITS[0])
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
+
+// 選択画面に表示する待ち受け先の説明
+pub fn listen_label(target: &Target, config: &Config) -> String {
+    let kind = match target.kind {
+        TargetKind::Udp => "UDP",
+        _ => "TCP",
+    };
+    let listen = match (&target.listen_port, &target.sni, config.tls_sni_port) {
+        (Some(port), _, _) => format!("127.0.0.1:{}", port),
+        (None, Some(sni), Some(sni_port)) => format!("{} (SNI :{})", sni, sni_port),
+        _ => "待ち受けなし".to_string(),
+    };
+    format!("{} {}", kind, listen)
+}
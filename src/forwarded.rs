@@ -0,0 +1,183 @@
+// バックエンドへ送る X-Forwarded-* と Forwarded（RFC 7239）ヘッダー
+//
+// X-Forwarded-For と Forwarded は既存の値に接続元を追加する。Proto / Host / Port / Prefix は、
+// 接続元が trusted_proxies に含まれる場合だけ前段のプロキシが付けた値をそのまま使う。
+
+use axum::{
+    extract::{ConnectInfo, Request},
+    http::{HeaderMap, HeaderName, HeaderValue},
+};
+use serde::Deserialize;
+use std::net::{IpAddr, SocketAddr};
+
+use crate::{auth, Config};
+
+const X_FORWARDED_FOR: HeaderName = HeaderName::from_static("x-forwarded-for");
+const X_FORWARDED_PROTO: HeaderName = HeaderName::from_static("x-forwarded-proto");
+const X_FORWARDED_HOST: HeaderName = HeaderName::from_static("x-forwarded-host");
+const X_FORWARDED_PORT: HeaderName = HeaderName::from_static("x-forwarded-port");
+const X_FORWARDED_PREFIX: HeaderName = HeaderName::from_static("x-forwarded-prefix");
+const FORWARDED: HeaderName = HeaderName::from_static("forwarded");
+
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct ForwardedConfig {
+    // RFC 7239 の Forwarded ヘッダーも付ける
+    #[serde(default)]
+    pub forwarded_header: bool,
+    // PortRooter の前段にあるプロキシのアドレス（CIDR）
+    #[serde(default)]
+    pub trusted_proxies: Vec<String>,
+}
+
+// HTTPSの待ち受けで受け付けた接続の印（リクエストの拡張に入れる）
+#[derive(Debug, Clone, Copy)]
+pub struct Tls;
+
+// Unix ドメインソケットで受け付けた接続の印。client_ip と認証はこの印があれば接続元のアドレスを None とし、
+// trusted / allow やループバックとしては扱わない（前段の nginx などが LAN の接続を流し込むことがあるため）
+#[derive(Debug, Clone, Copy)]
+pub struct UnixPeer;
+
+#[derive(Debug)]
+pub struct Forwarding {
+    forwarded_header: bool,
+    trusted: Vec<auth::Cidr>,
+}
+
+impl Forwarding {
+    pub fn new(config: &Config) -> Result<Forwarding, String> {
+        Ok(Forwarding {
+            forwarded_header: config.forwarded.forwarded_header,
+            trusted: auth::parse_cidrs(&config.forwarded.trusted_proxies)?,
+        })
+    }
+
+    fn is_trusted(&self, ip: IpAddr) -> bool {
+        self.trusted.iter().any(|c| c.contains(ip))
+    }
+
+    // クライアントのアドレス。信頼できるプロキシ経由なら X-Forwarded-For を右からたどる
+    pub fn client_ip(&self, req: &Request) -> Option<IpAddr> {
+        let peer = peer_ip(req)?;
+        if !self.is_trusted(peer) {
+            return Some(peer);
+        }
+        let chain: Vec<IpAddr> = req
+            .headers()
+            .get_all(X_FORWARDED_FOR)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .filter_map(|ip| parse_ip(ip.trim()))
+            .collect();
+        Some(
+            chain
+                .iter()
+                .rev()
+                .copied()
+                .find(|ip| !self.is_trusted(*ip))
+                .or_else(|| chain.first().copied())
+                .unwrap_or(peer),
+        )
+    }
+
+    // 転送前のリクエストにヘッダーを付ける（host はブラウザが指定したホスト、prefix は取り除いたパス）
+    pub fn apply(&self, req: &mut Request, host: &str, prefix: Option<&str>) {
+        let peer = peer_ip(req);
+        let trusted = peer.is_some_and(|ip| self.is_trusted(ip));
+        let proto = if req.extensions().get::<Tls>().is_some() { "https" } else { "http" };
+        let headers = req.headers_mut();
+
+        let proto = keep_or(headers, trusted, &X_FORWARDED_PROTO, proto);
+        let host = keep_or(headers, trusted, &X_FORWARDED_HOST, host);
+        let default_port = if proto == "https" { "443" } else { "80" };
+        let port = host
+            .rsplit_once(':')
+            .map(|(_, port)| port)
+            .filter(|port| !port.contains(']'))
+            .unwrap_or(default_port)
+            .to_string();
+        let port = keep_or(headers, trusted, &X_FORWARDED_PORT, &port);
+
+        let for_value = peer.map(|ip| ip.to_string()).unwrap_or_else(|| "unknown".to_string());
+        append(headers, X_FORWARDED_FOR, &for_value);
+        set(headers, X_FORWARDED_PROTO, &proto);
+        set(headers, X_FORWARDED_HOST, &host);
+        set(headers, X_FORWARDED_PORT, &port);
+        match prefix {
+            Some(prefix) if !(trusted && headers.contains_key(X_FORWARDED_PREFIX)) => {
+                set(headers, X_FORWARDED_PREFIX, prefix);
+            }
+            Some(_) => {}
+            None if !trusted => {
+                headers.remove(X_FORWARDED_PREFIX);
+            }
+            None => {}
+        }
+
+        if self.forwarded_header {
+            let node = match peer {
+                Some(IpAddr::V6(ip)) => format!("\"[{}]\"", ip),
+                Some(ip) => ip.to_string(),
+                None => "unknown".to_string(),
+            };
+            let element = format!("for={};host={};proto={}", node, quote(&host), proto);
+            append(headers, FORWARDED, &element);
+        }
+    }
+}
+
+// 接続元のアドレス。Unix ドメインソケットの接続はアドレスを持たないものとして扱う
+fn peer_ip(req: &Request) -> Option<IpAddr> {
+    if req.extensions().get::<UnixPeer>().is_some() {
+        return None;
+    }
+    req.extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip().to_canonical())
+}
+
+// X-Forwarded-For の要素（ポート付きや角括弧付きのIPv6も受け付ける）
+fn parse_ip(text: &str) -> Option<IpAddr> {
+    text.parse()
+        .ok()
+        .or_else(|| text.parse::<SocketAddr>().ok().map(|addr| addr.ip()))
+        .or_else(|| text.trim_start_matches('[').trim_end_matches(']').parse().ok())
+}
+
+// 信頼できるプロキシが付けた値があればそれを使う
+fn keep_or(headers: &HeaderMap, trusted: bool, name: &HeaderName, value: &str) -> String {
+    headers
+        .get(name)
+        .filter(|_| trusted)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.split(',').next())
+        .map(|v| v.trim().to_string())
+        .unwrap_or_else(|| value.to_string())
+}
+
+fn set(headers: &mut HeaderMap, name: HeaderName, value: &str) {
+    if let Ok(value) = HeaderValue::try_from(value) {
+        headers.insert(name, value);
+    }
+}
+
+// 既存の値の末尾に「, 値」で追加する
+fn append(headers: &mut HeaderMap, name: HeaderName, value: &str) {
+    let existing: Vec<&str> = headers.get_all(&name).iter().filter_map(|v| v.to_str().ok()).collect();
+    let value = if existing.is_empty() {
+        value.to_string()
+    } else {
+        format!("{}, {}", existing.join(", "), value)
+    };
+    set(headers, name, &value);
+}
+
+// RFC 7239 の値は token 以外の文字（: など）を含むと引用符が必要
+fn quote(value: &str) -> String {
+    if value.chars().all(|c| c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c)) {
+        value.to_string()
+    } else {
+        format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+    }
+}
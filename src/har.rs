@@ -0,0 +1,393 @@
+// HAR 1.2 のエクスポートとインポート
+//
+// インスペクターに記録したリクエストをHARとして書き出し、HARを読み込んでインスペクターで表示できるようにする。
+// タイミングはプロキシ自身が計測した値（レスポンスヘッダーまで / 完了まで）から埋める。
+
+use axum::{
+    body::Body,
+    extract::{Query, State},
+    http::{header, StatusCode},
+    response::{IntoResponse, Json, Response},
+};
+use http_body_util::{BodyExt, LengthLimitError, Limited};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    access_log,
+    inspect::{header_value, CapturedBody, Exchange},
+    AppState,
+};
+
+// 読み込むHARの上限（MB）
+const MAX_IMPORT_MB: usize = 64;
+
+#[derive(Debug, Deserialize, Default)]
+pub struct ExportQuery {
+    // ターゲット名で絞り込む
+    #[serde(default)]
+    target: Option<String>,
+    // 直近N分のリクエストだけを書き出す
+    #[serde(default)]
+    minutes: Option<u64>,
+    // 期間（RFC 3339、例: 2026-10-18T14:00:00Z）
+    #[serde(default)]
+    from: Option<String>,
+    #[serde(default)]
+    to: Option<String>,
+}
+
+// 記録したリクエストをHARとして返す
+pub async fn export_handler(State(state): State<AppState>, Query(query): Query<ExportQuery>) -> Response {
+    let mut from = match query.from.as_deref().filter(|v| !v.is_empty()).map(parse_time) {
+        Some(Some(time)) => Some(time),
+        Some(None) => return (StatusCode::BAD_REQUEST, "from の日時を解釈できません").into_response(),
+        None => None,
+    };
+    let to = match query.to.as_deref().filter(|v| !v.is_empty()).map(parse_time) {
+        Some(Some(time)) => Some(time),
+        Some(None) => return (StatusCode::BAD_REQUEST, "to の日時を解釈できません").into_response(),
+        None => None,
+    };
+    if let Some(minutes) = query.minutes {
+        let Some(secs) = minutes.checked_mul(60) else {
+            return (StatusCode::BAD_REQUEST, "minutes が大きすぎます").into_response();
+        };
+        from = SystemTime::now().checked_sub(Duration::from_secs(secs));
+    }
+    let target = query.target.filter(|t| !t.is_empty());
+
+    let entries: Vec<Value> = state
+        .inspector
+        .snapshot()
+        .iter()
+        .filter(|e| e.complete)
+        .filter(|e| target.is_none() || e.target == target)
+        .filter(|e| from.is_none_or(|from| e.started >= from))
+        .filter(|e| to.is_none_or(|to| e.started <= to))
+        .map(to_har_entry)
+        .collect();
+
+    let har = json!({
+        "log": {
+            "version": "1.2",
+            "creator": { "name": "PortRooter", "version": env!("CARGO_PKG_VERSION") },
+            "pages": [],
+            "entries": entries,
+        }
+    });
+
+    (
+        [
+            (header::CONTENT_TYPE, "application/json; charset=utf-8"),
+            (header::CONTENT_DISPOSITION, "attachment; filename=\"portrooter.har\""),
+        ],
+        serde_json::to_string_pretty(&har).unwrap_or_default(),
+    )
+        .into_response()
+}
+
+// HARを読み込み、インスペクターに追加する
+pub async fn import_handler(State(state): State<AppState>, body: Body) -> Response {
+    let bytes = match Limited::new(body, MAX_IMPORT_MB * 1024 * 1024).collect().await {
+        Ok(collected) => collected.to_bytes(),
+        Err(err) if err.is::<LengthLimitError>() => {
+            return (StatusCode::PAYLOAD_TOO_LARGE, format!("HARが大きすぎます（上限 {}MB）", MAX_IMPORT_MB)).into_response()
+        }
+        Err(err) => return (StatusCode::BAD_REQUEST, format!("読み取りに失敗しました: {}", err)).into_response(),
+    };
+    let exchanges = match parse(&bytes) {
+        Ok(exchanges) => exchanges,
+        Err(err) => return (StatusCode::BAD_REQUEST, err).into_response(),
+    };
+    let count = exchanges.len();
+    for mut exchange in exchanges {
+        exchange.id = state.inspector.next_id();
+        exchange.redact();
+        state.inspector.push(Arc::new(Mutex::new(exchange)));
+    }
+    Json(json!({ "imported": count })).into_response()
+}
+
+fn to_har_entry(e: &Exchange) -> Value {
+    let headers = |list: &[(String, String)]| {
+        list.iter()
+            .map(|(name, value)| json!({ "name": name, "value": value }))
+            .collect::<Vec<_>>()
+    };
+    let host = header_value(&e.request_headers, "host").unwrap_or("localhost");
+    let url = if e.uri.starts_with("http://") || e.uri.starts_with("https://") {
+        e.uri.clone()
+    } else {
+        format!("http://{}{}", host, e.uri)
+    };
+    let query_string: Vec<Value> = e
+        .uri
+        .split_once('?')
+        .map(|(_, query)| {
+            query
+                .split('&')
+                .filter(|pair| !pair.is_empty())
+                .map(|pair| {
+                    let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+                    json!({ "name": decode_component(name), "value": decode_component(value) })
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+
+    let mut request = json!({
+        "method": e.method,
+        "url": url,
+        "httpVersion": e.http_version,
+        "cookies": [],
+        "headers": headers(&e.request_headers),
+        "queryString": query_string,
+        "headersSize": -1,
+        "bodySize": e.request_body.size,
+    });
+    if e.request_body.size > 0 {
+        request["postData"] = json!({
+            "mimeType": header_value(&e.request_headers, "content-type").unwrap_or(""),
+            "text": String::from_utf8_lossy(&e.request_body.data),
+        });
+    }
+
+    let mut content = json!({
+        "size": e.response_body.size,
+        "mimeType": header_value(&e.response_headers, "content-type").unwrap_or(""),
+    });
+    match e.response_body.text() {
+        Some(text) => content["text"] = json!(text),
+        None => {
+            content["text"] = json!(base64_encode(&e.response_body.data));
+            content["encoding"] = json!("base64");
+        }
+    }
+    if e.response_body.truncated {
+        content["comment"] = json!("PortRooterの保存上限を超えたため先頭のみ");
+    }
+
+    let receive = (e.duration_ms - e.wait_ms).max(0.0);
+    json!({
+        "startedDateTime": access_log::format_times(e.started).1,
+        "time": e.duration_ms,
+        "request": request,
+        "response": {
+            "status": e.status,
+            "statusText": StatusCode::from_u16(e.status)
+                .ok()
+                .and_then(|s| s.canonical_reason())
+                .unwrap_or(""),
+            "httpVersion": e.http_version,
+            "cookies": [],
+            "headers": headers(&e.response_headers),
+            "content": content,
+            "redirectURL": header_value(&e.response_headers, "location").unwrap_or(""),
+            "headersSize": -1,
+            "bodySize": e.response_body.size,
+        },
+        "cache": {},
+        "timings": {
+            "blocked": -1,
+            "dns": -1,
+            "connect": -1,
+            "send": 0,
+            "wait": e.wait_ms,
+            "receive": receive,
+        },
+        "_target": e.target,
+    })
+}
+
+// HARの entries を Exchange に変換する（idは呼び出し側で振る）
+pub fn parse(bytes: &[u8]) -> Result<Vec<Exchange>, String> {
+    let har: Value = serde_json::from_slice(bytes).map_err(|e| format!("HARを解釈できません: {}", e))?;
+    let entries = har["log"]["entries"]
+        .as_array()
+        .ok_or("log.entries がありません")?;
+    entries
+        .iter()
+        .enumerate()
+        .map(|(i, entry)| from_har_entry(entry).map_err(|e| format!("entries[{}]: {}", i, e)))
+        .collect()
+}
+
+fn from_har_entry(entry: &Value) -> Result<Exchange, String> {
+    let headers = |value: &Value| -> Vec<(String, String)> {
+        value
+            .as_array()
+            .map(|list| {
+                list.iter()
+                    .filter_map(|h| Some((h["name"].as_str()?.to_string(), h["value"].as_str()?.to_string())))
+                    .collect()
+            })
+            .unwrap_or_default()
+    };
+    let request = &entry["request"];
+    let response = &entry["response"];
+
+    let method = request["method"].as_str().ok_or("request.method がありません")?.to_string();
+    let url = request["url"].as_str().ok_or("request.url がありません")?;
+    // インスペクターでは他の記録と同じくパス以降を表示する
+    let uri = url
+        .split_once("://")
+        .and_then(|(_, rest)| rest.find('/').map(|pos| rest[pos..].to_string()))
+        .unwrap_or_else(|| url.to_string());
+
+    let mut request_body = CapturedBody::default();
+    if let Some(text) = request["postData"]["text"].as_str() {
+        request_body.data = text.as_bytes().to_vec();
+        // 保存上限で切り詰めた記録は bodySize が実際の大きさを示す
+        request_body.size = request["bodySize"]
+            .as_u64()
+            .unwrap_or(request_body.data.len() as u64)
+            .max(request_body.data.len() as u64);
+        request_body.truncated = request_body.size > request_body.data.len() as u64;
+    }
+
+    let content = &response["content"];
+    let mut response_body = CapturedBody::default();
+    if let Some(text) = content["text"].as_str() {
+        response_body.data = if content["encoding"].as_str() == Some("base64") {
+            base64_decode(text).ok_or("response.content.text のbase64を解釈できません")?
+        } else {
+            text.as_bytes().to_vec()
+        };
+    }
+    response_body.size = content["size"]
+        .as_u64()
+        .unwrap_or(response_body.data.len() as u64);
+    response_body.truncated = response_body.size > response_body.data.len() as u64;
+
+    let timing = |name: &str| entry["timings"][name].as_f64().filter(|v| *v > 0.0).unwrap_or(0.0);
+    let duration_ms = entry["time"].as_f64().unwrap_or(0.0);
+    let wait_ms = timing("blocked") + timing("dns") + timing("connect") + timing("ssl") + timing("send") + timing("wait");
+
+    let status = match &response["status"] {
+        Value::Null => 0,
+        value => value
+            .as_u64()
+            .and_then(|status| u16::try_from(status).ok())
+            .ok_or_else(|| format!("response.status を解釈できません: {}", value))?,
+    };
+    let started = match entry["startedDateTime"].as_str() {
+        Some(value) => parse_time(value).ok_or_else(|| format!("startedDateTime を解釈できません: {}", value))?,
+        None => SystemTime::now(),
+    };
+
+    Ok(Exchange {
+        id: 0,
+        started,
+        target: entry["_target"].as_str().map(|t| t.to_string()),
+        method,
+        uri,
+        http_version: request["httpVersion"].as_str().unwrap_or("HTTP/1.1").to_string(),
+        request_headers: headers(&request["headers"]),
+        request_body,
+        status,
+        response_headers: headers(&response["headers"]),
+        response_body,
+        wait_ms,
+        duration_ms,
+        complete: true,
+        imported: true,
+    })
+}
+
+fn decode_component(value: &str) -> String {
+    let value = value.replace('+', " ");
+    urlencoding::decode(&value)
+        .map(|v| v.into_owned())
+        .unwrap_or(value)
+}
+
+// RFC 3339（2026-10-18T14:24:30.123Z / +09:00 などのオフセット付き）を解釈する
+pub fn parse_time(value: &str) -> Option<SystemTime> {
+    let (date, rest) = value.split_once(['T', ' '])?;
+    let mut date_parts = date.splitn(3, '-');
+    let year: i64 = date_parts.next()?.parse().ok()?;
+    let month: u32 = date_parts.next()?.parse().ok()?;
+    let day: u32 = date_parts.next()?.parse().ok()?;
+
+    let (time, offset_secs) = if let Some(time) = rest.strip_suffix(['Z', 'z']) {
+        (time, 0i64)
+    } else {
+        let pos = rest.rfind(['+', '-'])?;
+        let (time, offset) = rest.split_at(pos);
+        let sign = if offset.starts_with('-') { -1 } else { 1 };
+        let (hours, minutes) = offset[1..].split_once(':')?;
+        let hours: i64 = hours.parse().ok().filter(|h| (0..24).contains(h))?;
+        let minutes: i64 = minutes.parse().ok().filter(|m| (0..60).contains(m))?;
+        (time, sign * (hours * 3600 + minutes * 60))
+    };
+    let mut time_parts = time.splitn(3, ':');
+    let hour: i64 = time_parts.next()?.parse().ok()?;
+    let minute: i64 = time_parts.next()?.parse().ok()?;
+    let seconds: f64 = time_parts.next()?.parse().ok()?;
+
+    // 細工された値でも桁あふれやパニックにならないよう、範囲を確かめてから計算する
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) || !(0..24).contains(&hour) || !(0..60).contains(&minute) {
+        return None;
+    }
+    let days = days_from_civil(year, month, day)?;
+    let secs = days
+        .checked_mul(86400)?
+        .checked_add(hour * 3600 + minute * 60)?
+        .checked_sub(offset_secs)?;
+    let total = secs as f64 + seconds;
+    if total < 0.0 {
+        return None;
+    }
+    UNIX_EPOCH.checked_add(Duration::try_from_secs_f64(total).ok()?)
+}
+
+// 年月日を1970-01-01からの日数に変換する（access_log::civil_from_days の逆）
+fn days_from_civil(year: i64, month: u32, day: u32) -> Option<i64> {
+    let year = if month <= 2 { year.checked_sub(1)? } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let mp = (month as i64 + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era.checked_mul(146097)?.checked_add(doe - 719468)
+}
+
+const BASE64_CHARS: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+pub fn base64_encode(data: &[u8]) -> String {
+    let mut out = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let b = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+        let n = (b[0] as u32) << 16 | (b[1] as u32) << 8 | b[2] as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(BASE64_CHARS[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+pub fn base64_decode(text: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(text.len() / 4 * 3);
+    let mut buf = 0u32;
+    let mut bits = 0;
+    for c in text.bytes().filter(|c| !c.is_ascii_whitespace() && *c != b'=') {
+        let value = BASE64_CHARS.iter().position(|&b| b == c)? as u32;
+        buf = buf << 6 | value;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((buf >> bits) as u8);
+            buf &= (1 << bits) - 1;
+        }
+    }
+    Some(out)
+}
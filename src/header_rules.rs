@@ -0,0 +1,309 @@
+// ターゲットごとのリクエスト・レスポンスヘッダーの書き換えルール
+//
+// [[targets.request_headers]] / [[targets.response_headers]] に書いた順に、PortRooter の既定の書き換え
+// （Host や X-Forwarded-* の設定、CSP の削除など）の後で適用する。
+// keep は既定の書き換えを行わず、受け取った値をそのまま残す。
+
+use axum::http::{HeaderMap, HeaderName, HeaderValue};
+use serde::Deserialize;
+use std::collections::HashMap;
+use tracing::warn;
+
+use crate::Config;
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct HeaderRuleConfig {
+    pub action: RuleAction,
+    pub name: String,
+    // add / set / default の値（{{target}} などのテンプレートが使える）
+    #[serde(default)]
+    pub value: Option<String>,
+    // rename の変更後の名前
+    #[serde(default)]
+    pub to: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum RuleAction {
+    // 値を追加する（同名のヘッダーがあっても残す）
+    Add,
+    // 値を置き換える
+    Set,
+    // ヘッダーがない場合だけ設定する
+    Default,
+    Remove,
+    Rename,
+    // 既定の書き換えをせずに受け取った値のまま残す
+    Keep,
+}
+
+// テンプレートの要素
+#[derive(Debug)]
+enum Part {
+    Text(String),
+    Target,
+    Port,
+    Host,
+    Method,
+    Path,
+    Env(String),
+    Header(HeaderName),
+}
+
+#[derive(Debug)]
+struct Template(Vec<Part>);
+
+impl Template {
+    fn compile(text: &str) -> Result<Template, String> {
+        let mut parts = Vec::new();
+        let mut rest = text;
+        while let Some(start) = rest.find("{{") {
+            let Some(end) = rest[start..].find("}}") else {
+                return Err(format!("{{{{ が閉じていません: {}", text));
+            };
+            if start > 0 {
+                parts.push(Part::Text(rest[..start].to_string()));
+            }
+            let name = rest[start + 2..start + end].trim();
+            let part = match name {
+                "target" => Part::Target,
+                "port" => Part::Port,
+                "host" => Part::Host,
+                "method" => Part::Method,
+                "path" => Part::Path,
+                _ => {
+                    if let Some(var) = name.strip_prefix("env.") {
+                        Part::Env(var.to_string())
+                    } else if let Some(header) = name.strip_prefix("header.") {
+                        Part::Header(
+                            HeaderName::try_from(header).map_err(|_| format!("不正なヘッダー名です: {}", header))?,
+                        )
+                    } else {
+                        return Err(format!("不明なテンプレート変数です: {{{{{}}}}}", name));
+                    }
+                }
+            };
+            parts.push(part);
+            rest = &rest[start + end + 2..];
+        }
+        if !rest.is_empty() {
+            parts.push(Part::Text(rest.to_string()));
+        }
+        Ok(Template(parts))
+    }
+
+    // {{header.名前}} はそのルールを適用する時点のヘッダーの値（ない場合は空文字）
+    fn render(&self, context: &RuleContext, headers: &HeaderMap) -> String {
+        let mut out = String::new();
+        for part in &self.0 {
+            match part {
+                Part::Text(text) => out.push_str(text),
+                Part::Target => out.push_str(context.target),
+                Part::Port => out.push_str(&context.port.to_string()),
+                Part::Host => out.push_str(context.host),
+                Part::Method => out.push_str(context.method),
+                Part::Path => out.push_str(context.path),
+                Part::Env(var) => out.push_str(&std::env::var(var).unwrap_or_default()),
+                Part::Header(name) => {
+                    out.push_str(headers.get(name).and_then(|v| v.to_str().ok()).unwrap_or(""))
+                }
+            }
+        }
+        out
+    }
+}
+
+// テンプレートで使う値
+pub struct RuleContext<'a> {
+    // ターゲット名
+    pub target: &'a str,
+    // バックエンドのポート
+    pub port: u16,
+    // ブラウザが指定したホスト
+    pub host: &'a str,
+    pub method: &'a str,
+    // バックエンドへ転送するパス
+    pub path: &'a str,
+}
+
+#[derive(Debug)]
+enum Rule {
+    Add(HeaderName, Template),
+    Set(HeaderName, Template),
+    Default(HeaderName, Template),
+    Remove(HeaderName),
+    Rename(HeaderName, HeaderName),
+    Keep(HeaderName),
+}
+
+impl Rule {
+    fn compile(config: &HeaderRuleConfig) -> Result<Rule, String> {
+        let name = HeaderName::try_from(config.name.as_str())
+            .map_err(|_| format!("不正なヘッダー名です: {}", config.name))?;
+        let template = || {
+            config
+                .value
+                .as_deref()
+                .ok_or_else(|| format!("{} には value が必要です: {}", action_name(config.action), config.name))
+                .and_then(Template::compile)
+        };
+        Ok(match config.action {
+            RuleAction::Add => Rule::Add(name, template()?),
+            RuleAction::Set => Rule::Set(name, template()?),
+            RuleAction::Default => Rule::Default(name, template()?),
+            RuleAction::Remove => Rule::Remove(name),
+            RuleAction::Rename => {
+                let to = config
+                    .to
+                    .as_deref()
+                    .ok_or_else(|| format!("rename には to が必要です: {}", config.name))?;
+                let to = HeaderName::try_from(to).map_err(|_| format!("不正なヘッダー名です: {}", to))?;
+                Rule::Rename(name, to)
+            }
+            RuleAction::Keep => Rule::Keep(name),
+        })
+    }
+}
+
+fn action_name(action: RuleAction) -> &'static str {
+    match action {
+        RuleAction::Add => "add",
+        RuleAction::Set => "set",
+        RuleAction::Default => "default",
+        RuleAction::Remove => "remove",
+        RuleAction::Rename => "rename",
+        RuleAction::Keep => "keep",
+    }
+}
+
+// 一方向（リクエストまたはレスポンス）のルール
+#[derive(Debug, Default)]
+pub struct RuleSet {
+    rules: Vec<Rule>,
+}
+
+static EMPTY: RuleSet = RuleSet { rules: Vec::new() };
+
+// keep に指定したヘッダーの、既定の書き換え前の値（ない場合は空）
+pub struct Kept(Vec<(HeaderName, Vec<HeaderValue>)>);
+
+impl RuleSet {
+    fn compile(configs: &[HeaderRuleConfig]) -> Result<RuleSet, String> {
+        let rules = configs.iter().map(Rule::compile).collect::<Result<_, _>>()?;
+        Ok(RuleSet { rules })
+    }
+
+    // 既定の書き換えの前に呼び、keep するヘッダーの値を控えておく
+    pub fn keep(&self, headers: &HeaderMap) -> Kept {
+        Kept(
+            self.rules
+                .iter()
+                .filter_map(|rule| match rule {
+                    Rule::Keep(name) => Some((name.clone(), headers.get_all(name).iter().cloned().collect())),
+                    _ => None,
+                })
+                .collect(),
+        )
+    }
+
+    // 既定の書き換えの後に呼び、keep したヘッダーを戻してからルールを順に適用する
+    pub fn apply(&self, headers: &mut HeaderMap, kept: Kept, context: &RuleContext) {
+        for (name, values) in kept.0 {
+            headers.remove(&name);
+            for value in values {
+                headers.append(name.clone(), value);
+            }
+        }
+        for rule in &self.rules {
+            match rule {
+                Rule::Add(name, template) => {
+                    if let Some(value) = render_value(name, template, context, headers) {
+                        headers.append(name.clone(), value);
+                    }
+                }
+                Rule::Set(name, template) => {
+                    if let Some(value) = render_value(name, template, context, headers) {
+                        headers.insert(name.clone(), value);
+                    }
+                }
+                Rule::Default(name, template) => {
+                    if !headers.contains_key(name) {
+                        if let Some(value) = render_value(name, template, context, headers) {
+                            headers.insert(name.clone(), value);
+                        }
+                    }
+                }
+                Rule::Remove(name) => {
+                    headers.remove(name);
+                }
+                Rule::Rename(from, to) => {
+                    let values: Vec<HeaderValue> = headers.get_all(from).iter().cloned().collect();
+                    if values.is_empty() {
+                        continue;
+                    }
+                    headers.remove(from);
+                    headers.remove(to);
+                    for value in values {
+                        headers.append(to.clone(), value);
+                    }
+                }
+                Rule::Keep(_) => {}
+            }
+        }
+    }
+}
+
+fn render_value(name: &HeaderName, template: &Template, context: &RuleContext, headers: &HeaderMap) -> Option<HeaderValue> {
+    let value = template.render(context, headers);
+    match HeaderValue::try_from(value) {
+        Ok(value) => Some(value),
+        Err(_) => {
+            warn!("ヘッダー {} の値として使えない文字が含まれています（ターゲット: {}）", name, context.target);
+            None
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct TargetRules {
+    request: RuleSet,
+    response: RuleSet,
+}
+
+#[derive(Debug, Default)]
+pub struct HeaderRules {
+    targets: HashMap<String, TargetRules>,
+}
+
+impl HeaderRules {
+    // ターゲットへ転送するリクエストのルール
+    pub fn request(&self, target: &str) -> &RuleSet {
+        self.targets.get(target).map(|t| &t.request).unwrap_or(&EMPTY)
+    }
+
+    // ターゲットから返ってきたレスポンスのルール
+    pub fn response(&self, target: &str) -> &RuleSet {
+        self.targets.get(target).map(|t| &t.response).unwrap_or(&EMPTY)
+    }
+}
+
+// 起動時にすべてのルールを検証して組み立てる
+pub fn compile(config: &Config) -> Result<HeaderRules, String> {
+    let mut targets = HashMap::new();
+    for target in config
+        .targets
+        .iter()
+        .filter(|t| t.is_http() && !(t.request_headers.is_empty() && t.response_headers.is_empty()))
+    {
+        let describe = |e: String| format!("{}: {}", target.name, e);
+        targets.insert(
+            target.name.clone(),
+            TargetRules {
+                request: RuleSet::compile(&target.request_headers).map_err(describe)?,
+                response: RuleSet::compile(&target.response_headers).map_err(describe)?,
+            },
+        );
+    }
+    Ok(HeaderRules { targets })
+}
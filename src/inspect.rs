@@ -0,0 +1,667 @@
+// トラフィックインスペクター（/__portrooter/inspect）
+//
+// プロキシしたリクエストとレスポンスをメモリ上のリングバッファに保持し、ブラウザから確認できるようにする。
+// ボディは上限サイズまでだけ保存する。
+
+use axum::{
+    body::Body,
+    extract::{Path, Query, Request, State},
+    http::{HeaderMap, StatusCode},
+    middleware::Next,
+    response::{Html, IntoResponse, Json, Response},
+};
+use hyper::body::{Body as HttpBody, Bytes, Frame, SizeHint};
+use serde::Deserialize;
+use serde_json::json;
+use std::{
+    collections::VecDeque,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll},
+    time::{Instant, SystemTime},
+};
+
+use crate::{access_log::{self, AccessNote}, redact, AppState};
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct InspectConfig {
+    // 記録するかどうか
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    // 保持するリクエストの数
+    #[serde(default = "default_max_entries")]
+    pub max_entries: usize,
+    // 保存するボディの上限（KB）
+    #[serde(default = "default_max_body_kb")]
+    pub max_body_kb: usize,
+}
+
+impl Default for InspectConfig {
+    fn default() -> Self {
+        InspectConfig {
+            enabled: default_enabled(),
+            max_entries: default_max_entries(),
+            max_body_kb: default_max_body_kb(),
+        }
+    }
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+fn default_max_entries() -> usize {
+    500
+}
+
+fn default_max_body_kb() -> usize {
+    64
+}
+
+// 上限までのボディ
+#[derive(Debug, Clone, Default)]
+pub struct CapturedBody {
+    pub data: Vec<u8>,
+    // 実際に送受信したバイト数
+    pub size: u64,
+    pub truncated: bool,
+}
+
+impl CapturedBody {
+    fn push(&mut self, chunk: &[u8], limit: usize) {
+        self.size += chunk.len() as u64;
+        let room = limit.saturating_sub(self.data.len());
+        if chunk.len() > room {
+            self.truncated = true;
+        }
+        self.data.extend_from_slice(&chunk[..chunk.len().min(room)]);
+    }
+
+    // UTF-8として読めればテキストとして返す
+    pub fn text(&self) -> Option<&str> {
+        std::str::from_utf8(&self.data).ok()
+    }
+}
+
+// 1回分のリクエストとレスポンス
+#[derive(Debug, Clone)]
+pub struct Exchange {
+    pub id: u64,
+    pub started: SystemTime,
+    pub target: Option<String>,
+    pub method: String,
+    pub uri: String,
+    pub http_version: String,
+    pub request_headers: Vec<(String, String)>,
+    pub request_body: CapturedBody,
+    // レスポンス待ちの間は0
+    pub status: u16,
+    pub response_headers: Vec<(String, String)>,
+    pub response_body: CapturedBody,
+    // レスポンスヘッダーまでの時間（ミリ秒）
+    pub wait_ms: f64,
+    // レスポンスボディの送信完了までの時間（ミリ秒）
+    pub duration_ms: f64,
+    pub complete: bool,
+    // HARから読み込んだ記録
+    pub imported: bool,
+}
+
+impl Exchange {
+    // URI・ヘッダー・記録済みのボディの秘密情報を伏せる
+    pub fn redact(&mut self) {
+        let redactor = redact::get();
+        self.uri = redactor.text(&self.uri).into_owned();
+        redactor.header_pairs(&mut self.request_headers);
+        redactor.header_pairs(&mut self.response_headers);
+        redactor.body(&mut self.request_body.data);
+        redactor.body(&mut self.response_body.data);
+    }
+
+    fn summary(&self) -> serde_json::Value {
+        json!({
+            "id": self.id,
+            "started_at": access_log::format_times(self.started).1,
+            "target": self.target,
+            "method": self.method,
+            "uri": self.uri,
+            "status": self.status,
+            "content_type": header_value(&self.response_headers, "content-type"),
+            "response_size": self.response_body.size,
+            "wait_ms": self.wait_ms,
+            "duration_ms": self.duration_ms,
+            "complete": self.complete,
+            "imported": self.imported,
+        })
+    }
+
+    fn detail(&self) -> serde_json::Value {
+        let body = |body: &CapturedBody| {
+            // 受信中のボディはまだ伏せていないため、ここでも伏せる
+            let mut data = body.data.clone();
+            redact::get().body(&mut data);
+            json!({
+                "size": body.size,
+                "truncated": body.truncated,
+                "text": std::str::from_utf8(&data).ok(),
+            })
+        };
+        let mut value = self.summary();
+        value["http_version"] = json!(self.http_version);
+        value["request_headers"] = json!(self.request_headers);
+        value["request_body"] = body(&self.request_body);
+        value["response_headers"] = json!(self.response_headers);
+        value["response_body"] = body(&self.response_body);
+        value
+    }
+}
+
+pub fn header_value<'a>(headers: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|(n, _)| n.eq_ignore_ascii_case(name))
+        .map(|(_, v)| v.as_str())
+}
+
+pub fn header_pairs(headers: &HeaderMap) -> Vec<(String, String)> {
+    headers
+        .iter()
+        .map(|(name, value)| (name.to_string(), String::from_utf8_lossy(value.as_bytes()).to_string()))
+        .collect()
+}
+
+fn redacted_header_pairs(headers: &HeaderMap) -> Vec<(String, String)> {
+    let mut pairs = header_pairs(headers);
+    redact::get().header_pairs(&mut pairs);
+    pairs
+}
+
+pub type SharedExchange = Arc<Mutex<Exchange>>;
+
+// 記録したリクエストのリングバッファ
+pub struct Inspector {
+    config: InspectConfig,
+    next_id: AtomicU64,
+    entries: Mutex<VecDeque<SharedExchange>>,
+}
+
+impl Inspector {
+    pub fn new(config: &InspectConfig) -> Inspector {
+        Inspector {
+            config: config.clone(),
+            next_id: AtomicU64::new(1),
+            entries: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    fn body_limit(&self) -> usize {
+        self.config.max_body_kb.saturating_mul(1024)
+    }
+
+    pub fn next_id(&self) -> u64 {
+        self.next_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    // 古いものから捨てて上限を保つ
+    pub fn push(&self, exchange: SharedExchange) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.push_back(exchange);
+        while entries.len() > self.config.max_entries {
+            entries.pop_front();
+        }
+    }
+
+    // 現在保持しているリクエストのコピー（古い順）
+    pub fn snapshot(&self) -> Vec<Exchange> {
+        self.entries
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|e| e.lock().unwrap().clone())
+            .collect()
+    }
+
+    fn find(&self, id: u64) -> Option<Exchange> {
+        self.entries
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|e| e.lock().unwrap())
+            .find(|e| e.id == id)
+            .map(|e| e.clone())
+    }
+}
+
+// プロキシしたリクエストを記録するミドルウェア
+pub async fn middleware(State(state): State<AppState>, mut req: Request, next: Next) -> Response {
+    let inspector = state.inspector.clone();
+    // 管理用のエンドポイント自身は記録しない
+    if !inspector.config.enabled || req.uri().path().starts_with("/__portrooter/") {
+        return next.run(req).await;
+    }
+
+    let note = AccessNote::attach(&mut req);
+    let started = Instant::now();
+    let exchange = Arc::new(Mutex::new(Exchange {
+        id: inspector.next_id(),
+        started: SystemTime::now(),
+        target: None,
+        method: req.method().to_string(),
+        uri: redact::get().text(&req.uri().to_string()).into_owned(),
+        http_version: format!("{:?}", req.version()),
+        request_headers: redacted_header_pairs(req.headers()),
+        request_body: CapturedBody::default(),
+        status: 0,
+        response_headers: Vec::new(),
+        response_body: CapturedBody::default(),
+        wait_ms: 0.0,
+        duration_ms: 0.0,
+        complete: false,
+        imported: false,
+    }));
+    inspector.push(exchange.clone());
+
+    let limit = inspector.body_limit();
+    let req = req.map(|body| {
+        Body::new(CaptureBody {
+            inner: body,
+            exchange: exchange.clone(),
+            side: Side::Request,
+            limit,
+            started,
+        })
+    });
+
+    let response = next.run(req).await;
+    {
+        let mut exchange = exchange.lock().unwrap();
+        exchange.target = note.target();
+        exchange.status = response.status().as_u16();
+        exchange.response_headers = redacted_header_pairs(response.headers());
+        exchange.wait_ms = started.elapsed().as_secs_f64() * 1000.0;
+    }
+
+    response.map(|body| {
+        Body::new(CaptureBody {
+            inner: body,
+            exchange,
+            side: Side::Response,
+            limit,
+            started,
+        })
+    })
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Side {
+    Request,
+    Response,
+}
+
+// 流れるデータを上限まで写し取るボディ。レスポンス側は破棄されたときに完了とする
+struct CaptureBody {
+    inner: Body,
+    exchange: SharedExchange,
+    side: Side,
+    limit: usize,
+    started: Instant,
+}
+
+impl HttpBody for CaptureBody {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let poll = Pin::new(&mut self.inner).poll_frame(cx);
+        if let Poll::Ready(Some(Ok(frame))) = &poll {
+            if let Some(data) = frame.data_ref() {
+                let mut exchange = self.exchange.lock().unwrap();
+                let body = match self.side {
+                    Side::Request => &mut exchange.request_body,
+                    Side::Response => &mut exchange.response_body,
+                };
+                body.push(data, self.limit);
+            }
+        }
+        poll
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+impl Drop for CaptureBody {
+    fn drop(&mut self) {
+        let mut exchange = self.exchange.lock().unwrap();
+        // 記録し終えたボディの秘密情報を伏せる
+        match self.side {
+            Side::Request => redact::get().body(&mut exchange.request_body.data),
+            Side::Response => {
+                redact::get().body(&mut exchange.response_body.data);
+                exchange.duration_ms = self.started.elapsed().as_secs_f64() * 1000.0;
+                exchange.complete = true;
+            }
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Default)]
+pub struct EntryFilter {
+    #[serde(default)]
+    target: Option<String>,
+    // "404" や "4xx"、"5" のように先頭から比較する
+    #[serde(default)]
+    status: Option<String>,
+    // URIに含まれる文字列
+    #[serde(default)]
+    path: Option<String>,
+}
+
+impl EntryFilter {
+    fn matches(&self, exchange: &Exchange) -> bool {
+        let non_empty = |v: &Option<String>| v.as_deref().filter(|v| !v.is_empty()).map(str::to_string);
+        if let Some(target) = non_empty(&self.target) {
+            if exchange.target.as_deref() != Some(target.as_str()) {
+                return false;
+            }
+        }
+        if let Some(status) = non_empty(&self.status) {
+            let actual = exchange.status.to_string();
+            let matched = status.len() <= actual.len()
+                && status
+                    .chars()
+                    .zip(actual.chars())
+                    .all(|(want, got)| want.eq_ignore_ascii_case(&'x') || want == got);
+            if !matched {
+                return false;
+            }
+        }
+        if let Some(path) = non_empty(&self.path) {
+            if !exchange.uri.contains(&path) {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+// 一覧（新しい順）
+pub async fn entries_handler(State(state): State<AppState>, Query(filter): Query<EntryFilter>) -> Json<serde_json::Value> {
+    let entries: Vec<_> = state
+        .inspector
+        .snapshot()
+        .iter()
+        .rev()
+        .filter(|e| filter.matches(e))
+        .map(Exchange::summary)
+        .collect();
+    let targets: Vec<_> = state
+        .config
+        .targets
+        .iter()
+        .filter(|t| t.is_http())
+        .map(|t| t.name.clone())
+        .collect();
+    Json(json!({ "entries": entries, "targets": targets }))
+}
+
+// 1件の詳細（ヘッダーとボディ）
+pub async fn entry_handler(State(state): State<AppState>, Path(id): Path<u64>) -> Response {
+    match state.inspector.find(id) {
+        Some(exchange) => Json(exchange.detail()).into_response(),
+        None => (StatusCode::NOT_FOUND, "記録が見つかりません（古いものは破棄されています）").into_response(),
+    }
+}
+
+// インスペクター画面
+pub async fn page_handler() -> Html<&'static str> {
+    Html(INSPECT_PAGE)
+}
+
+const INSPECT_PAGE: &str = r#"<!DOCTYPE html>
+<html lang="ja">
+<head>
+    <meta charset="UTF-8">
+    <meta name="viewport" content="width=device-width, initial-scale=1.0">
+    <title>PortRooter - トラフィックインスペクター</title>
+    <style>
+        * {
+            margin: 0;
+            padding: 0;
+            box-sizing: border-box;
+        }
+        body {
+            font-family: -apple-system, BlinkMacSystemFont, 'Segoe UI', Roboto, 'Helvetica Neue', Arial, sans-serif;
+            background: #f4f5fb;
+            color: #333;
+            font-size: 13px;
+        }
+        header {
+            background: linear-gradient(135deg, #667eea 0%, #764ba2 100%);
+            color: white;
+            padding: 12px 20px;
+            display: flex;
+            gap: 12px;
+            align-items: center;
+            flex-wrap: wrap;
+        }
+        header h1 {
+            font-size: 18px;
+            margin-right: auto;
+        }
+        header a {
+            color: white;
+        }
+        input, select, button {
+            font: inherit;
+            padding: 4px 8px;
+            border-radius: 6px;
+            border: 1px solid #ccc;
+        }
+        main {
+            display: flex;
+            height: calc(100vh - 56px);
+        }
+        #list {
+            flex: 1;
+            overflow: auto;
+        }
+        #detail {
+            flex: 1;
+            overflow: auto;
+            background: white;
+            border-left: 1px solid #ddd;
+            padding: 16px;
+            display: none;
+        }
+        table {
+            width: 100%;
+            border-collapse: collapse;
+        }
+        th, td {
+            text-align: left;
+            padding: 4px 8px;
+            border-bottom: 1px solid #e4e4ee;
+            white-space: nowrap;
+        }
+        th {
+            position: sticky;
+            top: 0;
+            background: #e9eaf5;
+        }
+        td.uri {
+            max-width: 480px;
+            overflow: hidden;
+            text-overflow: ellipsis;
+        }
+        tbody tr {
+            cursor: pointer;
+        }
+        tbody tr:hover, tbody tr.selected {
+            background: #e6e9ff;
+        }
+        .s2 { color: #2e7d32; }
+        .s3 { color: #1565c0; }
+        .s4 { color: #ef6c00; }
+        .s5 { color: #c62828; }
+        .pending { color: #999; }
+        h2 {
+            font-size: 14px;
+            margin: 16px 0 6px;
+            color: #667eea;
+        }
+        pre {
+            background: #f8f9fa;
+            padding: 8px;
+            border-radius: 6px;
+            white-space: pre-wrap;
+            word-break: break-all;
+            max-height: 400px;
+            overflow: auto;
+        }
+    </style>
+</head>
+<body>
+    <header>
+        <h1>🔍 トラフィックインスペクター</h1>
+        <select id="target"><option value="">すべてのターゲット</option></select>
+        <input id="status" placeholder="ステータス（例: 4xx）" size="14">
+        <input id="path" placeholder="パスを含む" size="20">
+        <button id="pause">一時停止</button>
+        <button id="export">HARを保存</button>
+        <label><input type="file" id="import" accept=".har,application/json" hidden><button id="import-button">HARを読み込む</button></label>
+        <a href="/">ポート選択へ</a>
+    </header>
+    <main>
+        <div id="list">
+            <table>
+                <thead>
+                    <tr><th>時刻</th><th>ターゲット</th><th>メソッド</th><th>ステータス</th><th>URI</th><th>種類</th><th>サイズ</th><th>時間</th></tr>
+                </thead>
+                <tbody id="rows"></tbody>
+            </table>
+        </div>
+        <div id="detail"></div>
+    </main>
+    <script>
+        const $ = (id) => document.getElementById(id);
+        let paused = false;
+        let selected = null;
+
+        function cell(row, text, className) {
+            const td = row.insertCell();
+            td.textContent = text;
+            if (className) td.className = className;
+        }
+
+        function formatSize(bytes) {
+            if (bytes < 1024) return bytes + ' B';
+            if (bytes < 1024 * 1024) return (bytes / 1024).toFixed(1) + ' KB';
+            return (bytes / 1024 / 1024).toFixed(1) + ' MB';
+        }
+
+        async function refresh() {
+            if (paused) return;
+            const params = new URLSearchParams({ target: $('target').value, status: $('status').value, path: $('path').value });
+            const res = await fetch('/__portrooter/inspect/entries?' + params);
+            if (!res.ok) return;
+            const data = await res.json();
+
+            const select = $('target');
+            for (const name of data.targets) {
+                if (![...select.options].some((o) => o.value === name)) {
+                    select.add(new Option(name, name));
+                }
+            }
+
+            const rows = $('rows');
+            rows.innerHTML = '';
+            for (const e of data.entries) {
+                const row = rows.insertRow();
+                if (e.id === selected) row.className = 'selected';
+                cell(row, (e.imported ? '📥 ' : '') + e.started_at.substring(11, 23));
+                cell(row, e.target || '-');
+                cell(row, e.method);
+                cell(row, e.status || '...', e.status ? 's' + String(e.status)[0] : 'pending');
+                cell(row, e.uri, 'uri');
+                cell(row, e.content_type || '');
+                cell(row, formatSize(e.response_size));
+                cell(row, e.complete ? e.duration_ms.toFixed(0) + ' ms' : '...', e.complete ? '' : 'pending');
+                row.onclick = () => showDetail(e.id);
+            }
+        }
+
+        function section(parent, title, text) {
+            const h = document.createElement('h2');
+            h.textContent = title;
+            const pre = document.createElement('pre');
+            pre.textContent = text;
+            parent.append(h, pre);
+        }
+
+        function bodyText(body) {
+            if (body.size === 0) return '(なし)';
+            if (body.text === null) return '(バイナリ ' + formatSize(body.size) + ')';
+            return body.text + (body.truncated ? '\n…（' + formatSize(body.size) + ' のうち先頭のみ）' : '');
+        }
+
+        async function showDetail(id) {
+            selected = id;
+            const res = await fetch('/__portrooter/inspect/entries/' + id);
+            const detail = $('detail');
+            detail.style.display = 'block';
+            detail.innerHTML = '';
+            if (!res.ok) {
+                section(detail, 'エラー', await res.text());
+                return;
+            }
+            const e = await res.json();
+            const headers = (list) => list.map(([n, v]) => n + ': ' + v).join('\n');
+            section(detail, '概要', e.method + ' ' + e.uri + ' ' + e.http_version + '\n'
+                + 'ステータス: ' + (e.status || '応答待ち') + '\n'
+                + 'ターゲット: ' + (e.target || '-') + '\n'
+                + 'ヘッダーまで: ' + e.wait_ms.toFixed(1) + ' ms / 完了まで: ' + (e.complete ? e.duration_ms.toFixed(1) + ' ms' : '転送中'));
+            section(detail, 'リクエストヘッダー', headers(e.request_headers));
+            section(detail, 'リクエストボディ', bodyText(e.request_body));
+            section(detail, 'レスポンスヘッダー', headers(e.response_headers));
+            section(detail, 'レスポンスボディ', bodyText(e.response_body));
+        }
+
+        $('pause').onclick = () => {
+            paused = !paused;
+            $('pause').textContent = paused ? '再開' : '一時停止';
+            refresh();
+        };
+        $('export').onclick = () => {
+            const params = new URLSearchParams({ target: $('target').value });
+            location.href = '/__portrooter/inspect/har?' + params;
+        };
+        $('import-button').onclick = () => $('import').click();
+        $('import').onchange = async () => {
+            const file = $('import').files[0];
+            if (!file) return;
+            const res = await fetch('/__portrooter/inspect/har', { method: 'POST', body: file });
+            alert(res.ok ? (await res.json()).imported + ' 件を読み込みました' : await res.text());
+            $('import').value = '';
+            refresh();
+        };
+        for (const id of ['target', 'status', 'path']) {
+            $(id).oninput = refresh;
+        }
+        refresh();
+        setInterval(refresh, 1000);
+    </script>
+</body>
+</html>
+"#;
@@ -0,0 +1,312 @@
+// 待ち受けアドレス
+//
+// 既定では 127.0.0.1:{router_port} だけで待ち受ける。listen で IPv4 / IPv6 のアドレスや
+// Unix ドメインソケットのパスを複数指定できる。systemd のソケットアクティベーション（LISTEN_FDS）で
+// ソケットが渡されていれば、listen の代わりにそちらを使う。
+// Unix ドメインソケットからの接続は接続元のアドレスを持たず、ループバックとしては扱わない（forwarded::UnixPeer）。
+
+use axum::{Extension, Router};
+use std::{
+    collections::HashSet,
+    io,
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+};
+use tokio::{net::TcpListener, task::JoinHandle};
+use tracing::{error, info, warn};
+
+use crate::{forwarded, shutdown::Shutdown, Config};
+
+pub enum Listener {
+    Tcp(TcpListener),
+    // systemd から渡されたソケットはパスを持たない（終了時に削除しない）
+    #[cfg(unix)]
+    Unix(tokio::net::UnixListener, Option<PathBuf>),
+}
+
+enum ListenAddr {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+// "127.0.0.1:3015" / "[::1]:3015" / "0.0.0.0"（ポートは router_port） / "unix:/run/portrooter.sock" / "/run/portrooter.sock"
+fn parse(entry: &str, port: u16) -> Result<ListenAddr, String> {
+    if let Some(path) = entry.strip_prefix("unix:") {
+        return Ok(ListenAddr::Unix(PathBuf::from(path)));
+    }
+    if entry.starts_with('/') || entry.starts_with("./") {
+        return Ok(ListenAddr::Unix(PathBuf::from(entry)));
+    }
+    if let Ok(addr) = entry.parse::<SocketAddr>() {
+        return Ok(ListenAddr::Tcp(addr));
+    }
+    entry
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .parse::<IpAddr>()
+        .map(|ip| ListenAddr::Tcp(SocketAddr::new(ip, port)))
+        .map_err(|_| format!("listen のアドレスを解釈できません: {}", entry))
+}
+
+// 設定（または systemd から渡されたソケット）に従って待ち受けを始める
+pub async fn bind(config: &Config) -> Result<Vec<Listener>, String> {
+    #[cfg(unix)]
+    if let Some(listeners) = from_systemd()? {
+        return Ok(listeners);
+    }
+
+    let entries = if config.listen.is_empty() {
+        vec![format!("127.0.0.1:{}", config.router_port)]
+    } else {
+        config.listen.clone()
+    };
+    let mut listeners = Vec::new();
+    for entry in &entries {
+        let listener = match parse(entry, config.router_port)? {
+            ListenAddr::Tcp(addr) => TcpListener::bind(addr)
+                .await
+                .map(Listener::Tcp)
+                .map_err(|err| bind_error(&addr.to_string(), addr.port(), err))?,
+            #[cfg(unix)]
+            ListenAddr::Unix(path) => bind_unix(path)?,
+            #[cfg(not(unix))]
+            ListenAddr::Unix(path) => {
+                return Err(format!("この環境では Unix ドメインソケットを使えません: {}", path.display()))
+            }
+        };
+        listeners.push(listener);
+    }
+    Ok(listeners)
+}
+
+fn bind_error(addr: &str, port: u16, err: io::Error) -> String {
+    if err.kind() != io::ErrorKind::AddrInUse {
+        return format!("{} で待ち受けられません: {}", addr, err);
+    }
+    let holder = match port_holder(port) {
+        Some(holder) => format!("{} が使っています", holder),
+        None => "使っているプロセスは特定できませんでした（`ss -ltnp` などで確認してください）".to_string(),
+    };
+    format!(
+        "{} はすでに使われています。{}。そのプロセスを止めるか、router_port / listen を変えてください",
+        addr, holder
+    )
+}
+
+// /proc からポートで待ち受けているプロセスを探す（ほかのユーザーのプロセスは見えないことがある）
+fn port_holder(port: u16) -> Option<String> {
+    let inodes: HashSet<String> = ["/proc/net/tcp", "/proc/net/tcp6"]
+        .iter()
+        .filter_map(|path| std::fs::read_to_string(path).ok())
+        .flat_map(|table| {
+            table
+                .lines()
+                .skip(1)
+                .filter_map(|line| {
+                    let fields: Vec<&str> = line.split_whitespace().collect();
+                    let local_port = fields.get(1)?.rsplit(':').next()?;
+                    // 0A は LISTEN
+                    if *fields.get(3)? != "0A" || u16::from_str_radix(local_port, 16).ok()? != port {
+                        return None;
+                    }
+                    fields.get(9).map(|inode| inode.to_string())
+                })
+                .collect::<Vec<_>>()
+        })
+        .collect();
+    if inodes.is_empty() {
+        return None;
+    }
+    let sockets: HashSet<String> = inodes.iter().map(|inode| format!("socket:[{}]", inode)).collect();
+    for entry in std::fs::read_dir("/proc").ok()?.flatten() {
+        let Some(pid) = entry.file_name().to_str().and_then(|name| name.parse::<u32>().ok()) else {
+            continue;
+        };
+        let Ok(fds) = std::fs::read_dir(entry.path().join("fd")) else {
+            continue;
+        };
+        let holds = fds
+            .flatten()
+            .filter_map(|fd| std::fs::read_link(fd.path()).ok())
+            .any(|link| link.to_str().is_some_and(|link| sockets.contains(link)));
+        if holds {
+            let name = std::fs::read_to_string(entry.path().join("comm")).unwrap_or_default();
+            return Some(format!("pid {} の {}", pid, name.trim()));
+        }
+    }
+    None
+}
+
+#[cfg(unix)]
+fn bind_unix(path: PathBuf) -> Result<Listener, String> {
+    use std::os::unix::{fs::FileTypeExt, net::UnixStream};
+
+    // 前回の起動で残ったソケットファイルは、誰も待ち受けていなければ削除する
+    if let Ok(metadata) = std::fs::symlink_metadata(&path) {
+        if !metadata.file_type().is_socket() {
+            return Err(format!("{} はソケットではないファイルです", path.display()));
+        }
+        if UnixStream::connect(&path).is_ok() {
+            return Err(format!(
+                "{} はすでに使われています（別のプロセスが待ち受けています）",
+                path.display()
+            ));
+        }
+        std::fs::remove_file(&path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    }
+    tokio::net::UnixListener::bind(&path)
+        .map(|listener| Listener::Unix(listener, Some(path.clone())))
+        .map_err(|e| format!("{} で待ち受けられません: {}", path.display(), e))
+}
+
+// systemd のソケットアクティベーションで渡されたソケット（fd 3 から LISTEN_FDS 個）
+#[cfg(unix)]
+fn from_systemd() -> Result<Option<Vec<Listener>>, String> {
+    use std::os::fd::{FromRawFd, IntoRawFd};
+
+    let ours = std::env::var("LISTEN_PID")
+        .ok()
+        .and_then(|pid| pid.parse::<u32>().ok())
+        .is_some_and(|pid| pid == std::process::id());
+    if !ours {
+        return Ok(None);
+    }
+    let count = std::env::var("LISTEN_FDS")
+        .ok()
+        .and_then(|n| n.parse::<i32>().ok())
+        .unwrap_or(0);
+    // 子プロセスに引き継がないようにする
+    for name in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+        std::env::remove_var(name);
+    }
+    if count <= 0 {
+        return Ok(None);
+    }
+    info!("systemd から渡された{}個のソケットで待ち受けます（listen は使いません）", count);
+
+    (3..3 + count)
+        .map(|fd| {
+            // SAFETY: systemd が LISTEN_FDS で渡した、このプロセスが所有する待ち受けソケット
+            let tcp = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+            // アドレスファミリーが IP でなければ Unix ドメインソケットとして扱う
+            let listener = if tcp.local_addr().is_ok() {
+                tcp.set_nonblocking(true)
+                    .and_then(|_| TcpListener::from_std(tcp))
+                    .map(Listener::Tcp)
+            } else {
+                // SAFETY: 同じ fd を Unix ドメインソケットとして受け取り直す
+                let unix = unsafe { std::os::unix::net::UnixListener::from_raw_fd(tcp.into_raw_fd()) };
+                unix.set_nonblocking(true)
+                    .and_then(|_| tokio::net::UnixListener::from_std(unix))
+                    .map(|listener| Listener::Unix(listener, None))
+            };
+            listener.map_err(|e| format!("systemd から渡されたソケット（fd {}）を使えません: {}", fd, e))
+        })
+        .collect::<Result<Vec<_>, _>>()
+        .map(Some)
+}
+
+impl Listener {
+    // ログに出す待ち受け先
+    pub fn describe(&self) -> String {
+        match self {
+            Listener::Tcp(listener) => match listener.local_addr() {
+                Ok(addr) => format!("http://{}", addr),
+                Err(_) => "TCP".to_string(),
+            },
+            #[cfg(unix)]
+            Listener::Unix(listener, _) => match listener.local_addr().ok().and_then(|a| a.as_pathname().map(|p| p.to_path_buf())) {
+                Some(path) => format!("unix:{}", path.display()),
+                None => "unix:（名前なし）".to_string(),
+            },
+        }
+    }
+
+    // ブラウザで開くURL（ループバックとすべてのアドレスでの待ち受けは localhost で表す）
+    pub fn browser_url(&self) -> Option<String> {
+        match self {
+            Listener::Tcp(listener) => listener.local_addr().ok().map(|addr| {
+                if addr.ip().is_loopback() || addr.ip().is_unspecified() {
+                    format!("http://localhost:{}", addr.port())
+                } else {
+                    format!("http://{}", addr)
+                }
+            }),
+            #[cfg(unix)]
+            Listener::Unix(..) => None,
+        }
+    }
+
+    // ループバック以外のアドレスで待ち受けている
+    pub fn is_public(&self) -> bool {
+        match self {
+            Listener::Tcp(listener) => listener.local_addr().is_ok_and(|addr| !addr.ip().is_loopback()),
+            #[cfg(unix)]
+            Listener::Unix(..) => false,
+        }
+    }
+
+    // シャットダウンが始まったら新しい接続を受け付けず、処理中のリクエストが終わった接続から閉じる
+    pub fn serve(self, app: Router, shutdown: Shutdown) -> JoinHandle<()> {
+        match self {
+            Listener::Tcp(listener) => tokio::spawn(async move {
+                let server = axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>())
+                    .with_graceful_shutdown(async move { shutdown.started().await });
+                if let Err(err) = server.await {
+                    error!("待ち受けが異常終了しました: {}", err);
+                }
+            }),
+            #[cfg(unix)]
+            Listener::Unix(listener, path) => tokio::spawn(serve_unix(listener, path, app, shutdown)),
+        }
+    }
+}
+
+#[cfg(unix)]
+async fn serve_unix(listener: tokio::net::UnixListener, path: Option<PathBuf>, app: Router, shutdown: Shutdown) {
+    use hyper_util::{
+        rt::{TokioExecutor, TokioIo},
+        server::{conn::auto::Builder, graceful::GracefulShutdown},
+        service::TowerToHyperService,
+    };
+
+    let app = app.layer(Extension(forwarded::UnixPeer));
+    let graceful = GracefulShutdown::new();
+    loop {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = shutdown.started() => break,
+        };
+        let stream = match accepted {
+            Ok((stream, _)) => stream,
+            Err(err) => {
+                warn!("Unix ドメインソケットの接続の受け付けに失敗しました: {}", err);
+                // ファイルディスクリプタが尽きたときに空回りしないように少し待つ
+                tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+        let service = TowerToHyperService::new(app.clone());
+        let watcher = graceful.watcher();
+        tokio::spawn(async move {
+            let builder = Builder::new(TokioExecutor::new());
+            let connection = builder.serve_connection_with_upgrades(TokioIo::new(stream), service);
+            let _ = watcher.watch(connection.into_owned()).await;
+        });
+    }
+    drop(listener);
+    if let Some(path) = path {
+        let _ = std::fs::remove_file(&path);
+    }
+    graceful.shutdown().await;
+}
+
+// 待ち受け先をログに出す（ループバック以外で認証がなければ注意する）
+pub fn announce(listeners: &[Listener], config: &Config) {
+    for listener in listeners {
+        info!("待ち受け: {}", listener.describe());
+    }
+    if config.auth.is_none() && listeners.iter().any(Listener::is_public) {
+        warn!("ループバック以外のアドレスで待ち受けています。LANに公開する場合は [auth] の設定を検討してください");
+    }
+}
@@ -0,0 +1,295 @@
+// 構造化ログ（tracing）
+//
+// リクエストごとに request スパンを作り、X-Request-Id で相関をとる。
+// 既定ではリクエストごとに1行のアクセスログだけを出し、詳細はdebugレベルで出力する。
+// [logging.targets] でターゲットごとにレベルを上書きできる。
+
+use axum::{
+    extract::Request,
+    http::{HeaderName, HeaderValue},
+    middleware::Next,
+    response::Response,
+};
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    io::{IsTerminal, Write},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        OnceLock,
+    },
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+use tracing::{
+    field::{Field, Visit},
+    span, Instrument, Level, Metadata, Subscriber,
+};
+use tracing_subscriber::{
+    filter::LevelFilter,
+    fmt,
+    layer::{Context, Filter, SubscriberExt},
+    registry::LookupSpan,
+    util::SubscriberInitExt,
+    Layer,
+};
+
+use crate::redact;
+
+pub const X_REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+
+// リクエストスパンでターゲット名を保持するフィールド
+const TARGET_FIELD: &str = "target_name";
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct LoggingConfig {
+    // trace / debug / info / warn / error / off
+    #[serde(default = "default_level")]
+    pub level: String,
+    #[serde(default)]
+    pub format: LogFormat,
+    // ターゲット名ごとのログレベル
+    #[serde(default)]
+    pub targets: HashMap<String, String>,
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        LoggingConfig {
+            level: default_level(),
+            format: LogFormat::default(),
+            targets: HashMap::new(),
+        }
+    }
+}
+
+fn default_level() -> String {
+    "info".to_string()
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    // 1イベント1行
+    #[default]
+    Text,
+    // 複数行で読みやすく
+    Pretty,
+    // JSON Lines
+    Json,
+}
+
+fn parse_level(level: &str) -> Result<LevelFilter, String> {
+    level
+        .parse()
+        .map_err(|_| format!("不明なログレベルです: {}", level))
+}
+
+// グローバルなサブスクライバーを設定する
+pub fn init(config: &LoggingConfig) -> Result<(), String> {
+    started_at();
+    let filter = TargetLevelFilter {
+        default: parse_level(&config.level)?,
+        overrides: config
+            .targets
+            .iter()
+            .map(|(name, level)| Ok((name.clone(), parse_level(level)?)))
+            .collect::<Result<_, String>>()?,
+    };
+
+    // ファイルやパイプへ出力するときは色付けしない
+    let ansi = std::io::stdout().is_terminal();
+    let registry = tracing_subscriber::registry();
+    let writer = || RedactingWriter(std::io::stdout());
+    let result = match config.format {
+        LogFormat::Text => registry
+            .with(fmt::layer().compact().with_target(false).with_ansi(ansi).with_writer(writer).with_filter(filter))
+            .try_init(),
+        LogFormat::Pretty => registry
+            .with(fmt::layer().pretty().with_ansi(ansi).with_writer(writer).with_filter(filter))
+            .try_init(),
+        LogFormat::Json => registry
+            .with(fmt::layer().json().with_span_list(false).with_writer(writer).with_filter(filter))
+            .try_init(),
+    };
+    result.map_err(|e| e.to_string())
+}
+
+// 秘密情報を伏せてから書き出す（fmt レイヤーは1イベント分をまとめて書き込む）
+struct RedactingWriter<W>(W);
+
+impl<W: Write> Write for RedactingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let text = String::from_utf8_lossy(buf);
+        self.0.write_all(redact::get().text(&text).as_bytes())?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.0.flush()
+    }
+}
+
+// 現在のリクエストスパンにターゲット名を記録する（ターゲットごとのレベル上書きに使う）
+pub fn record_target(name: &str) {
+    tracing::Span::current().record(TARGET_FIELD, name);
+}
+
+// リクエストIDを付与し、リクエストごとに1行のアクセスログを出すミドルウェア
+pub async fn request_log_middleware(mut req: Request, next: Next) -> Response {
+    // 上流のプロキシから届いたIDはそのまま引き継ぐ
+    let request_id = req
+        .headers()
+        .get(&X_REQUEST_ID)
+        .and_then(|v| v.to_str().ok())
+        .filter(|v| !v.is_empty() && v.len() <= 128)
+        .map(|v| v.to_string())
+        .unwrap_or_else(new_request_id);
+    let request_id_value = HeaderValue::from_str(&request_id).ok();
+    if let Some(value) = &request_id_value {
+        // バックエンドにも同じIDを渡す
+        req.headers_mut().insert(X_REQUEST_ID, value.clone());
+    }
+
+    let span = tracing::info_span!("request", id = %request_id, target_name = tracing::field::Empty);
+    let method = req.method().clone();
+    let uri = req.uri().clone();
+    let started = Instant::now();
+
+    let mut response = next.run(req).instrument(span.clone()).await;
+
+    if let Some(value) = request_id_value {
+        response.headers_mut().insert(X_REQUEST_ID, value);
+    }
+    let status = response.status().as_u16();
+    let latency_ms = started.elapsed().as_millis() as u64;
+    span.in_scope(|| {
+        tracing::info!(%method, %uri, status, latency_ms, "リクエスト完了");
+    });
+
+    response
+}
+
+static REQUEST_COUNTER: AtomicU64 = AtomicU64::new(0);
+static STARTED_AT: OnceLock<u32> = OnceLock::new();
+
+// 起動時刻（UNIX時間の秒）。init で一度だけ読む
+fn started_at() -> u32 {
+    *STARTED_AT.get_or_init(|| {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs() as u32)
+            .unwrap_or_default()
+    })
+}
+
+// 起動時刻と連番から重複しにくいIDを作る
+fn new_request_id() -> String {
+    let seq = REQUEST_COUNTER.fetch_add(1, Ordering::Relaxed);
+    format!("{:08x}-{:06x}", started_at(), seq)
+}
+
+// リクエストスパンに付与する、ターゲットごとのログレベル
+struct SpanLevel(LevelFilter);
+
+// 全体のレベルに加えて、リクエストスパンのターゲット名に応じたレベルで絞り込むフィルター
+struct TargetLevelFilter {
+    default: LevelFilter,
+    overrides: HashMap<String, LevelFilter>,
+}
+
+impl TargetLevelFilter {
+    fn is_ours(meta: &Metadata<'_>) -> bool {
+        meta.target().starts_with(env!("CARGO_CRATE_NAME"))
+    }
+
+    fn max_level(&self) -> LevelFilter {
+        self.overrides
+            .values()
+            .copied()
+            .fold(self.default, LevelFilter::max)
+    }
+
+    // スパンのフィールドからターゲット名を読み取り、上書きレベルがあればスパンに付与する
+    fn apply_override<S>(&self, visitor: TargetNameVisitor, id: &span::Id, ctx: &Context<'_, S>)
+    where
+        S: Subscriber + for<'a> LookupSpan<'a>,
+    {
+        let Some(level) = visitor.0.and_then(|name| self.overrides.get(&name).copied()) else {
+            return;
+        };
+        if let Some(span) = ctx.span(id) {
+            span.extensions_mut().replace(SpanLevel(level));
+        }
+    }
+}
+
+impl<S> Filter<S> for TargetLevelFilter
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn enabled(&self, meta: &Metadata<'_>, cx: &Context<'_, S>) -> bool {
+        if !Self::is_ours(meta) {
+            return meta.level() <= &self.default;
+        }
+        // ターゲット名を記録するため、自前のスパンは常に有効にする
+        if meta.is_span() {
+            return true;
+        }
+        let level = cx
+            .lookup_current()
+            .and_then(|span| {
+                span.scope()
+                    .find_map(|s| s.extensions().get::<SpanLevel>().map(|l| l.0))
+            })
+            .unwrap_or(self.default);
+        meta.level() <= &level
+    }
+
+    fn callsite_enabled(&self, meta: &'static Metadata<'static>) -> tracing::subscriber::Interest {
+        use tracing::subscriber::Interest;
+        if !Self::is_ours(meta) {
+            return if meta.level() <= &self.default {
+                Interest::always()
+            } else {
+                Interest::never()
+            };
+        }
+        if meta.is_span() || meta.level() <= &self.max_level() {
+            Interest::sometimes()
+        } else {
+            Interest::never()
+        }
+    }
+
+    fn max_level_hint(&self) -> Option<LevelFilter> {
+        Some(self.max_level().max(LevelFilter::from_level(Level::INFO)))
+    }
+
+    fn on_new_span(&self, attrs: &span::Attributes<'_>, id: &span::Id, ctx: Context<'_, S>) {
+        let mut visitor = TargetNameVisitor(None);
+        attrs.record(&mut visitor);
+        self.apply_override(visitor, id, &ctx);
+    }
+
+    fn on_record(&self, id: &span::Id, values: &span::Record<'_>, ctx: Context<'_, S>) {
+        let mut visitor = TargetNameVisitor(None);
+        values.record(&mut visitor);
+        self.apply_override(visitor, id, &ctx);
+    }
+}
+
+struct TargetNameVisitor(Option<String>);
+
+impl Visit for TargetNameVisitor {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == TARGET_FIELD {
+            self.0 = Some(value.to_string());
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        if field.name() == TARGET_FIELD {
+            self.0 = Some(format!("{:?}", value));
+        }
+    }
+}
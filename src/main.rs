@@ -2,8 +2,9 @@ use axum::{
     body::Body,
     extract::{Path, Request, State},
     http::{self, header, HeaderName, StatusCode},
+    middleware,
     response::{Html, IntoResponse, Response},
-    routing::get,
+    routing::{get, patch, put},
     Router,
 };
 use http_body_util::BodyExt;
@@ -12,13 +13,78 @@ use hyper_util::{
     rt::TokioExecutor,
 };
 use serde::Deserialize;
-use std::{net::SocketAddr, sync::Arc, time::Duration};
-use tokio::time::timeout;
+use std::{process::ExitCode, sync::Arc, time::Instant};
+use std::sync::atomic::Ordering;
+use tracing::{debug, error, info, warn};
+
+mod access_log;
+mod auth;
+mod cors;
+mod csp;
+mod faults;
+mod forward;
+mod forwarded;
+mod har;
+mod header_rules;
+mod inspect;
+mod listen;
+mod logging;
+mod metrics;
+mod mirror;
+mod mocks;
+mod pool;
+mod rate_limit;
+mod redact;
+mod replay;
+mod request_body;
+mod routes;
+mod shutdown;
+mod streaming;
+mod tcp_forward;
+mod tls;
+mod udp_forward;
+mod upgrade;
 
 #[derive(Debug, Deserialize, Clone)]
 struct Config {
     router_port: u16,
+    // 待ち受けるアドレス（省略時は 127.0.0.1:{router_port}、Unix ドメインソケットのパスも可）
+    #[serde(default)]
+    listen: Vec<String>,
+    // SNIで振り分けるTCPターゲット用の共有TLSポート
+    #[serde(default)]
+    tls_sni_port: Option<u16>,
+    // HTTPS（HTTP/2）での待ち受け設定
+    #[serde(default)]
+    tls: Option<tls::TlsConfig>,
     targets: Vec<Target>,
+    // パスベースのルーティングルール（上から順に評価）
+    #[serde(default)]
+    routes: Vec<routes::RouteConfig>,
+    // すべてのリクエストに対するモックレスポンス（上から順に評価）
+    #[serde(default)]
+    mocks: Vec<mocks::MockConfig>,
+    // ログ出力の設定
+    #[serde(default)]
+    logging: logging::LoggingConfig,
+    // アクセスログの設定（省略時は出力しない）
+    #[serde(default)]
+    access_log: Option<access_log::AccessLogConfig>,
+    // トラフィックインスペクターの設定
+    #[serde(default)]
+    inspect: inspect::InspectConfig,
+    // LANに公開するときの認証（省略時は認証なし）
+    #[serde(default)]
+    auth: Option<auth::AuthConfig>,
+    // X-Forwarded-* / Forwarded ヘッダーの設定
+    #[serde(default)]
+    forwarded: forwarded::ForwardedConfig,
+    // ログとキャプチャの秘密情報の伏せ字（既定で有効）
+    #[serde(default)]
+    redact: redact::RedactConfig,
+    // SIGINT / SIGTERM での終了
+    #[serde(default)]
+    shutdown: shutdown::ShutdownConfig,
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -26,51 +92,375 @@ struct Target {
     name: String,
     port: u16,
     description: String,
+    #[serde(default)]
+    kind: TargetKind,
+    // TCP/UDPターゲットの待ち受けポート
+    #[serde(default)]
+    listen_port: Option<u16>,
+    // TCPターゲットを共有TLSポートで振り分けるためのサーバー名
+    #[serde(default)]
+    sni: Option<String>,
+    // UDPセッションのアイドルタイムアウト（秒）
+    #[serde(default)]
+    idle_timeout_secs: Option<u64>,
+    // バックエンドへHTTP/2（h2c prior knowledge）で接続する
+    #[serde(default)]
+    h2c: bool,
+    // チャンク転送のレスポンスを書き換えずにストリームとして流す
+    #[serde(default)]
+    stream_chunked: bool,
+    // レスポンスの記録・再生
+    #[serde(default)]
+    replay: Option<replay::ReplayConfig>,
+    // このターゲットへ転送するパスに対するモックレスポンス
+    #[serde(default)]
+    mocks: Vec<mocks::MockConfig>,
+    // 障害注入のルール（管理APIから実行中に変更できる）
+    #[serde(default)]
+    faults: Vec<faults::FaultConfig>,
+    // シャドウへのミラーリング
+    #[serde(default)]
+    mirror: Option<mirror::MirrorConfig>,
+    // バックエンドへ送るリクエストヘッダーの書き換えルール
+    #[serde(default)]
+    request_headers: Vec<header_rules::HeaderRuleConfig>,
+    // ブラウザへ返すレスポンスヘッダーの書き換えルール
+    #[serde(default)]
+    response_headers: Vec<header_rules::HeaderRuleConfig>,
+    // Content-Security-Policy の扱い（strip / preserve / rewrite）
+    #[serde(default)]
+    csp: csp::CspMode,
+    // 認証設定の上書き（enabled = false で認証なし）
+    #[serde(default)]
+    auth: Option<auth::AuthConfig>,
+    // 流量制限と同時実行数の制限
+    #[serde(default)]
+    rate_limit: Option<rate_limit::RateLimitConfig>,
+    // リクエストボディの上限（KB、省略時は無制限）
+    #[serde(default)]
+    max_request_body_kb: Option<u64>,
+    // CORSポリシー（プリフライトに答え、Access-Control-* を付ける）
+    #[serde(default)]
+    cors: Option<cors::CorsConfig>,
+    // Origin ヘッダーをバックエンドのオリジン（http://localhost:{port}）に書き換える
+    #[serde(default)]
+    spoof_origin: bool,
+    // バックエンドへの接続プールと keep-alive の設定
+    #[serde(default)]
+    pool: Option<pool::PoolConfig>,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum TargetKind {
+    #[default]
+    Http,
+    Tcp,
+    Udp,
+}
+
+impl Target {
+    fn is_http(&self) -> bool {
+        self.kind == TargetKind::Http
+    }
+
+    fn is_tcp(&self) -> bool {
+        self.kind == TargetKind::Tcp
+    }
+
+    fn is_udp(&self) -> bool {
+        self.kind == TargetKind::Udp
+    }
+}
+
+impl Config {
+    // HTTPプロキシの対象となるターゲットを名前で検索
+    fn http_target(&self, name: &str) -> Option<&Target> {
+        self.targets.iter().find(|t| t.is_http() && t.name == name)
+    }
 }
 
 #[derive(Clone)]
 struct AppState {
     config: Arc<Config>,
     client: Client<HttpConnector, Body>,
+    h2c_client: Client<HttpConnector, Body>,
+    pools: Arc<pool::Pools>,
+    forward_stats: forward::ForwardStatsMap,
+    routes: Arc<Vec<routes::Route>>,
+    access_log: Option<Arc<access_log::AccessLog>>,
+    metrics: Arc<metrics::Metrics>,
+    inspector: Arc<inspect::Inspector>,
+    replayers: replay::ReplayMap,
+    mocks: Arc<mocks::Mocks>,
+    faults: Arc<faults::Faults>,
+    mirrors: mirror::MirrorMap,
+    header_rules: Arc<header_rules::HeaderRules>,
+    auth: Arc<auth::Auth>,
+    forwarding: Arc<forwarded::Forwarding>,
+    limiters: rate_limit::LimiterMap,
+    cors: Arc<cors::Cors>,
+    shutdown: shutdown::Shutdown,
+}
+
+impl AppState {
+    // ターゲットのプロトコルに合ったクライアントを選び、リクエストのHTTPバージョンを揃える
+    // （ブラウザからのHTTP/2リクエストをHTTP/1.1のバックエンドへ送れるようにする）
+    fn client_for(&self, target: &Target, req: &mut Request) -> &Client<HttpConnector, Body> {
+        let (shared, version) = self.shared_client(target);
+        *req.version_mut() = version;
+        self.pools.client(&target.name).unwrap_or(shared)
+    }
+
+    // ターゲットの方式（HTTP/1.1 か h2c）に合わせた共有のクライアントと、リクエストに付けるバージョン
+    fn shared_client(&self, target: &Target) -> (&Client<HttpConnector, Body>, http::Version) {
+        if target.h2c {
+            (&self.h2c_client, http::Version::HTTP_2)
+        } else {
+            (&self.client, http::Version::HTTP_11)
+        }
+    }
+
+    // バックエンドへ送る（再利用した接続が閉じられていたら、送り直せるリクエストは新しい接続でもう一度送る）
+    async fn request_backend(
+        &self,
+        target: &Target,
+        mut req: Request,
+    ) -> Result<http::Response<hyper::body::Incoming>, hyper_util::client::legacy::Error> {
+        let client = self.client_for(target, &mut req);
+        self.pools.prepare(&target.name, &mut req);
+        let retry = pool::retry_copy(&req);
+        match client.request(req).await {
+            Err(err) if retry.is_some() && pool::is_stale_connection(&err) => {
+                debug!(error = ?err, "再利用した接続が閉じられていたため、新しい接続で送り直します: {}", target.name);
+                self.pools.fresh(target.h2c).request(retry.unwrap()).await
+            }
+            result => result,
+        }
+    }
+
+    // バックエンドへリクエストを送る（ボディの上限、流量制限と障害注入を適用し、モックに一致すればモック、記録・再生モードのターゲットはフィクスチャを使う）
+    async fn send_upstream(&self, target: &Target, mut req: Request) -> Result<Response, hyper_util::client::legacy::Error> {
+        let upload = match request_body::enforce(&mut req, target) {
+            Ok(upload) => upload,
+            Err(limit) => return Ok(request_body::too_large(&target.name, limit)),
+        };
+        // 同時実行数の枠はレスポンスのボディを送り終えるまで保持する
+        let result = match self.limiters.get(&target.name) {
+            None => self.send_admitted(target, req).await,
+            Some(limiter) => match limiter.admit(self.forwarding.client_ip(&req)).await {
+                Ok(admission) => self.send_admitted(target, req).await.map(|response| admission.hold(response)),
+                Err(response) => Ok(response),
+            },
+        };
+        // 上限を超えてボディの転送を打ち切った場合は接続エラーではなく 413 にする
+        match result {
+            Err(_) if upload.exceeded() => Ok(request_body::exceeded_response(&upload, &target.name)),
+            result => result,
+        }
+    }
+
+    async fn send_admitted(&self, target: &Target, mut req: Request) -> Result<Response, hyper_util::client::legacy::Error> {
+        if let Some(response) = faults::before_upstream(&self.faults, &target.name, &mut req).await {
+            return Ok(response);
+        }
+        if let Some(response) = self.mocks.respond_for_target(&target.name, &req) {
+            return Ok(response);
+        }
+        // ミラーリングする場合はボディを複製する（大きなボディは複製せずにそのまま流す）
+        let mirror = self.mirrors.get(&target.name).filter(|m| m.sampled());
+        let copy = match mirror {
+            Some(mirror) => mirror.copy_request(&mut req).await,
+            None => None,
+        };
+        let response = if let Some(replayer) = self.replayers.get(&target.name) {
+            replayer.send(self, target, req).await?
+        } else {
+            // WebSocket などのアップグレードは、バックエンドが 101 を返したら双方の接続をつなぐ
+            let upgrade = upgrade::take(&mut req);
+            let mut response = self.request_backend(target, req).await?;
+            if let Some(upgrade) = upgrade {
+                upgrade.tunnel(&target.name, &mut response, &self.shutdown);
+            }
+            response.map(Body::new)
+        };
+        Ok(match (mirror, copy) {
+            (Some(mirror), Some(copy)) => {
+                // シャドウも同じ方式で動いているものとして送る
+                let (client, version) = self.shared_client(target);
+                mirror.mirror(client, version, copy, response)
+            }
+            _ => response,
+        })
+    }
 }
 
 #[tokio::main]
-async fn main() {
+async fn main() -> ExitCode {
+    if std::env::args().any(|arg| arg == "--hash-password") {
+        auth::print_password_hash();
+        return ExitCode::SUCCESS;
+    }
+
     // 設定ファイルを読み込み
     let config_str = std::fs::read_to_string("config.toml")
         .expect("config.tomlを読み込めませんでした");
-    let config: Config = toml::from_str(&config_str)
+    let mut config: Config = toml::from_str(&config_str)
         .expect("config.tomlのパースに失敗しました");
+    replay::apply_cli_mode(&mut config);
+
+    redact::init(&config.redact).expect("[redact] の設定が不正です");
+    logging::init(&config.logging).expect("ログ出力の初期化に失敗しました");
+    if !redact::get().is_enabled() {
+        warn!("秘密情報の伏せ字を無効にしています（[redact] enabled = false）");
+    }
 
-    println!("🚀 PortRooter を起動中...");
-    println!("📝 集約ポート: {}", config.router_port);
-    println!("📋 登録されたターゲット:");
+    info!("PortRooter を起動中...");
+    info!("集約ポート: {}", config.router_port);
+    info!("登録されたターゲット:");
     for target in &config.targets {
-        println!("  - {} (localhost:{}): {}", target.name, target.port, target.description);
+        if !target.is_http() {
+            info!(
+                "  - {} [{} -> localhost:{}]: {}",
+                target.name,
+                forward::listen_label(target, &config),
+                target.port,
+                target.description
+            );
+        } else {
+            info!("  - {} (localhost:{}): {}", target.name, target.port, target.description);
+        }
     }
 
     let client = Client::builder(TokioExecutor::new()).build_http();
+    let h2c_client = Client::builder(TokioExecutor::new())
+        .http2_only(true)
+        .build_http();
+
+    let routes = routes::compile(&config).expect("[[routes]] の設定が不正です");
+    if !config.routes.is_empty() {
+        info!("ルーティングルール:");
+        for route in &config.routes {
+            info!("  - {} -> {}", route.pattern(), route.target);
+        }
+    }
+
+    let access_log = config.access_log.as_ref().map(|access_log_config| {
+        Arc::new(access_log::AccessLog::start(access_log_config).expect("アクセスログを開けませんでした"))
+    });
+
+    let forward_stats = forward::stats_for(&config);
+    tcp_forward::spawn(&config, &forward_stats).await;
+    udp_forward::spawn(&config, &forward_stats).await.expect("UDPターゲットの設定が不正です");
+
+    let metrics = Arc::new(metrics::Metrics::default());
+    metrics::spawn_health_checks(&config, metrics.clone());
+
+    let inspector = Arc::new(inspect::Inspector::new(&config.inspect));
+    let replayers = replay::replayers_for(&config).expect("記録・再生の設定が不正です");
+    let mocks = mocks::compile(&config).expect("モックの設定が不正です");
+    let faults = faults::compile(&config).expect("障害注入の設定が不正です");
+    let mirrors = mirror::mirrors_for(&config);
+    let limiters = rate_limit::limiters_for(&config);
+    let header_rules = header_rules::compile(&config).expect("ヘッダーの書き換えルールが不正です");
+    let auth = auth::compile(&config).expect("認証の設定が不正です");
+    let cors = cors::compile(&config).expect("CORSの設定が不正です");
+    let forwarding = forwarded::Forwarding::new(&config).expect("[forwarded] の設定が不正です");
+    for (scope, mock) in config
+        .mocks
+        .iter()
+        .map(|m| ("全体", m))
+        .chain(config.targets.iter().flat_map(|t| t.mocks.iter().map(move |m| (t.name.as_str(), m))))
+    {
+        info!(
+            "モック: [{}] {} {} -> {}",
+            scope,
+            mock.method.as_deref().unwrap_or("*").to_ascii_uppercase(),
+            mock.path,
+            mock.status
+        );
+    }
+
+    // 待ち受けを始める前に登録しておき、起動中に届いたシグナルでもグレースフルに終了する
+    let signals = shutdown::Signals::new();
+    let shutdown = shutdown::Shutdown::new();
+    let metrics_for_shutdown = metrics.clone();
 
     let state = AppState {
         config: Arc::new(config.clone()),
         client,
+        h2c_client,
+        pools: Arc::new(pool::Pools::new(&config)),
+        forward_stats,
+        routes: Arc::new(routes),
+        access_log,
+        metrics,
+        inspector,
+        replayers,
+        mocks: Arc::new(mocks),
+        faults: Arc::new(faults),
+        mirrors,
+        header_rules: Arc::new(header_rules),
+        auth: Arc::new(auth),
+        forwarding: Arc::new(forwarding),
+        limiters,
+        cors: Arc::new(cors),
+        shutdown: shutdown.clone(),
     };
 
     // ルーター設定
     let app = Router::new()
         .route("/", get(show_selector))
+        .route("/__portrooter/login", get(auth::login_handler))
+        .route("/__portrooter/metrics", get(metrics::metrics_handler))
+        .route("/__portrooter/inspect", get(inspect::page_handler))
+        .route("/__portrooter/inspect/entries", get(inspect::entries_handler))
+        .route("/__portrooter/inspect/entries/:id", get(inspect::entry_handler))
+        .route("/__portrooter/replay", get(replay::report_handler))
+        .route("/__portrooter/mirror", get(mirror::page_handler))
+        .route("/__portrooter/api/mirror", get(mirror::report_handler))
+        .route("/__portrooter/api/faults", get(faults::list_handler))
+        .route("/__portrooter/api/faults/:target", put(faults::replace_handler))
+        .route("/__portrooter/api/faults/:target/:index", patch(faults::update_handler))
+        .route("/__portrooter/inspect/har", get(har::export_handler).post(har::import_handler))
         .route("/proxy/:target_name", get(proxy_handler).post(proxy_handler))
-        .route("/proxy/:target_name/*path", get(proxy_handler).post(proxy_handler).put(proxy_handler).delete(proxy_handler).patch(proxy_handler))
-        .fallback(get(fallback_handler).post(fallback_handler).put(fallback_handler).delete(fallback_handler).patch(fallback_handler))
+        .route("/proxy/:target_name/*path", get(proxy_handler).post(proxy_handler).put(proxy_handler).delete(proxy_handler).patch(proxy_handler).options(proxy_handler))
+        .fallback(get(fallback_handler).post(fallback_handler).put(fallback_handler).delete(fallback_handler).patch(fallback_handler).options(fallback_handler))
+        .layer(middleware::from_fn_with_state(state.clone(), routes::route_middleware))
+        .layer(middleware::from_fn_with_state(state.clone(), mocks::middleware))
+        .layer(middleware::from_fn(faults::middleware))
+        .layer(middleware::from_fn_with_state(state.clone(), inspect::middleware))
+        .layer(middleware::from_fn_with_state(state.clone(), auth::middleware))
+        .layer(middleware::from_fn_with_state(state.clone(), cors::middleware))
+        .layer(middleware::from_fn_with_state(state.clone(), access_log::middleware))
+        .layer(middleware::from_fn_with_state(state.clone(), metrics::middleware))
+        .layer(middleware::from_fn(logging::request_log_middleware))
         .with_state(state);
 
-    let addr = SocketAddr::from(([127, 0, 0, 1], config.router_port));
-    let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
+    let listeners = match listen::bind(&config).await {
+        Ok(listeners) => listeners,
+        Err(err) => {
+            error!("{}", err);
+            return ExitCode::from(2);
+        }
+    };
+    listen::announce(&listeners, &config);
 
-    println!("\n✅ サーバー起動完了!");
-    println!("🌐 http://localhost:{} にアクセスしてください\n", config.router_port);
+    let url = listeners
+        .iter()
+        .find_map(listen::Listener::browser_url)
+        .unwrap_or_else(|| format!("http://localhost:{}", config.router_port));
+    info!("サーバー起動完了! {} にアクセスしてください", url);
+
+    let mut servers: Vec<_> = listeners
+        .into_iter()
+        .map(|listener| listener.serve(app.clone(), shutdown.clone()))
+        .collect();
+    if let Some(tls_config) = &config.tls {
+        servers.extend(tls::spawn(tls_config, app.clone(), shutdown.clone()).await);
+    }
 
-    axum::serve(listener, app).await.unwrap();
+    shutdown::run(signals, shutdown, &config.shutdown, servers, &metrics_for_shutdown).await
 }
 
 // ターゲット選択UIを表示
@@ -154,6 +544,36 @@ async fn show_selector(State(state): State<AppState>) -> Html<String> {
         .icon {
             margin-right: 8px;
         }
+        .target-card.forward {
+            cursor: default;
+        }
+        .target-card.forward:hover {
+            border-color: transparent;
+            transform: none;
+            box-shadow: none;
+        }
+        .target-stats {
+            font-size: 13px;
+            color: #888;
+            margin-top: 8px;
+        }
+        .target-faults {
+            font-size: 13px;
+            color: #c62828;
+            margin-top: 8px;
+        }
+        .target-limits {
+            font-size: 13px;
+            color: #888;
+            margin-top: 8px;
+        }
+        .tools {
+            margin-top: 24px;
+            font-size: 14px;
+        }
+        .tools a {
+            color: #667eea;
+        }
     </style>
 </head>
 <body>
@@ -164,25 +584,74 @@ async fn show_selector(State(state): State<AppState>) -> Html<String> {
 "#);
 
     for target in &state.config.targets {
+        if !target.is_http() {
+            let stats = &state.forward_stats[&target.name];
+            html.push_str(&format!(
+                r#"
+            <div class="target-card forward">
+                <div class="target-name"><span class="icon">🔌</span>{}</div>
+                <div class="target-port">{} → localhost:{}</div>
+                <div class="target-description">{}</div>
+                <div class="target-stats">アクティブ {} / 累計 {} ・ ↑ {} ↓ {}</div>
+            </div>
+"#,
+                html_escape::encode_text(&target.name),
+                html_escape::encode_text(&forward::listen_label(target, &state.config)),
+                target.port,
+                html_escape::encode_text(&target.description),
+                stats.active.load(Ordering::Relaxed),
+                stats.total.load(Ordering::Relaxed),
+                forward::format_bytes(stats.bytes_in.load(Ordering::Relaxed)),
+                forward::format_bytes(stats.bytes_out.load(Ordering::Relaxed)),
+            ));
+            continue;
+        }
+
+        // 有効な障害注入のルールを表示する
+        let faults: String = state
+            .faults
+            .active(&target.name)
+            .iter()
+            .map(|fault| format!(r#"<div class="target-faults">⚠️ 障害注入: {}</div>"#, html_escape::encode_text(fault)))
+            .collect();
+        // 流量制限のカウンター
+        let limits = state
+            .limiters
+            .get(&target.name)
+            .map(|limiter| format!(r#"<div class="target-limits">🚦 流量制限: {}</div>"#, html_escape::encode_text(&limiter.describe())))
+            .unwrap_or_default();
+
         html.push_str(&format!(
             r#"
             <a href="/proxy/{}" class="target-card">
                 <div class="target-name"><span class="icon">🎯</span>{}</div>
                 <div class="target-port">localhost:{}</div>
                 <div class="target-description">{}</div>
+                {}{}
             </a>
 "#,
             urlencoding::encode(&target.name),
             html_escape::encode_text(&target.name),
             target.port,
-            html_escape::encode_text(&target.description)
+            html_escape::encode_text(&target.description),
+            faults,
+            limits
         ));
     }
 
+    html.push_str("\n        </div>\n");
+    let mut tools = Vec::new();
+    if state.config.inspect.enabled {
+        tools.push(r#"<a href="/__portrooter/inspect">🔍 トラフィックインスペクター</a>"#);
+    }
+    if !state.mirrors.is_empty() {
+        tools.push(r#"<a href="/__portrooter/mirror">🪞 ミラーリングの差分</a>"#);
+    }
+    if !tools.is_empty() {
+        html.push_str(&format!("        <p class=\"tools\">{}</p>\n", tools.join(" ・ ")));
+    }
     html.push_str(
-        r#"
-        </div>
-    </div>
+        r#"    </div>
 </body>
 </html>
 "#,
@@ -191,19 +660,16 @@ async fn show_selector(State(state): State<AppState>) -> Html<String> {
     Html(html)
 }
 
-// フォールバックハンドラー（リファラーベースのルーティング）
-async fn fallback_handler(
-    State(state): State<AppState>,
-    mut req: Request,
-) -> Result<Response, StatusCode> {
+// フォールバックで転送するターゲット名をリファラー（またはOrigin）から決める
+fn fallback_target_name(config: &Config, headers: &http::HeaderMap) -> Option<String> {
     // リファラーヘッダーから対象ターゲットを抽出
-    let referer = req.headers()
+    let referer = headers
         .get(header::REFERER)
         .and_then(|r| r.to_str().ok())
         .unwrap_or("");
 
     // リファラーから /proxy/{target_name} の部分を抽出
-    let target_name = if let Some(proxy_pos) = referer.find("/proxy/") {
+    if let Some(proxy_pos) = referer.find("/proxy/") {
         let start = proxy_pos + "/proxy/".len();
         let remaining = &referer[start..];
         // 次の / または末尾までを取得
@@ -219,23 +685,31 @@ async fn fallback_handler(
     } else {
         // Refererに /proxy/ が含まれていない場合、
         // Originヘッダーをチェックしてプロキシ経由かどうか判断
-        let origin = req.headers()
+        let origin = headers
             .get(header::ORIGIN)
             .and_then(|o| o.to_str().ok())
             .unwrap_or("");
 
         // Originがプロキシサーバーのポートの場合、デフォルトターゲット（最初のターゲット）を使用
-        if origin.contains(&format!(":{}", state.config.router_port)) ||
-           referer.contains(&format!(":{}", state.config.router_port)) {
-            state.config.targets.first().map(|t| t.name.clone())
+        if origin.contains(&format!(":{}", config.router_port)) ||
+           referer.contains(&format!(":{}", config.router_port)) {
+            config.targets.iter().find(|t| t.is_http()).map(|t| t.name.clone())
         } else {
             None
         }
-    };
+    }
+}
+
+// フォールバックハンドラー（リファラーベースのルーティング）
+async fn fallback_handler(
+    State(state): State<AppState>,
+    mut req: Request,
+) -> Result<Response, StatusCode> {
+    let target_name = fallback_target_name(&state.config, req.headers());
 
     if let Some(target_name) = target_name {
         // ターゲットを検索
-        if let Some(target) = state.config.targets.iter().find(|t| t.name == target_name) {
+        if let Some(target) = state.config.http_target(&target_name) {
             // リクエストパスを取得（そのまま使う）
             let request_path = req.uri().path().to_string();
             let query = req.uri().query()
@@ -245,17 +719,34 @@ async fn fallback_handler(
             // プロキシURIを構築
             let proxy_uri = format!("http://localhost:{}{}{}", target.port, request_path, query);
 
-            println!("🔄 フォールバック: {} -> {}", req.uri(), proxy_uri);
+            logging::record_target(&target.name);
+            let access = access_log::AccessNote::from_request(&req);
+            access.upstream(&target.name, &proxy_uri);
+            debug!("フォールバック: {} -> {}", req.uri(), proxy_uri);
 
             let original_host = req.headers()
                 .get(header::HOST)
                 .and_then(|h| h.to_str().ok())
+                .or_else(|| req.uri().authority().map(|a| a.as_str()))
                 .unwrap_or("localhost")
                 .to_string();
+            let method = req.method().to_string();
+            let rule_context = header_rules::RuleContext {
+                target: &target.name,
+                port: target.port,
+                host: &original_host,
+                method: &method,
+                path: &request_path,
+            };
+            let request_rules = state.header_rules.request(&target.name);
+            let kept = request_rules.keep(req.headers());
 
             // URIを更新
             *req.uri_mut() = proxy_uri.parse().map_err(|_| StatusCode::BAD_REQUEST)?;
 
+            // X-Forwarded-* ヘッダーを追加（接続元を既存のチェーンに追加）
+            state.forwarding.apply(&mut req, &original_host, None);
+
             // ヘッダーを適切に設定
             let headers = req.headers_mut();
 
@@ -270,22 +761,8 @@ async fn fallback_handler(
                     .map_err(|_| StatusCode::BAD_REQUEST)?,
             );
 
-            // X-Forwarded-* ヘッダーを追加
-            headers.insert(
-                HeaderName::from_static("x-forwarded-for"),
-                "127.0.0.1".parse().unwrap(),
-            );
-            headers.insert(
-                HeaderName::from_static("x-forwarded-proto"),
-                "http".parse().unwrap(),
-            );
-            headers.insert(
-                HeaderName::from_static("x-forwarded-host"),
-                original_host.as_str().parse().unwrap(),
-            );
-
-            // Originヘッダーを更新
-            if headers.contains_key(header::ORIGIN) {
+            // Originヘッダーを更新（spoof_origin のターゲットだけ）
+            if target.spoof_origin && headers.contains_key(header::ORIGIN) {
                 headers.insert(
                     header::ORIGIN,
                     format!("http://localhost:{}", target.port)
@@ -295,7 +772,7 @@ async fn fallback_handler(
             }
 
             // Refererヘッダーを更新
-            if let Some(ref referer_value) = headers.get(header::REFERER).and_then(|r| r.to_str().ok()) {
+            if let Some(referer_value) = headers.get(header::REFERER).and_then(|r| r.to_str().ok()) {
                 if let Ok(referer_uri) = referer_value.parse::<http::Uri>() {
                     let new_referer = format!(
                         "http://localhost:{}{}",
@@ -309,15 +786,23 @@ async fn fallback_handler(
                 }
             }
 
-            // プロキシリクエストを送信（10秒のタイムアウト）
-            let response = match timeout(Duration::from_secs(90), state.client.request(req)).await {
+            // ターゲットごとの書き換えルールを適用
+            request_rules.apply(req.headers_mut(), kept, &rule_context);
+
+            let wants_stream = streaming::accepts_event_stream(req.headers());
+            let upload = request_body::track(&mut req);
+
+            // プロキシリクエストを送信（レスポンスヘッダーまでのタイムアウト）
+            let upstream_started = Instant::now();
+            let response = match streaming::wait_for_head(wants_stream, &upload, state.send_upstream(target, req)).await {
                 Ok(Ok(response)) => {
-                    println!("✅ フォールバック成功: ステータス {}", response.status());
+                    state.metrics.observe_upstream(&target.name, upstream_started.elapsed());
+                    debug!("フォールバック成功: ステータス {}", response.status());
                     response
                 }
                 Ok(Err(err)) => {
-                    eprintln!("❌ フォールバックプロキシエラー: {} -> {}", proxy_uri, err);
-                    eprintln!("   詳細: {:?}", err);
+                    state.metrics.upstream_connect_error(&target.name);
+                    warn!(error = ?err, "フォールバックプロキシエラー: {} -> {}", proxy_uri, err);
                     let error_body = format!("プロキシエラー: バックエンドサーバー {}:{} に接続できません\n詳細: {}",
                         target.name, target.port, err);
                     return Ok(Response::builder()
@@ -327,8 +812,9 @@ async fn fallback_handler(
                         .into_response());
                 }
                 Err(_) => {
-                    eprintln!("❌ フォールバックタイムアウト: {} (10秒)", proxy_uri);
-                    let error_body = format!("タイムアウト: バックエンドサーバー {}:{} が応答しません（10秒）",
+                    state.metrics.upstream_timeout(&target.name);
+                    warn!("フォールバックタイムアウト: {} (90秒)", proxy_uri);
+                    let error_body = format!("タイムアウト: バックエンドサーバー {}:{} が応答しません（90秒）",
                         target.name, target.port);
                     return Ok(Response::builder()
                         .status(StatusCode::GATEWAY_TIMEOUT)
@@ -340,10 +826,14 @@ async fn fallback_handler(
 
             // レスポンスを取得
             let (mut parts, body) = response.into_parts();
+            let response_rules = state.header_rules.response(&target.name);
+            let kept = response_rules.keep(&parts.headers);
+
+            let proxy_prefix = format!("/proxy/{}", urlencoding::encode(&target.name));
 
-            // CSPヘッダーを削除
-            parts.headers.remove(header::CONTENT_SECURITY_POLICY);
-            parts.headers.remove(HeaderName::from_static("content-security-policy-report-only"));
+            // CSPを扱う（既定では削除）
+            let mut csp_rewrite = csp::CspRewrite::new(&original_host, &proxy_prefix, target.port, false);
+            csp::apply_headers(target.csp, &mut parts.headers, &mut csp_rewrite);
 
             // Cross-Origin-Resource-Policyヘッダーを追加（SharedArrayBuffer/WASM対応）
             if !parts.headers.contains_key(HeaderName::from_static("cross-origin-resource-policy")) {
@@ -352,14 +842,18 @@ async fn fallback_handler(
                     "cross-origin".parse().unwrap(),
                 );
             }
+            response_rules.apply(&mut parts.headers, kept, &rule_context);
+
+            // SSEなどのストリームはバッファリングせずにそのまま返す
+            if wants_stream || streaming::is_streaming_response(&parts.headers, target) {
+                return Ok(Response::from_parts(parts, body).into_response());
+            }
 
             let content_type = parts.headers
                 .get(header::CONTENT_TYPE)
                 .and_then(|v| v.to_str().ok())
                 .unwrap_or("");
 
-            let proxy_prefix = format!("/proxy/{}", urlencoding::encode(&target.name));
-
             // JavaScript/TypeScriptファイルの場合、import文を変換
             if content_type.contains("javascript") || content_type.contains("typescript")
                || request_path.ends_with(".js") || request_path.ends_with(".mjs")
@@ -369,7 +863,7 @@ async fn fallback_handler(
                 let body_bytes = match body.collect().await {
                     Ok(collected) => collected.to_bytes(),
                     Err(err) => {
-                        eprintln!("❌ フォールバックJavaScriptボディ読み取りエラー: {:?}", err);
+                        warn!(error = ?err, "フォールバックJavaScriptボディ読み取りエラー");
                         let error_body = "JavaScriptレスポンスの読み取りに失敗しました";
                         return Ok(Response::builder()
                             .status(StatusCode::INTERNAL_SERVER_ERROR)
@@ -379,6 +873,8 @@ async fn fallback_handler(
                     }
                 };
 
+                access.rewrite("js");
+                let rewrite_started = Instant::now();
                 let mut content = String::from_utf8_lossy(&body_bytes).to_string();
 
                 // Viteのプリバンドルファイル（node_modules/.vite/deps/）は変換しない
@@ -401,6 +897,7 @@ async fn fallback_handler(
                     content = content.replace(&format!("import(\"{}/proxy/", proxy_prefix), "import(\"/proxy/");
                 }
 
+                state.metrics.observe_rewrite(&target.name, "js", rewrite_started.elapsed(), content.len());
                 let mut response = Response::new(Body::from(content));
                 *response.status_mut() = parts.status;
                 *response.headers_mut() = parts.headers;
@@ -411,7 +908,7 @@ async fn fallback_handler(
                 let body_bytes = match body.collect().await {
                     Ok(collected) => collected.to_bytes(),
                     Err(err) => {
-                        eprintln!("❌ フォールバックCSSボディ読み取りエラー: {:?}", err);
+                        warn!(error = ?err, "フォールバックCSSボディ読み取りエラー");
                         let error_body = "CSSレスポンスの読み取りに失敗しました";
                         return Ok(Response::builder()
                             .status(StatusCode::INTERNAL_SERVER_ERROR)
@@ -421,6 +918,8 @@ async fn fallback_handler(
                     }
                 };
 
+                access.rewrite("css");
+                let rewrite_started = Instant::now();
                 let mut content = String::from_utf8_lossy(&body_bytes).to_string();
 
                 // url()と@importを変換
@@ -437,6 +936,7 @@ async fn fallback_handler(
                 content = content.replace(&format!("@import '{}/proxy/", proxy_prefix), "@import '/proxy/");
                 content = content.replace(&format!("@import \"{}/proxy/", proxy_prefix), "@import \"/proxy/");
 
+                state.metrics.observe_rewrite(&target.name, "css", rewrite_started.elapsed(), content.len());
                 let mut response = Response::new(Body::from(content));
                 *response.status_mut() = parts.status;
                 *response.headers_mut() = parts.headers;
@@ -464,15 +964,13 @@ async fn proxy_handler(
     let target_name = params.get("target_name")
         .ok_or(StatusCode::BAD_REQUEST)?;
 
-    println!("🛰️ プロキシ要求を受信 target_name='{}'", target_name);
+    debug!("プロキシ要求を受信 target_name='{}'", target_name);
 
     // ターゲットを検索
-    let target = match state.config.targets
-        .iter()
-        .find(|t| &t.name == target_name) {
+    let target = match state.config.http_target(target_name) {
         Some(t) => t,
         None => {
-            eprintln!("❗ ターゲットが見つかりません: '{}'. 登録済み: {:?}", target_name, state.config.targets.iter().map(|t| t.name.clone()).collect::<Vec<_>>());
+            warn!("ターゲットが見つかりません: '{}'. 登録済み: {:?}", target_name, state.config.targets.iter().map(|t| t.name.clone()).collect::<Vec<_>>());
             return Err(StatusCode::NOT_FOUND);
         }
     };
@@ -503,17 +1001,35 @@ async fn proxy_handler(
     // 新しいURIを構築
     let proxy_uri = format!("http://localhost:{}{}{}", target.port, path, query);
 
-    println!("🔄 プロキシ: {} -> {}", req.uri(), proxy_uri);
+    logging::record_target(&target.name);
+    let access = access_log::AccessNote::from_request(&req);
+    access.upstream(&target.name, &proxy_uri);
+    debug!("プロキシ: {} -> {}", req.uri(), proxy_uri);
 
     let original_host = req.headers()
         .get(header::HOST)
         .and_then(|h| h.to_str().ok())
+        // HTTP/2では:authorityとして届く
+        .or_else(|| req.uri().authority().map(|a| a.as_str()))
         .unwrap_or("localhost")
         .to_string();
+    let method = req.method().to_string();
+    let rule_context = header_rules::RuleContext {
+        target: &target.name,
+        port: target.port,
+        host: &original_host,
+        method: &method,
+        path,
+    };
+    let request_rules = state.header_rules.request(&target.name);
+    let kept = request_rules.keep(req.headers());
 
     // URIを更新
     *req.uri_mut() = proxy_uri.parse().map_err(|_| StatusCode::BAD_REQUEST)?;
 
+    // X-Forwarded-* ヘッダーを追加（プロキシ経由であることを通知し、/proxy/{name} をプレフィックスとして伝える）
+    state.forwarding.apply(&mut req, &original_host, Some(&prefix));
+
     // ヘッダーを適切に設定
     let headers = req.headers_mut();
 
@@ -529,22 +1045,8 @@ async fn proxy_handler(
             .map_err(|_| StatusCode::BAD_REQUEST)?,
     );
 
-    // X-Forwarded-* ヘッダーを追加（プロキシ経由であることを通知）
-    headers.insert(
-        HeaderName::from_static("x-forwarded-for"),
-        "127.0.0.1".parse().unwrap(),
-    );
-    headers.insert(
-        HeaderName::from_static("x-forwarded-proto"),
-        "http".parse().unwrap(),
-    );
-    headers.insert(
-        HeaderName::from_static("x-forwarded-host"),
-        original_host.as_str().parse().unwrap(),
-    );
-
-    // Originヘッダーを更新（存在する場合）
-    if headers.contains_key(header::ORIGIN) {
+    // Originヘッダーを更新（spoof_origin のターゲットで、存在する場合）
+    if target.spoof_origin && headers.contains_key(header::ORIGIN) {
         headers.insert(
             header::ORIGIN,
             format!("http://localhost:{}", target.port)
@@ -569,15 +1071,23 @@ async fn proxy_handler(
         }
     }
 
-    // プロキシリクエストを送信（10秒のタイムアウト）
-    let response = match timeout(Duration::from_secs(90), state.client.request(req)).await {
+    // ターゲットごとの書き換えルールを適用
+    request_rules.apply(req.headers_mut(), kept, &rule_context);
+
+    let wants_stream = streaming::accepts_event_stream(req.headers());
+    let upload = request_body::track(&mut req);
+
+    // プロキシリクエストを送信（レスポンスヘッダーまでのタイムアウト）
+    let upstream_started = Instant::now();
+    let response = match streaming::wait_for_head(wants_stream, &upload, state.send_upstream(target, req)).await {
         Ok(Ok(response)) => {
-            println!("✅ プロキシ成功: ステータス {}", response.status());
+            state.metrics.observe_upstream(&target.name, upstream_started.elapsed());
+            debug!("プロキシ成功: ステータス {}", response.status());
             response
         }
         Ok(Err(err)) => {
-            eprintln!("❌ プロキシエラー: {} -> {}", proxy_uri, err);
-            eprintln!("   詳細: {:?}", err);
+            state.metrics.upstream_connect_error(&target.name);
+            warn!(error = ?err, "プロキシエラー: {} -> {}", proxy_uri, err);
             let error_body = format!("プロキシエラー: バックエンドサーバー {}:{} に接続できません\n詳細: {}",
                 target.name, target.port, err);
             return Ok(Response::builder()
@@ -587,8 +1097,9 @@ async fn proxy_handler(
                 .into_response());
         }
         Err(_) => {
-            eprintln!("❌ タイムアウト: {} (10秒)", proxy_uri);
-            let error_body = format!("タイムアウト: バックエンドサーバー {}:{} が応答しません（10秒）",
+            state.metrics.upstream_timeout(&target.name);
+            warn!("タイムアウト: {} (90秒)", proxy_uri);
+            let error_body = format!("タイムアウト: バックエンドサーバー {}:{} が応答しません（90秒）",
                 target.name, target.port);
             return Ok(Response::builder()
                 .status(StatusCode::GATEWAY_TIMEOUT)
@@ -600,20 +1111,27 @@ async fn proxy_handler(
 
     // レスポンスを取得
     let (mut parts, body) = response.into_parts();
+    let response_rules = state.header_rules.response(&target.name);
+    let kept = response_rules.keep(&parts.headers);
 
-    println!("📦 レスポンス情報:");
-    println!("   ステータス: {}", parts.status);
     let content_type = parts.headers
         .get(header::CONTENT_TYPE)
         .and_then(|v| v.to_str().ok())
         .unwrap_or("")
         .to_string();  // Stringに変換して借用を解放
-    println!("   Content-Type: {}", if content_type.is_empty() { "(なし)" } else { &content_type });
-    println!("   パス: {}", request_path);
+    debug!(
+        status = %parts.status,
+        content_type = if content_type.is_empty() { "(なし)" } else { content_type.as_str() },
+        path = %request_path,
+        "レスポンス情報"
+    );
+
+    let proxy_prefix = format!("/proxy/{}", urlencoding::encode(&target.name));
 
-    // CSPヘッダーを削除（プロキシ経由でのスクリプト実行を許可）
-    parts.headers.remove(header::CONTENT_SECURITY_POLICY);
-    parts.headers.remove(HeaderName::from_static("content-security-policy-report-only"));
+    // CSPを扱う（既定では削除してプロキシ経由でのスクリプト実行を許可）
+    let is_html = content_type.contains("text/html");
+    let mut csp_rewrite = csp::CspRewrite::new(&original_host, &proxy_prefix, target.port, is_html);
+    csp::apply_headers(target.csp, &mut parts.headers, &mut csp_rewrite);
 
     // Cross-Origin-Resource-Policyヘッダーを追加（SharedArrayBuffer/WASM対応）
     // Cross-Origin-Embedder-Policy: require-corp と互換性を持たせる
@@ -623,18 +1141,23 @@ async fn proxy_handler(
             "cross-origin".parse().unwrap(),
         );
     }
+    response_rules.apply(&mut parts.headers, kept, &rule_context);
 
-    let proxy_prefix = format!("/proxy/{}", urlencoding::encode(&target.name));
+    // SSEなどのストリームはバッファリングせずにそのまま返す
+    if wants_stream || streaming::is_streaming_response(&parts.headers, target) {
+        debug!("ストリーミングレスポンス（変換なし）");
+        return Ok(Response::from_parts(parts, body).into_response());
+    }
 
     // HTMLレスポンスの場合、<base>タグを挿入して絶対パスを変換
-    if content_type.contains("text/html") {
-        println!("🔧 HTML処理を開始");
+    if is_html {
+        debug!("HTML処理を開始");
 
         // ボディを読み取る
         let body_bytes = match body.collect().await {
             Ok(collected) => collected.to_bytes(),
             Err(err) => {
-                eprintln!("❌ HTMLボディ読み取りエラー: {:?}", err);
+                warn!(error = ?err, "HTMLボディ読み取りエラー");
                 let error_body = "HTMLレスポンスの読み取りに失敗しました";
                 return Ok(Response::builder()
                     .status(StatusCode::INTERNAL_SERVER_ERROR)
@@ -644,9 +1167,11 @@ async fn proxy_handler(
             }
         };
 
-        println!("   元のHTMLサイズ: {} bytes", body_bytes.len());
+        debug!("元のHTMLサイズ: {} bytes", body_bytes.len());
 
 
+        access.rewrite("html");
+        let rewrite_started = Instant::now();
         let html = String::from_utf8_lossy(&body_bytes);
 
         // <base>タグを挿入
@@ -674,25 +1199,8 @@ async fn proxy_handler(
             }
         };
 
-        // CSP metaタグを削除
-        // <meta http-equiv="Content-Security-Policy" ...> を削除
-        if let Some(start) = modified_html.find("<meta http-equiv=\"Content-Security-Policy\"") {
-            if let Some(end) = modified_html[start..].find('>') {
-                let end_pos = start + end + 1;
-                modified_html = format!("{}{}", &modified_html[..start], &modified_html[end_pos..]);
-            }
-        }
-        // 小文字版も対応
-        if let Some(start) = modified_html.find("<meta http-equiv='Content-Security-Policy'") {
-            if let Some(end) = modified_html[start..].find('>') {
-                let end_pos = start + end + 1;
-                modified_html = format!("{}{}", &modified_html[..start], &modified_html[end_pos..]);
-            }
-        }
-
         // HTML内の絶対パス（/で始まるパス）をプロキシパスに変換
         // <base>タグは絶対パスに適用されないため、手動で変換する必要がある
-        let proxy_prefix = format!("/proxy/{}", urlencoding::encode(&target.name));
 
         // src="/..." と href="/..." を src="/proxy/{target}/..." と href="/proxy/{target}/..." に変換
         // ただし、すでに /proxy/ で始まっているパスや http:// https:// で始まるURLは変換しない
@@ -726,8 +1234,12 @@ async fn proxy_handler(
         modified_html = modified_html.replace(&format!(".open(\"GET\", \"{}/proxy/", proxy_prefix), ".open(\"GET\", \"/proxy/");
         modified_html = modified_html.replace(&format!(".open(\"POST\", \"{}/proxy/", proxy_prefix), ".open(\"POST\", \"/proxy/");
 
+        // CSP metaタグを削除または書き換え、書き換えたインラインスクリプトに nonce を付ける
+        modified_html = csp::apply_html(target.csp, &html, modified_html, &csp_rewrite);
+
         // 新しいレスポンスを作成
-        println!("   変換後のHTMLサイズ: {} bytes", modified_html.len());
+        debug!("変換後のHTMLサイズ: {} bytes", modified_html.len());
+        state.metrics.observe_rewrite(&target.name, "html", rewrite_started.elapsed(), modified_html.len());
         let mut response = Response::new(Body::from(modified_html));
         *response.status_mut() = parts.status;
         *response.headers_mut() = parts.headers;
@@ -735,15 +1247,14 @@ async fn proxy_handler(
         // Content-Lengthを更新（変更されている可能性があるため）
         response.headers_mut().remove(header::CONTENT_LENGTH);
 
-        println!("✅ HTMLレスポンスを返却");
         Ok(response)
     } else if content_type.contains("css") || request_path.ends_with(".css") {
-        println!("🔧 CSS処理を開始");
+        debug!("CSS処理を開始");
         // CSSファイルの場合、url()と@importの絶対パスを変換
         let body_bytes = match body.collect().await {
             Ok(collected) => collected.to_bytes(),
             Err(err) => {
-                eprintln!("❌ CSSボディ読み取りエラー: {:?}", err);
+                warn!(error = ?err, "CSSボディ読み取りエラー");
                 let error_body = "CSSレスポンスの読み取りに失敗しました";
                 return Ok(Response::builder()
                     .status(StatusCode::INTERNAL_SERVER_ERROR)
@@ -753,6 +1264,8 @@ async fn proxy_handler(
             }
         };
 
+        access.rewrite("css");
+        let rewrite_started = Instant::now();
         let mut content = String::from_utf8_lossy(&body_bytes).to_string();
 
         // url('/path') を url('/proxy/{target}/path') に変換
@@ -771,24 +1284,24 @@ async fn proxy_handler(
         content = content.replace(&format!("@import '{}/proxy/", proxy_prefix), "@import '/proxy/");
         content = content.replace(&format!("@import \"{}/proxy/", proxy_prefix), "@import \"/proxy/");
 
+        state.metrics.observe_rewrite(&target.name, "css", rewrite_started.elapsed(), content.len());
         let mut response = Response::new(Body::from(content));
         *response.status_mut() = parts.status;
         *response.headers_mut() = parts.headers;
         response.headers_mut().remove(header::CONTENT_LENGTH);
 
-        println!("✅ CSSレスポンスを返却");
         Ok(response)
     } else if content_type.contains("javascript") || content_type.contains("typescript")
            || request_path.ends_with(".js") || request_path.ends_with(".mjs")
            || request_path.ends_with(".ts") || request_path.ends_with(".tsx")
            || request_path.contains(".js?") || request_path.contains(".mjs?")
            || request_path.contains(".ts?") || request_path.contains(".tsx?") {
-        println!("🔧 JavaScript処理を開始");
+        debug!("JavaScript処理を開始");
         // JavaScript/TypeScript ファイルの場合、import文の絶対パスを変換
         let body_bytes = match body.collect().await {
             Ok(collected) => collected.to_bytes(),
             Err(err) => {
-                eprintln!("❌ JavaScriptボディ読み取りエラー: {:?}", err);
+                warn!(error = ?err, "JavaScriptボディ読み取りエラー");
                 let error_body = "JavaScriptレスポンスの読み取りに失敗しました";
                 return Ok(Response::builder()
                     .status(StatusCode::INTERNAL_SERVER_ERROR)
@@ -798,6 +1311,8 @@ async fn proxy_handler(
             }
         };
 
+        access.rewrite("js");
+        let rewrite_started = Instant::now();
         let mut content = String::from_utf8_lossy(&body_bytes).to_string();
 
         // Viteのプリバンドルファイル（node_modules/.vite/deps/）は変換しない
@@ -822,18 +1337,17 @@ async fn proxy_handler(
         }
 
         // 新しいレスポンスを作成
+        state.metrics.observe_rewrite(&target.name, "js", rewrite_started.elapsed(), content.len());
         let mut response = Response::new(Body::from(content));
         *response.status_mut() = parts.status;
         *response.headers_mut() = parts.headers;
         response.headers_mut().remove(header::CONTENT_LENGTH);
 
-        println!("✅ JavaScriptレスポンスを返却");
         Ok(response)
     } else {
-        println!("🔧 その他のファイル（変換なし）");
+        debug!("その他のファイル（変換なし）");
         // その他のレスポンスはそのまま返す
         let response = Response::from_parts(parts, body);
-        println!("✅ レスポンスを返却");
         Ok(response.into_response())
     }
 }
@@ -0,0 +1,323 @@
+// Prometheusメトリクス（/__portrooter/metrics）
+//
+// 依存を増やさないよう、テキスト形式の出力は自前で組み立てる。
+
+use axum::{
+    body::Body,
+    extract::{Request, State},
+    http::header,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use hyper::body::{Body as HttpBody, Bytes, Frame, SizeHint};
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicI64, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::{net::TcpStream, time::timeout};
+
+use crate::{access_log::AccessNote, AppState, Config};
+
+// 上流の応答時間（秒）のバケット
+const UPSTREAM_BUCKETS: [f64; 13] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0];
+// 書き換え処理時間（秒）のバケット
+const REWRITE_BUCKETS: [f64; 9] = [0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25];
+
+// ヘルスチェックの間隔とタイムアウト
+const HEALTH_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(1);
+
+// ターゲットに紐づかないリクエスト（選択画面など）のラベル
+const NO_TARGET: &str = "(none)";
+
+#[derive(Default)]
+struct Histogram {
+    buckets: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn observe(&mut self, bounds: &[f64], value: f64) {
+        if self.buckets.is_empty() {
+            self.buckets = vec![0; bounds.len()];
+        }
+        for (bucket, bound) in self.buckets.iter_mut().zip(bounds) {
+            if value <= *bound {
+                *bucket += 1;
+            }
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+
+    fn render(&self, out: &mut String, name: &str, labels: &str, bounds: &[f64]) {
+        for (bucket, bound) in self.buckets.iter().zip(bounds) {
+            let _ = writeln!(out, "{}_bucket{{{},le=\"{}\"}} {}", name, labels, bound, bucket);
+        }
+        let _ = writeln!(out, "{}_bucket{{{},le=\"+Inf\"}} {}", name, labels, self.count);
+        let _ = writeln!(out, "{}_sum{{{}}} {}", name, labels, self.sum);
+        let _ = writeln!(out, "{}_count{{{}}} {}", name, labels, self.count);
+    }
+}
+
+#[derive(Default)]
+pub struct Metrics {
+    // (target, method, status_class)
+    requests: Mutex<BTreeMap<(String, String, String), u64>>,
+    upstream_latency: Mutex<BTreeMap<String, Histogram>>,
+    // (target, kind)
+    rewrite_duration: Mutex<BTreeMap<(String, &'static str), Histogram>>,
+    rewrite_bytes: Mutex<BTreeMap<(String, &'static str), u64>>,
+    // (target, kind) kind は timeout / connect
+    upstream_errors: Mutex<BTreeMap<(String, &'static str), u64>>,
+    in_flight: AtomicI64,
+    target_up: Mutex<BTreeMap<String, bool>>,
+}
+
+impl Metrics {
+    pub fn in_flight(&self) -> i64 {
+        self.in_flight.load(Ordering::Relaxed)
+    }
+
+    pub fn observe_upstream(&self, target: &str, elapsed: Duration) {
+        self.upstream_latency
+            .lock()
+            .unwrap()
+            .entry(target.to_string())
+            .or_default()
+            .observe(&UPSTREAM_BUCKETS, elapsed.as_secs_f64());
+    }
+
+    pub fn upstream_timeout(&self, target: &str) {
+        self.upstream_error(target, "timeout");
+    }
+
+    pub fn upstream_connect_error(&self, target: &str) {
+        self.upstream_error(target, "connect");
+    }
+
+    fn upstream_error(&self, target: &str, kind: &'static str) {
+        *self
+            .upstream_errors
+            .lock()
+            .unwrap()
+            .entry((target.to_string(), kind))
+            .or_default() += 1;
+    }
+
+    // HTML/CSS/JavaScriptの書き換えにかかった時間と書き換え後のサイズ
+    pub fn observe_rewrite(&self, target: &str, kind: &'static str, elapsed: Duration, bytes: usize) {
+        self.rewrite_duration
+            .lock()
+            .unwrap()
+            .entry((target.to_string(), kind))
+            .or_default()
+            .observe(&REWRITE_BUCKETS, elapsed.as_secs_f64());
+        *self
+            .rewrite_bytes
+            .lock()
+            .unwrap()
+            .entry((target.to_string(), kind))
+            .or_default() += bytes as u64;
+    }
+
+    fn record_request(&self, target: &str, method: &str, status: u16) {
+        let class = format!("{}xx", status / 100);
+        *self
+            .requests
+            .lock()
+            .unwrap()
+            .entry((target.to_string(), method.to_string(), class))
+            .or_default() += 1;
+    }
+
+    fn render(&self, state: &AppState) -> String {
+        let mut out = String::new();
+
+        out.push_str("# HELP portrooter_requests_total プロキシしたリクエスト数\n");
+        out.push_str("# TYPE portrooter_requests_total counter\n");
+        for ((target, method, class), count) in self.requests.lock().unwrap().iter() {
+            let _ = writeln!(
+                out,
+                "portrooter_requests_total{{target=\"{}\",method=\"{}\",status_class=\"{}\"}} {}",
+                escape(target),
+                escape(method),
+                class,
+                count
+            );
+        }
+
+        out.push_str("# HELP portrooter_in_flight_requests 処理中のリクエスト数\n");
+        out.push_str("# TYPE portrooter_in_flight_requests gauge\n");
+        let _ = writeln!(out, "portrooter_in_flight_requests {}", self.in_flight.load(Ordering::Relaxed));
+
+        out.push_str("# HELP portrooter_upstream_duration_seconds バックエンドがレスポンスヘッダーを返すまでの時間\n");
+        out.push_str("# TYPE portrooter_upstream_duration_seconds histogram\n");
+        for (target, histogram) in self.upstream_latency.lock().unwrap().iter() {
+            let labels = format!("target=\"{}\"", escape(target));
+            histogram.render(&mut out, "portrooter_upstream_duration_seconds", &labels, &UPSTREAM_BUCKETS);
+        }
+
+        out.push_str("# HELP portrooter_upstream_errors_total バックエンドへの転送エラー（timeout / connect）\n");
+        out.push_str("# TYPE portrooter_upstream_errors_total counter\n");
+        for ((target, kind), count) in self.upstream_errors.lock().unwrap().iter() {
+            let _ = writeln!(
+                out,
+                "portrooter_upstream_errors_total{{target=\"{}\",kind=\"{}\"}} {}",
+                escape(target),
+                kind,
+                count
+            );
+        }
+
+        out.push_str("# HELP portrooter_rewrite_duration_seconds HTML/CSS/JavaScriptの書き換えにかかった時間\n");
+        out.push_str("# TYPE portrooter_rewrite_duration_seconds histogram\n");
+        for ((target, kind), histogram) in self.rewrite_duration.lock().unwrap().iter() {
+            let labels = format!("target=\"{}\",kind=\"{}\"", escape(target), kind);
+            histogram.render(&mut out, "portrooter_rewrite_duration_seconds", &labels, &REWRITE_BUCKETS);
+        }
+
+        out.push_str("# HELP portrooter_rewrite_bytes_total 書き換え後のレスポンスのバイト数\n");
+        out.push_str("# TYPE portrooter_rewrite_bytes_total counter\n");
+        for ((target, kind), bytes) in self.rewrite_bytes.lock().unwrap().iter() {
+            let _ = writeln!(
+                out,
+                "portrooter_rewrite_bytes_total{{target=\"{}\",kind=\"{}\"}} {}",
+                escape(target),
+                kind,
+                bytes
+            );
+        }
+
+        out.push_str("# HELP portrooter_target_up ヘルスチェックの結果（1: 接続可能、0: 接続不可）\n");
+        out.push_str("# TYPE portrooter_target_up gauge\n");
+        for (target, up) in self.target_up.lock().unwrap().iter() {
+            let _ = writeln!(out, "portrooter_target_up{{target=\"{}\"}} {}", escape(target), u8::from(*up));
+        }
+
+        out.push_str("# HELP portrooter_forward_connections TCP接続・UDPセッションの数\n");
+        out.push_str("# TYPE portrooter_forward_connections gauge\n");
+        for (target, stats) in state.forward_stats.iter() {
+            let _ = writeln!(
+                out,
+                "portrooter_forward_connections{{target=\"{}\"}} {}",
+                escape(target),
+                stats.active.load(Ordering::Relaxed)
+            );
+        }
+        out.push_str("# HELP portrooter_forward_bytes_total TCP/UDPで転送したバイト数\n");
+        out.push_str("# TYPE portrooter_forward_bytes_total counter\n");
+        for (target, stats) in state.forward_stats.iter() {
+            let _ = writeln!(
+                out,
+                "portrooter_forward_bytes_total{{target=\"{}\",direction=\"in\"}} {}",
+                escape(target),
+                stats.bytes_in.load(Ordering::Relaxed)
+            );
+            let _ = writeln!(
+                out,
+                "portrooter_forward_bytes_total{{target=\"{}\",direction=\"out\"}} {}",
+                escape(target),
+                stats.bytes_out.load(Ordering::Relaxed)
+            );
+        }
+
+        out
+    }
+}
+
+// ラベル値のエスケープ
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+// メトリクスを返すハンドラー
+pub async fn metrics_handler(State(state): State<AppState>) -> Response {
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4; charset=utf-8")],
+        state.metrics.render(&state),
+    )
+        .into_response()
+}
+
+// リクエスト数と処理中のリクエスト数を記録するミドルウェア
+pub async fn middleware(State(state): State<AppState>, mut req: Request, next: Next) -> Response {
+    let note = AccessNote::attach(&mut req);
+    let method = req.method().to_string();
+
+    state.metrics.in_flight.fetch_add(1, Ordering::Relaxed);
+    let guard = InFlightGuard(state.metrics.clone());
+
+    let response = next.run(req).await;
+    let target = note.target().unwrap_or_else(|| NO_TARGET.to_string());
+    state.metrics.record_request(&target, &method, response.status().as_u16());
+
+    // ボディの送信が終わるまで処理中として数える
+    response.map(|body| Body::new(InFlightBody { inner: body, _guard: guard }))
+}
+
+struct InFlightGuard(Arc<Metrics>);
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        self.0.in_flight.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+struct InFlightBody {
+    inner: Body,
+    _guard: InFlightGuard,
+}
+
+impl HttpBody for InFlightBody {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        Pin::new(&mut self.inner).poll_frame(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+// HTTP/TCPターゲットへ定期的に接続を試し、結果を記録する
+pub fn spawn_health_checks(config: &Config, metrics: Arc<Metrics>) {
+    let targets: Vec<(String, u16)> = config
+        .targets
+        .iter()
+        .filter(|t| !t.is_udp())
+        .map(|t| (t.name.clone(), t.port))
+        .collect();
+    tokio::spawn(async move {
+        loop {
+            for (name, port) in &targets {
+                let up = matches!(
+                    timeout(HEALTH_CHECK_TIMEOUT, TcpStream::connect(("127.0.0.1", *port))).await,
+                    Ok(Ok(_))
+                );
+                metrics.target_up.lock().unwrap().insert(name.clone(), up);
+            }
+            tokio::time::sleep(HEALTH_CHECK_INTERVAL).await;
+        }
+    });
+}
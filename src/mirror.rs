@@ -0,0 +1,494 @@
+// トラフィックのミラーリング（新しい実装との比較用）
+//
+// ターゲットへのリクエストの一部を複製してシャドウ（別ポートで動く新しい実装）にも送る。
+// ブラウザにはメインのレスポンスだけを返し、シャドウのステータスやボディが異なれば差分として記録する。
+
+use axum::{
+    body::Body,
+    extract::{Request, State},
+    http::{header, HeaderMap, Method, Version},
+    response::{Html, Json, Response},
+};
+use http_body_util::BodyExt;
+use hyper::body::{Body as HttpBody, Bytes, Frame, SizeHint};
+use hyper_util::client::legacy::{connect::HttpConnector, Client};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::{
+    collections::{HashMap, VecDeque},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll},
+    time::{Duration, SystemTime},
+};
+use tokio::{sync::oneshot, time::timeout};
+use tracing::{debug, warn};
+
+use crate::{access_log, faults, redact, request_body, AppState, Config};
+
+// シャドウのレスポンスを待つ時間
+const SHADOW_TIMEOUT: Duration = Duration::from_secs(30);
+// 保持する差分の数
+const MAX_DIFFS: usize = 200;
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct MirrorConfig {
+    // シャドウのポート（localhost）
+    pub ports: Vec<u16>,
+    // 複製する割合（%）
+    #[serde(default = "default_sample_rate")]
+    pub sample_rate: f64,
+    // ステータスやボディが異なるときに差分を記録する
+    #[serde(default = "default_true")]
+    pub diff: bool,
+    // 比較するボディの上限（KB）
+    #[serde(default = "default_max_body_kb")]
+    pub max_body_kb: usize,
+}
+
+fn default_sample_rate() -> f64 {
+    100.0
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_max_body_kb() -> usize {
+    256
+}
+
+// 記録した差分
+#[derive(Debug, Clone, Serialize)]
+pub struct MirrorDiff {
+    time: String,
+    target: String,
+    shadow_port: u16,
+    method: String,
+    uri: String,
+    primary_status: u16,
+    // シャドウに接続できなかった場合は None
+    shadow_status: Option<u16>,
+    status_differs: bool,
+    body_differs: bool,
+    // 最初に異なる行の前後（またはエラー内容）
+    detail: String,
+}
+
+pub struct Mirror {
+    target: String,
+    config: MirrorConfig,
+    mirrored: AtomicU64,
+    differed: AtomicU64,
+    errors: AtomicU64,
+    diffs: Mutex<VecDeque<MirrorDiff>>,
+}
+
+pub type MirrorMap = Arc<HashMap<String, Arc<Mirror>>>;
+
+pub fn mirrors_for(config: &Config) -> MirrorMap {
+    Arc::new(
+        config
+            .targets
+            .iter()
+            .filter(|t| t.is_http())
+            .filter_map(|t| {
+                let mirror = t.mirror.as_ref().filter(|m| !m.ports.is_empty())?;
+                Some((
+                    t.name.clone(),
+                    Arc::new(Mirror {
+                        target: t.name.clone(),
+                        config: mirror.clone(),
+                        mirrored: AtomicU64::new(0),
+                        differed: AtomicU64::new(0),
+                        errors: AtomicU64::new(0),
+                        diffs: Mutex::new(VecDeque::new()),
+                    }),
+                ))
+            })
+            .collect(),
+    )
+}
+
+// シャドウに送るためのリクエストの複製
+pub struct RequestCopy {
+    method: Method,
+    uri: String,
+    headers: HeaderMap,
+    body: Bytes,
+}
+
+impl Mirror {
+    // このリクエストを複製するかどうか
+    pub fn sampled(&self) -> bool {
+        faults::random() * 100.0 < self.config.sample_rate
+    }
+
+    // リクエストボディが max_body_kb 以内なら複製を作り、元のリクエストには同じボディを入れ直す。
+    // 大きなボディは集めずにそのまま流し、このリクエストは複製しない
+    pub async fn copy_request(&self, req: &mut Request) -> Option<RequestCopy> {
+        let limit = self.config.max_body_kb.saturating_mul(1024);
+        let length = req
+            .headers()
+            .get(header::CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse::<usize>().ok());
+        if length.is_some_and(|length| length > limit) {
+            debug!("ボディが大きいため複製しません: {} {}", self.target, req.uri());
+            return None;
+        }
+        let body = std::mem::take(req.body_mut());
+        let bytes = match request_body::read_prefix(body, limit).await {
+            Ok(bytes) => bytes,
+            Err(body) => {
+                *req.body_mut() = body;
+                debug!("ボディが大きいため複製しません: {} {}", self.target, req.uri());
+                return None;
+            }
+        };
+        *req.body_mut() = Body::from(bytes.clone());
+        Some(RequestCopy {
+            method: req.method().clone(),
+            uri: req
+                .uri()
+                .path_and_query()
+                .map(|p| p.to_string())
+                .unwrap_or_else(|| "/".to_string()),
+            headers: req.headers().clone(),
+            body: bytes,
+        })
+    }
+
+    // シャドウへ送り、メインのレスポンスボディを写し取って比較する
+    pub fn mirror(
+        self: &Arc<Self>,
+        client: &Client<HttpConnector, Body>,
+        version: Version,
+        copy: RequestCopy,
+        response: Response,
+    ) -> Response {
+        self.mirrored.fetch_add(1, Ordering::Relaxed);
+        let limit = self.config.max_body_kb.saturating_mul(1024);
+        let (tx, rx) = oneshot::channel();
+        let primary_status = response.status().as_u16();
+        let response = response.map(|body| {
+            Body::new(TeeBody {
+                inner: body,
+                buf: Vec::new(),
+                limit,
+                sender: Some(tx),
+            })
+        });
+
+        let mirror = self.clone();
+        let copy = Arc::new(copy);
+        // シャドウへは同時に送る
+        let shadows: Vec<_> = self
+            .config
+            .ports
+            .iter()
+            .map(|&port| {
+                let client = client.clone();
+                let copy = copy.clone();
+                (port, tokio::spawn(async move { send_shadow(&client, version, port, &copy, limit).await }))
+            })
+            .collect();
+        tokio::spawn(async move {
+            let mut results = Vec::with_capacity(shadows.len());
+            for (port, handle) in shadows {
+                let result = handle.await.unwrap_or_else(|e| Err(e.to_string()));
+                results.push((port, result));
+            }
+            if !mirror.config.diff {
+                return;
+            }
+            let primary_body = rx.await.unwrap_or_default();
+            for (port, result) in results {
+                mirror.compare(&copy, port, primary_status, &primary_body, result);
+            }
+        });
+        response
+    }
+
+    fn compare(&self, copy: &RequestCopy, port: u16, primary_status: u16, primary_body: &[u8], shadow: Result<(u16, Bytes), String>) {
+        let mut diff = MirrorDiff {
+            time: access_log::format_times(SystemTime::now()).1,
+            target: self.target.clone(),
+            shadow_port: port,
+            method: copy.method.to_string(),
+            uri: redact::get().text(&copy.uri).into_owned(),
+            primary_status,
+            shadow_status: None,
+            status_differs: false,
+            body_differs: false,
+            detail: String::new(),
+        };
+        match shadow {
+            Ok((status, body)) => {
+                diff.shadow_status = Some(status);
+                diff.status_differs = status != primary_status;
+                diff.body_differs = body.as_ref() != primary_body;
+                if !diff.status_differs && !diff.body_differs {
+                    debug!("ミラー一致: {} {} (:{})", copy.method, copy.uri, port);
+                    return;
+                }
+                if diff.body_differs {
+                    diff.detail = first_difference(primary_body, &body);
+                }
+                self.differed.fetch_add(1, Ordering::Relaxed);
+            }
+            Err(err) => {
+                self.errors.fetch_add(1, Ordering::Relaxed);
+                diff.detail = err;
+            }
+        }
+        diff.detail = redact::get().text(&diff.detail).into_owned();
+        debug!("ミラー差分: {} {} (:{}) {}", copy.method, copy.uri, port, diff.detail);
+        let mut diffs = self.diffs.lock().unwrap();
+        diffs.push_back(diff);
+        while diffs.len() > MAX_DIFFS {
+            diffs.pop_front();
+        }
+    }
+}
+
+async fn send_shadow(
+    client: &Client<HttpConnector, Body>,
+    version: Version,
+    port: u16,
+    copy: &RequestCopy,
+    limit: usize,
+) -> Result<(u16, Bytes), String> {
+    let mut req = Request::new(Body::from(copy.body.clone()));
+    *req.method_mut() = copy.method.clone();
+    *req.version_mut() = version;
+    *req.uri_mut() = format!("http://localhost:{}{}", port, copy.uri)
+        .parse()
+        .map_err(|e| format!("{}", e))?;
+    *req.headers_mut() = copy.headers.clone();
+    if let Ok(host) = format!("localhost:{}", port).parse() {
+        req.headers_mut().insert(header::HOST, host);
+    }
+    let response = match timeout(SHADOW_TIMEOUT, client.request(req)).await {
+        Ok(Ok(response)) => response,
+        Ok(Err(err)) => {
+            warn!("シャドウに接続できません: localhost:{} -> {}", port, err);
+            return Err(format!("シャドウに接続できません: {}", err));
+        }
+        Err(_) => return Err(format!("シャドウが応答しません（{}秒）", SHADOW_TIMEOUT.as_secs())),
+    };
+    let status = response.status().as_u16();
+    let mut body = response.into_body();
+    let mut buf = Vec::new();
+    while let Some(frame) = body.frame().await {
+        let frame = frame.map_err(|e| format!("シャドウのボディを読み取れません: {}", e))?;
+        if let Some(data) = frame.data_ref() {
+            buf.extend_from_slice(&data[..data.len().min(limit.saturating_sub(buf.len()))]);
+        }
+    }
+    Ok((status, Bytes::from(buf)))
+}
+
+// 最初に異なる行とその次の数行
+fn first_difference(primary: &[u8], shadow: &[u8]) -> String {
+    let primary = String::from_utf8_lossy(primary);
+    let shadow = String::from_utf8_lossy(shadow);
+    let primary_lines: Vec<&str> = primary.lines().collect();
+    let shadow_lines: Vec<&str> = shadow.lines().collect();
+    let line = primary_lines
+        .iter()
+        .zip(&shadow_lines)
+        .position(|(a, b)| a != b)
+        .unwrap_or(primary_lines.len().min(shadow_lines.len()));
+    let excerpt = |lines: &[&str]| {
+        lines
+            .iter()
+            .skip(line)
+            .take(3)
+            .map(|l| l.chars().take(200).collect::<String>())
+            .collect::<Vec<_>>()
+            .join("\n")
+    };
+    format!(
+        "{}行目から異なります\n--- メイン\n{}\n+++ シャドウ\n{}",
+        line + 1,
+        excerpt(&primary_lines),
+        excerpt(&shadow_lines)
+    )
+}
+
+// メインのレスポンスボディを上限まで写し取り、送信が終わったら比較用に渡すボディ
+struct TeeBody {
+    inner: Body,
+    buf: Vec<u8>,
+    limit: usize,
+    sender: Option<oneshot::Sender<Vec<u8>>>,
+}
+
+impl HttpBody for TeeBody {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let poll = Pin::new(&mut self.inner).poll_frame(cx);
+        if let Poll::Ready(Some(Ok(frame))) = &poll {
+            if let Some(data) = frame.data_ref() {
+                let room = self.limit.saturating_sub(self.buf.len());
+                let data = data[..data.len().min(room)].to_vec();
+                self.buf.extend_from_slice(&data);
+            }
+        }
+        poll
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+impl Drop for TeeBody {
+    fn drop(&mut self) {
+        if let Some(sender) = self.sender.take() {
+            let _ = sender.send(std::mem::take(&mut self.buf));
+        }
+    }
+}
+
+fn report(state: &AppState) -> serde_json::Value {
+    let targets: serde_json::Map<String, serde_json::Value> = state
+        .mirrors
+        .iter()
+        .map(|(name, mirror)| {
+            let diffs: Vec<_> = mirror.diffs.lock().unwrap().iter().rev().cloned().collect();
+            (
+                name.clone(),
+                json!({
+                    "shadow_ports": mirror.config.ports,
+                    "sample_rate": mirror.config.sample_rate,
+                    "mirrored": mirror.mirrored.load(Ordering::Relaxed),
+                    "differed": mirror.differed.load(Ordering::Relaxed),
+                    "errors": mirror.errors.load(Ordering::Relaxed),
+                    "diffs": diffs,
+                }),
+            )
+        })
+        .collect();
+    json!({ "targets": targets })
+}
+
+// 差分レポート（JSON）
+pub async fn report_handler(State(state): State<AppState>) -> Json<serde_json::Value> {
+    Json(report(&state))
+}
+
+// 差分レポート（画面）
+pub async fn page_handler(State(state): State<AppState>) -> Html<String> {
+    let report = report(&state);
+    let mut html = String::from(
+        r#"<!DOCTYPE html>
+<html lang="ja">
+<head>
+    <meta charset="UTF-8">
+    <meta name="viewport" content="width=device-width, initial-scale=1.0">
+    <title>PortRooter - ミラーリングの差分</title>
+    <style>
+        body {
+            font-family: -apple-system, BlinkMacSystemFont, 'Segoe UI', Roboto, 'Helvetica Neue', Arial, sans-serif;
+            background: #f4f5fb;
+            color: #333;
+            font-size: 13px;
+            margin: 0;
+            padding: 20px;
+        }
+        h1 {
+            font-size: 20px;
+        }
+        h2 {
+            font-size: 16px;
+            color: #667eea;
+            margin-top: 24px;
+        }
+        .summary {
+            color: #666;
+        }
+        table {
+            width: 100%;
+            border-collapse: collapse;
+            background: white;
+        }
+        th, td {
+            text-align: left;
+            vertical-align: top;
+            padding: 4px 8px;
+            border-bottom: 1px solid #e4e4ee;
+        }
+        th {
+            background: #e9eaf5;
+        }
+        pre {
+            margin: 0;
+            white-space: pre-wrap;
+            word-break: break-all;
+        }
+        .differs {
+            color: #c62828;
+            font-weight: 600;
+        }
+    </style>
+</head>
+<body>
+    <h1>🪞 ミラーリングの差分</h1>
+    <p><a href="/">ポート選択へ</a> ・ <a href="/__portrooter/api/mirror">JSON</a></p>
+"#,
+    );
+    let targets = report["targets"].as_object().cloned().unwrap_or_default();
+    if targets.is_empty() {
+        html.push_str("    <p>ミラーリングが設定されたターゲットはありません</p>\n");
+    }
+    for (name, target) in &targets {
+        html.push_str(&format!(
+            "    <h2>{}</h2>\n    <p class=\"summary\">シャドウ {} ・ 複製 {}% ・ 送信 {} ・ 差分 {} ・ エラー {}</p>\n",
+            html_escape::encode_text(name),
+            html_escape::encode_text(&target["shadow_ports"].to_string()),
+            target["sample_rate"],
+            target["mirrored"],
+            target["differed"],
+            target["errors"],
+        ));
+        let diffs = target["diffs"].as_array().cloned().unwrap_or_default();
+        if diffs.is_empty() {
+            continue;
+        }
+        html.push_str("    <table>\n        <tr><th>時刻</th><th>リクエスト</th><th>シャドウ</th><th>ステータス</th><th>詳細</th></tr>\n");
+        for diff in diffs {
+            let shadow_status = diff["shadow_status"]
+                .as_u64()
+                .map(|s| s.to_string())
+                .unwrap_or_else(|| "-".to_string());
+            html.push_str(&format!(
+                "        <tr><td>{}</td><td>{} {}</td><td>:{}</td><td class=\"{}\">{} / {}</td><td><pre>{}</pre></td></tr>\n",
+                html_escape::encode_text(diff["time"].as_str().unwrap_or("")),
+                html_escape::encode_text(diff["method"].as_str().unwrap_or("")),
+                html_escape::encode_text(diff["uri"].as_str().unwrap_or("")),
+                diff["shadow_port"],
+                if diff["status_differs"].as_bool().unwrap_or(false) { "differs" } else { "" },
+                diff["primary_status"],
+                shadow_status,
+                html_escape::encode_text(diff["detail"].as_str().unwrap_or("")),
+            ));
+        }
+        html.push_str("    </table>\n");
+    }
+    html.push_str("</body>\n</html>\n");
+    Html(html)
+}
@@ -0,0 +1,350 @@
+// 設定ファイルで定義するモックレスポンス
+//
+// [[mocks]] は集約ポートへのすべてのリクエスト、[[targets.mocks]] はそのターゲットへ転送するパスに対して評価し、
+// 一致すればバックエンドに転送せずに返す（記録・再生より優先）。
+
+use axum::{
+    body::Body,
+    extract::{Request, State},
+    http::{header, HeaderName, HeaderValue, Method, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use serde::Deserialize;
+use std::{collections::HashMap, fs};
+use tracing::{debug, warn};
+
+use crate::{AppState, Config};
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct MockConfig {
+    // 対象とするHTTPメソッド（省略時はすべて）
+    #[serde(default)]
+    pub method: Option<String>,
+    // パスのパターン（/users/:id、/static/* など）
+    pub path: String,
+    #[serde(default = "default_status")]
+    pub status: u16,
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    // レスポンスボディ（body と body_file はどちらか一方）
+    #[serde(default)]
+    pub body: Option<String>,
+    #[serde(default)]
+    pub body_file: Option<String>,
+}
+
+fn default_status() -> u16 {
+    200
+}
+
+// パスパターンの要素
+#[derive(Debug)]
+enum Segment {
+    Literal(String),
+    // :name
+    Param(String),
+    // * または *name（残りすべて）
+    Rest(Option<String>),
+}
+
+#[derive(Debug)]
+pub struct Mock {
+    method: Option<Method>,
+    segments: Vec<Segment>,
+    status: StatusCode,
+    headers: Vec<(HeaderName, String)>,
+    body: MockBody,
+    path: String,
+}
+
+#[derive(Debug)]
+enum MockBody {
+    Inline(String),
+    // リクエストのたびに読み込む（編集がすぐに反映されるように）
+    File(String),
+}
+
+impl Mock {
+    fn compile(config: &MockConfig) -> Result<Mock, String> {
+        let method = config
+            .method
+            .as_deref()
+            .filter(|m| *m != "*")
+            .map(|m| m.to_ascii_uppercase().parse::<Method>().map_err(|_| format!("不明なメソッドです: {}", m)))
+            .transpose()?;
+        if !config.path.starts_with('/') {
+            return Err(format!("path は / で始めてください: {}", config.path));
+        }
+        let parts: Vec<&str> = config.path[1..].split('/').collect();
+        let mut segments = Vec::new();
+        for (i, part) in parts.iter().enumerate() {
+            let segment = if let Some(name) = part.strip_prefix(':') {
+                Segment::Param(name.to_string())
+            } else if let Some(name) = part.strip_prefix('*') {
+                if i != parts.len() - 1 {
+                    return Err(format!("* はパスの最後にだけ書けます: {}", config.path));
+                }
+                Segment::Rest(Some(name.to_string()).filter(|n| !n.is_empty()))
+            } else {
+                Segment::Literal(part.to_string())
+            };
+            segments.push(segment);
+        }
+        let status = StatusCode::from_u16(config.status).map_err(|_| format!("不正なステータスです: {}", config.status))?;
+        let headers = config
+            .headers
+            .iter()
+            .map(|(name, value)| {
+                HeaderName::try_from(name.as_str())
+                    .map(|name| (name, value.clone()))
+                    .map_err(|_| format!("不正なヘッダー名です: {}", name))
+            })
+            .collect::<Result<_, String>>()?;
+        let body = match (&config.body, &config.body_file) {
+            (Some(_), Some(_)) => return Err(format!("body と body_file はどちらか一方にしてください: {}", config.path)),
+            (Some(body), None) => MockBody::Inline(body.clone()),
+            (None, Some(file)) => MockBody::File(file.clone()),
+            (None, None) => MockBody::Inline(String::new()),
+        };
+        Ok(Mock {
+            method,
+            segments,
+            status,
+            headers,
+            body,
+            path: config.path.clone(),
+        })
+    }
+
+    // 一致すればパスパラメーターを返す
+    fn matches(&self, method: &Method, path: &str) -> Option<HashMap<String, String>> {
+        if self.method.as_ref().is_some_and(|m| m != method) {
+            return None;
+        }
+        let mut params = HashMap::new();
+        let mut parts = path.strip_prefix('/').unwrap_or(path).split('/');
+        for segment in &self.segments {
+            match segment {
+                Segment::Rest(name) => {
+                    let rest = parts.by_ref().collect::<Vec<_>>().join("/");
+                    if let Some(name) = name {
+                        params.insert(name.clone(), decode(&rest));
+                    }
+                    return Some(params);
+                }
+                Segment::Literal(literal) => {
+                    if parts.next()? != literal {
+                        return None;
+                    }
+                }
+                Segment::Param(name) => {
+                    let value = parts.next().filter(|v| !v.is_empty())?;
+                    params.insert(name.clone(), decode(value));
+                }
+            }
+        }
+        parts.next().is_none().then_some(params)
+    }
+
+    fn respond(&self, params: &HashMap<String, String>, query: Option<&str>) -> Response {
+        let query: HashMap<String, String> = query
+            .unwrap_or("")
+            .split('&')
+            .filter(|pair| !pair.is_empty())
+            .map(|pair| {
+                let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+                (decode(name), decode(value))
+            })
+            .collect();
+        let render = |text: &str, escape: Escape| render_template(text, params, &query, escape);
+
+        let body = match &self.body {
+            MockBody::Inline(body) => body.clone(),
+            MockBody::File(file) => match fs::read_to_string(file) {
+                Ok(body) => body,
+                Err(err) => {
+                    warn!("モックのファイルを読み込めません: {} -> {}", file, err);
+                    return (
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        format!("モック {} のファイルを読み込めません: {}\n詳細: {}", self.path, file, err),
+                    )
+                        .into_response();
+                }
+            },
+        };
+        // 埋め込む値はボディの種類に合わせてエスケープするので、Content-Type は埋め込む前に決める
+        let content_type = match self.headers.iter().find(|(name, _)| name == header::CONTENT_TYPE) {
+            Some((_, value)) => render(value, Escape::None),
+            None => guess_content_type(&self.body, &body).to_string(),
+        };
+        let body = render(&body, Escape::for_content_type(&content_type));
+
+        let mut response = Response::new(Body::from(body));
+        *response.status_mut() = self.status;
+        for (name, value) in &self.headers {
+            if let Ok(value) = HeaderValue::try_from(render(value, Escape::None)) {
+                response.headers_mut().append(name.clone(), value);
+            }
+        }
+        if !response.headers().contains_key(header::CONTENT_TYPE) {
+            if let Ok(value) = HeaderValue::try_from(content_type) {
+                response.headers_mut().insert(header::CONTENT_TYPE, value);
+            }
+        }
+        response
+            .headers_mut()
+            .insert(HeaderName::from_static("x-portrooter-mock"), HeaderValue::from_static("hit"));
+        response
+    }
+}
+
+// 埋め込む値のエスケープ方法
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Escape {
+    None,
+    // JSON の文字列として安全にする（" と \ と制御文字）
+    Json,
+    // HTML / XML のテキストや属性値として安全にする
+    Html,
+}
+
+impl Escape {
+    fn for_content_type(content_type: &str) -> Escape {
+        let essence = content_type.split(';').next().unwrap_or("").trim().to_ascii_lowercase();
+        if essence.ends_with("json") || essence.ends_with("javascript") {
+            Escape::Json
+        } else if essence.ends_with("html") || essence.ends_with("xml") {
+            Escape::Html
+        } else {
+            Escape::None
+        }
+    }
+
+    fn apply(self, value: &str, out: &mut String) {
+        match self {
+            Escape::None => out.push_str(value),
+            Escape::Json => {
+                let quoted = serde_json::to_string(value).unwrap_or_default();
+                out.push_str(&quoted[1..quoted.len() - 1]);
+            }
+            Escape::Html => {
+                for c in value.chars() {
+                    match c {
+                        '&' => out.push_str("&amp;"),
+                        '<' => out.push_str("&lt;"),
+                        '>' => out.push_str("&gt;"),
+                        '"' => out.push_str("&quot;"),
+                        '\'' => out.push_str("&#39;"),
+                        _ => out.push(c),
+                    }
+                }
+            }
+        }
+    }
+}
+
+// {{name}} をパスパラメーター、{{query.name}} をクエリパラメーターで置き換える（ない場合は空文字）。
+// 値は escape でエスケープし、{{{name}}} と書いた場合だけそのまま埋め込む
+fn render_template(text: &str, params: &HashMap<String, String>, query: &HashMap<String, String>, escape: Escape) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find("{{") {
+        let (open, close, escape) = if rest[start..].starts_with("{{{") {
+            ("{{{", "}}}", Escape::None)
+        } else {
+            ("{{", "}}", escape)
+        };
+        let Some(end) = rest[start..].find(close) else {
+            break;
+        };
+        out.push_str(&rest[..start]);
+        let name = rest[start + open.len()..start + end].trim();
+        let value = match name.strip_prefix("query.") {
+            Some(name) => query.get(name),
+            None => params.get(name),
+        };
+        escape.apply(value.map(String::as_str).unwrap_or(""), &mut out);
+        rest = &rest[start + end + close.len()..];
+    }
+    out.push_str(rest);
+    out
+}
+
+fn guess_content_type(source: &MockBody, body: &str) -> &'static str {
+    if let MockBody::File(file) = source {
+        match file.rsplit('.').next().unwrap_or("") {
+            "json" => return "application/json",
+            "html" | "htm" => return "text/html; charset=utf-8",
+            "js" | "mjs" => return "text/javascript; charset=utf-8",
+            "css" => return "text/css; charset=utf-8",
+            "xml" => return "application/xml",
+            _ => {}
+        }
+    }
+    let trimmed = body.trim_start();
+    if trimmed.starts_with('{') || trimmed.starts_with('[') {
+        "application/json"
+    } else {
+        "text/plain; charset=utf-8"
+    }
+}
+
+fn decode(value: &str) -> String {
+    urlencoding::decode(value)
+        .map(|v| v.into_owned())
+        .unwrap_or_else(|_| value.to_string())
+}
+
+// 全体のモックとターゲットごとのモック（定義順に評価）
+#[derive(Debug, Default)]
+pub struct Mocks {
+    global: Vec<Mock>,
+    targets: HashMap<String, Vec<Mock>>,
+}
+
+impl Mocks {
+    // ターゲットへ転送するリクエストに一致するモックがあればレスポンスを返す
+    pub fn respond_for_target(&self, target: &str, req: &Request) -> Option<Response> {
+        let mocks = self.targets.get(target)?;
+        respond(mocks, req)
+    }
+}
+
+fn respond(mocks: &[Mock], req: &Request) -> Option<Response> {
+    mocks.iter().find_map(|mock| {
+        let params = mock.matches(req.method(), req.uri().path())?;
+        debug!("モック: {} {} -> {}", req.method(), req.uri(), mock.path);
+        Some(mock.respond(&params, req.uri().query()))
+    })
+}
+
+// 起動時にすべてのモックを検証して組み立てる
+pub fn compile(config: &Config) -> Result<Mocks, String> {
+    let global = config
+        .mocks
+        .iter()
+        .map(Mock::compile)
+        .collect::<Result<_, String>>()?;
+    let mut targets = HashMap::new();
+    for target in config.targets.iter().filter(|t| t.is_http() && !t.mocks.is_empty()) {
+        let mocks = target
+            .mocks
+            .iter()
+            .map(|m| Mock::compile(m).map_err(|e| format!("{}: {}", target.name, e)))
+            .collect::<Result<_, String>>()?;
+        targets.insert(target.name.clone(), mocks);
+    }
+    Ok(Mocks { global, targets })
+}
+
+// 全体のモックに一致したリクエストには転送せずに返すミドルウェア
+pub async fn middleware(State(state): State<AppState>, req: Request, next: Next) -> Response {
+    if req.uri().path().starts_with("/__portrooter/") {
+        return next.run(req).await;
+    }
+    match respond(&state.mocks.global, &req) {
+        Some(response) => response,
+        None => next.run(req).await,
+    }
+}
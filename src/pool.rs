@@ -0,0 +1,148 @@
+// バックエンドへの接続プール
+//
+// [targets.pool] を書いたターゲットは専用のクライアントを使い、アイドル接続を保持する時間と数、
+// keep-alive の有無を変えられる（keep-alive が苦手な古い開発サーバー向け）。
+// 再利用した接続がバックエンド側で閉じられていて送信に失敗した場合は、
+// ボディのない冪等なリクエスト（GET / HEAD / OPTIONS / PUT / DELETE / TRACE）に限り新しい接続で1回だけ送り直す。
+
+use axum::{
+    body::{Body, HttpBody},
+    extract::Request,
+    http::{header, HeaderValue, Method},
+};
+use hyper_util::{
+    client::legacy::{connect::HttpConnector, Client},
+    rt::{TokioExecutor, TokioTimer},
+};
+use serde::Deserialize;
+use std::{collections::HashMap, error::Error as _, io, time::Duration};
+
+use crate::Config;
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct PoolConfig {
+    // アイドル接続を閉じるまでの秒数（省略時は90秒）
+    #[serde(default)]
+    pub idle_timeout_secs: Option<u64>,
+    // 保持するアイドル接続の最大数（省略時は無制限）
+    #[serde(default)]
+    pub max_idle_per_host: Option<usize>,
+    // false で接続を使い回さず、リクエストごとに Connection: close で閉じる
+    #[serde(default = "default_keep_alive")]
+    pub keep_alive: bool,
+}
+
+fn default_keep_alive() -> bool {
+    true
+}
+
+pub struct Pools {
+    // [targets.pool] を書いたターゲットのクライアント
+    targets: HashMap<String, Client<HttpConnector, Body>>,
+    // keep-alive を切ったターゲット
+    close: Vec<String>,
+    // 送り直し用（アイドル接続を持たず、必ず新しく接続する）
+    fresh: Client<HttpConnector, Body>,
+    fresh_h2c: Client<HttpConnector, Body>,
+}
+
+fn builder() -> hyper_util::client::legacy::Builder {
+    let mut builder = Client::builder(TokioExecutor::new());
+    builder.pool_timer(TokioTimer::new());
+    builder
+}
+
+impl Pools {
+    pub fn new(config: &Config) -> Pools {
+        let mut targets = HashMap::new();
+        let mut close = Vec::new();
+        for target in config.targets.iter().filter(|t| t.is_http()) {
+            let Some(pool) = &target.pool else {
+                continue;
+            };
+            let mut builder = builder();
+            builder.http2_only(target.h2c);
+            if let Some(secs) = pool.idle_timeout_secs {
+                builder.pool_idle_timeout(Duration::from_secs(secs));
+            }
+            if let Some(max) = pool.max_idle_per_host {
+                builder.pool_max_idle_per_host(max);
+            }
+            if !pool.keep_alive {
+                builder.pool_max_idle_per_host(0);
+                close.push(target.name.clone());
+            }
+            targets.insert(target.name.clone(), builder.build_http());
+        }
+        Pools {
+            targets,
+            close,
+            fresh: builder().pool_max_idle_per_host(0).build_http(),
+            fresh_h2c: builder().pool_max_idle_per_host(0).http2_only(true).build_http(),
+        }
+    }
+
+    pub fn client(&self, target: &str) -> Option<&Client<HttpConnector, Body>> {
+        self.targets.get(target)
+    }
+
+    pub fn fresh(&self, h2c: bool) -> &Client<HttpConnector, Body> {
+        if h2c {
+            &self.fresh_h2c
+        } else {
+            &self.fresh
+        }
+    }
+
+    // keep-alive を切ったターゲットには Connection: close を付ける（アップグレードのリクエストは除く）
+    pub fn prepare(&self, target: &str, req: &mut Request) {
+        if req.version() == axum::http::Version::HTTP_2 || req.headers().contains_key(header::UPGRADE) {
+            return;
+        }
+        if self.close.iter().any(|name| name == target) {
+            req.headers_mut().insert(header::CONNECTION, HeaderValue::from_static("close"));
+        }
+    }
+}
+
+// 送り直せるリクエストなら、ボディのない複製を作っておく
+pub fn retry_copy(req: &Request) -> Option<Request> {
+    let idempotent = matches!(
+        *req.method(),
+        Method::GET | Method::HEAD | Method::OPTIONS | Method::PUT | Method::DELETE | Method::TRACE
+    );
+    if !idempotent || !req.body().is_end_stream() {
+        return None;
+    }
+    let mut copy = Request::new(Body::empty());
+    *copy.method_mut() = req.method().clone();
+    *copy.uri_mut() = req.uri().clone();
+    *copy.version_mut() = req.version();
+    *copy.headers_mut() = req.headers().clone();
+    Some(copy)
+}
+
+// 再利用した接続が閉じられていたときのエラー（接続を確立できなかった場合は含めない）
+pub fn is_stale_connection(err: &hyper_util::client::legacy::Error) -> bool {
+    if err.is_connect() {
+        return false;
+    }
+    let mut source = err.source();
+    while let Some(err) = source {
+        if let Some(err) = err.downcast_ref::<hyper::Error>() {
+            if err.is_incomplete_message() || err.is_canceled() {
+                return true;
+            }
+        }
+        if let Some(err) = err.downcast_ref::<io::Error>() {
+            if matches!(
+                err.kind(),
+                io::ErrorKind::ConnectionReset | io::ErrorKind::ConnectionAborted | io::ErrorKind::BrokenPipe
+            ) {
+                return true;
+            }
+        }
+        source = err.source();
+    }
+    false
+}
@@ -0,0 +1,311 @@
+// ターゲットごとの流量制限（トークンバケット）と同時実行数の制限
+//
+// 上限を超えたリクエストは 429 と Retry-After を返す。同時実行数の上限に達したリクエストは
+// 空きが出るまで待ち、queue_timeout_ms を過ぎたら 503 を返す。
+
+use axum::{
+    body::Body,
+    http::{header, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+};
+use hyper::body::{Body as HttpBody, Bytes, Frame, SizeHint};
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+use tokio::{
+    sync::{OwnedSemaphorePermit, Semaphore},
+    time::timeout,
+};
+use tracing::warn;
+
+use crate::Config;
+
+// クライアントごとのバケットを掃除する目安の数
+const MAX_CLIENTS: usize = 1024;
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct RateLimitConfig {
+    // ターゲット全体の1秒あたりのリクエスト数
+    #[serde(default)]
+    pub requests_per_second: Option<f64>,
+    // ターゲット全体で一度に受け付けられる数（省略時は requests_per_second と同じ）
+    #[serde(default)]
+    pub burst: Option<f64>,
+    // クライアント（接続元アドレス）ごとの1秒あたりのリクエスト数
+    #[serde(default)]
+    pub per_client_per_second: Option<f64>,
+    #[serde(default)]
+    pub per_client_burst: Option<f64>,
+    // バックエンドへ同時に送るリクエストの上限
+    #[serde(default)]
+    pub max_concurrent: Option<usize>,
+    // 同時実行数の空きを待つ時間（ミリ秒）
+    #[serde(default = "default_queue_timeout_ms")]
+    pub queue_timeout_ms: u64,
+}
+
+fn default_queue_timeout_ms() -> u64 {
+    10_000
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Rate {
+    per_second: f64,
+    burst: f64,
+}
+
+impl Rate {
+    fn new(per_second: Option<f64>, burst: Option<f64>) -> Option<Rate> {
+        let per_second = per_second.filter(|r| *r > 0.0)?;
+        Some(Rate {
+            per_second,
+            burst: burst.filter(|b| *b >= 1.0).unwrap_or(per_second.max(1.0)),
+        })
+    }
+
+    fn bucket(&self) -> Bucket {
+        Bucket {
+            tokens: self.burst,
+            updated: Instant::now(),
+        }
+    }
+
+    // トークンを1つ使う。足りなければ次のトークンまでの時間を返す
+    fn take(&self, bucket: &mut Bucket) -> Result<(), Duration> {
+        let now = Instant::now();
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.per_second).min(self.burst);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / self.per_second))
+        }
+    }
+
+    // take で使ったトークンを戻す
+    fn refund(&self, bucket: &mut Bucket) {
+        bucket.tokens = (bucket.tokens + 1.0).min(self.burst);
+    }
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    passed: AtomicU64,
+    limited: AtomicU64,
+    queued: AtomicU64,
+    queue_timeouts: AtomicU64,
+    waiting: AtomicU64,
+}
+
+#[derive(Debug)]
+pub struct Limiter {
+    target: String,
+    rate: Option<(Rate, Mutex<Bucket>)>,
+    client_rate: Option<(Rate, Mutex<HashMap<IpAddr, Bucket>>)>,
+    concurrency: Option<(usize, Arc<Semaphore>)>,
+    queue_timeout: Duration,
+    counters: Counters,
+}
+
+// リクエストを通すときに返す。同時実行数の枠はレスポンスのボディを送り終えるまで持ち続ける
+pub struct Admission(Option<OwnedSemaphorePermit>);
+
+impl Admission {
+    pub fn hold(self, response: Response) -> Response {
+        match self.0 {
+            Some(permit) => response.map(|body| Body::new(PermitBody { inner: body, _permit: permit })),
+            None => response,
+        }
+    }
+}
+
+impl Limiter {
+    fn new(target: &str, config: &RateLimitConfig) -> Limiter {
+        Limiter {
+            target: target.to_string(),
+            rate: Rate::new(config.requests_per_second, config.burst).map(|rate| (rate, Mutex::new(rate.bucket()))),
+            client_rate: Rate::new(config.per_client_per_second, config.per_client_burst)
+                .map(|rate| (rate, Mutex::new(HashMap::new()))),
+            concurrency: config
+                .max_concurrent
+                .filter(|max| *max > 0)
+                .map(|max| (max, Arc::new(Semaphore::new(max)))),
+            queue_timeout: Duration::from_millis(config.queue_timeout_ms),
+            counters: Counters::default(),
+        }
+    }
+
+    // リクエスト数の枠を1つ使う（超えていれば次の枠までの時間と、超えた枠の説明）。
+    // クライアントごとの枠を使ってからターゲット全体の枠を確かめ、全体で断ったらクライアントの枠は戻す
+    fn take_tokens(&self, client: Option<IpAddr>) -> Result<(), (Duration, String)> {
+        let mut client_bucket = None;
+        if let Some((rate, clients)) = &self.client_rate {
+            if let Some(client) = client {
+                let mut clients = clients.lock().unwrap();
+                if clients.len() > MAX_CLIENTS {
+                    let idle = Duration::from_secs_f64(rate.burst / rate.per_second);
+                    clients.retain(|_, bucket| bucket.updated.elapsed() < idle);
+                }
+                let bucket = clients.entry(client).or_insert_with(|| rate.bucket());
+                if let Err(wait) = rate.take(bucket) {
+                    return Err((wait, format!("クライアント {} ", client)));
+                }
+                client_bucket = Some((rate, clients, client));
+            }
+        }
+        if let Some((rate, bucket)) = &self.rate {
+            if let Err(wait) = rate.take(&mut bucket.lock().unwrap()) {
+                if let Some((client_rate, mut clients, client)) = client_bucket {
+                    if let Some(bucket) = clients.get_mut(&client) {
+                        client_rate.refund(bucket);
+                    }
+                }
+                return Err((wait, String::new()));
+            }
+        }
+        Ok(())
+    }
+
+    // 制限内なら Ok、超えていれば返すレスポンス
+    pub async fn admit(&self, client: Option<IpAddr>) -> Result<Admission, Response> {
+        if let Err((wait, who)) = self.take_tokens(client) {
+            return Err(self.too_many(wait, &who));
+        }
+        let permit = match &self.concurrency {
+            Some((max, semaphore)) => {
+                let permit = match semaphore.clone().try_acquire_owned() {
+                    Ok(permit) => permit,
+                    Err(_) => {
+                        self.counters.queued.fetch_add(1, Ordering::Relaxed);
+                        self.counters.waiting.fetch_add(1, Ordering::Relaxed);
+                        let acquired = timeout(self.queue_timeout, semaphore.clone().acquire_owned()).await;
+                        self.counters.waiting.fetch_sub(1, Ordering::Relaxed);
+                        match acquired {
+                            Ok(Ok(permit)) => permit,
+                            _ => {
+                                self.counters.queue_timeouts.fetch_add(1, Ordering::Relaxed);
+                                warn!("同時実行数の上限（{}）で待ちきれませんでした: {}", max, self.target);
+                                let mut response = (
+                                    StatusCode::SERVICE_UNAVAILABLE,
+                                    format!(
+                                        "同時実行数の上限: バックエンドサーバー {} へのリクエストが {} 件実行中のため、{}ミリ秒待ちましたが送れませんでした",
+                                        self.target,
+                                        max,
+                                        self.queue_timeout.as_millis()
+                                    ),
+                                )
+                                    .into_response();
+                                response.headers_mut().insert(header::RETRY_AFTER, HeaderValue::from_static("1"));
+                                return Err(response);
+                            }
+                        }
+                    }
+                };
+                Some(permit)
+            }
+            None => None,
+        };
+        self.counters.passed.fetch_add(1, Ordering::Relaxed);
+        Ok(Admission(permit))
+    }
+
+    fn too_many(&self, wait: Duration, scope: &str) -> Response {
+        self.counters.limited.fetch_add(1, Ordering::Relaxed);
+        let retry_after = wait.as_secs_f64().ceil().max(1.0) as u64;
+        warn!("流量制限: {}{} へのリクエストを拒否しました（{}秒後に再試行可）", scope, self.target, retry_after);
+        let mut response = (
+            StatusCode::TOO_MANY_REQUESTS,
+            format!(
+                "流量制限: {}バックエンドサーバー {} へのリクエストが多すぎます。{}秒後に再試行してください",
+                scope, self.target, retry_after
+            ),
+        )
+            .into_response();
+        if let Ok(value) = HeaderValue::try_from(retry_after.to_string()) {
+            response.headers_mut().insert(header::RETRY_AFTER, value);
+        }
+        response
+    }
+
+    // 選択画面に表示する説明
+    pub fn describe(&self) -> String {
+        let mut parts = Vec::new();
+        if let Some((max, semaphore)) = &self.concurrency {
+            parts.push(format!(
+                "実行中 {}/{} ・ 待ち {}",
+                max - semaphore.available_permits(),
+                max,
+                self.counters.waiting.load(Ordering::Relaxed)
+            ));
+        }
+        parts.push(format!("通過 {}", self.counters.passed.load(Ordering::Relaxed)));
+        parts.push(format!("429 {}回", self.counters.limited.load(Ordering::Relaxed)));
+        if self.concurrency.is_some() {
+            parts.push(format!(
+                "待ち合わせ {}回（タイムアウト {}回）",
+                self.counters.queued.load(Ordering::Relaxed),
+                self.counters.queue_timeouts.load(Ordering::Relaxed)
+            ));
+        }
+        parts.join(" ・ ")
+    }
+}
+
+struct PermitBody {
+    inner: Body,
+    _permit: OwnedSemaphorePermit,
+}
+
+impl HttpBody for PermitBody {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        Pin::new(&mut self.inner).poll_frame(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+pub type LimiterMap = Arc<HashMap<String, Arc<Limiter>>>;
+
+// 流量制限を設定したターゲットごとに Limiter を作る
+pub fn limiters_for(config: &Config) -> LimiterMap {
+    let map = config
+        .targets
+        .iter()
+        .filter(|t| t.is_http())
+        .filter_map(|t| {
+            let limit = t.rate_limit.as_ref()?;
+            Some((t.name.clone(), Arc::new(Limiter::new(&t.name, limit))))
+        })
+        .collect();
+    Arc::new(map)
+}
+
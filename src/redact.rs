@@ -0,0 +1,276 @@
+// ログとキャプチャに含まれる秘密情報の伏せ字
+//
+// tracing のログ出力、アクセスログ、トラフィックインスペクター（HARの書き出しを含む）、ミラーリングの差分で共通に使う。
+// 既定で有効で、[redact] enabled = false を書いた場合だけ無効になる。
+
+use regex::{Captures, Regex};
+use serde::Deserialize;
+use std::{borrow::Cow, collections::HashSet, sync::OnceLock};
+
+pub const MASK: &str = "[REDACTED]";
+
+// 既定で伏せるヘッダー
+const DEFAULT_HEADERS: &[&str] = &[
+    "authorization",
+    "proxy-authorization",
+    "cookie",
+    "set-cookie",
+    "x-api-key",
+    "x-auth-token",
+    "x-csrf-token",
+];
+
+// 既定で伏せるクエリパラメーター（token は /__portrooter/login の共有リンクにも使う）
+const DEFAULT_QUERY_PARAMS: &[&str] = &[
+    "access_token",
+    "refresh_token",
+    "id_token",
+    "token",
+    "api_key",
+    "apikey",
+    "client_secret",
+    "password",
+    "secret",
+];
+
+// 既定で伏せるJSONのフィールド
+const DEFAULT_JSON_FIELDS: &[&str] = &[
+    "password",
+    "access_token",
+    "refresh_token",
+    "id_token",
+    "token",
+    "client_secret",
+    "secret",
+    "api_key",
+];
+
+// 既定のパターン（キャプチャグループがあればその部分だけを伏せる）
+const DEFAULT_PATTERNS: &[&str] = &[
+    r"(?i)\bbearer\s+([a-z0-9._~+/-]+=*)",
+    r"(?i)\bbasic\s+([a-z0-9+/]+=*)",
+    r"\beyJ[A-Za-z0-9_-]+\.[A-Za-z0-9_-]+\.[A-Za-z0-9_-]+",
+];
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct RedactConfig {
+    // false で伏せ字を無効にする（明示的に書いた場合だけ）
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    // 既定に加えて伏せるヘッダー
+    #[serde(default)]
+    pub headers: Vec<String>,
+    // 既定に加えて伏せるクエリパラメーター（フォームのボディにも適用）
+    #[serde(default)]
+    pub query_params: Vec<String>,
+    // 既定に加えて伏せるJSONのフィールド
+    #[serde(default)]
+    pub json_fields: Vec<String>,
+    // 伏せる正規表現（キャプチャグループがあればその部分だけ）
+    #[serde(default)]
+    pub patterns: Vec<String>,
+}
+
+impl Default for RedactConfig {
+    fn default() -> Self {
+        RedactConfig {
+            enabled: default_enabled(),
+            headers: Vec::new(),
+            query_params: Vec::new(),
+            json_fields: Vec::new(),
+            patterns: Vec::new(),
+        }
+    }
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+#[derive(Debug)]
+pub struct Redactor {
+    enabled: bool,
+    headers: HashSet<String>,
+    // ?name=value / &name=value（フォームのボディの先頭も）
+    query: Regex,
+    // "name": "value"（JSONのフィールドと、Debug形式のヘッダー）
+    json: Regex,
+    patterns: Vec<Regex>,
+}
+
+static REDACTOR: OnceLock<Redactor> = OnceLock::new();
+
+// 起動時に設定から組み立てる（ログ出力の初期化より前に呼ぶ）
+pub fn init(config: &RedactConfig) -> Result<(), String> {
+    let redactor = Redactor::compile(config)?;
+    REDACTOR
+        .set(redactor)
+        .map_err(|_| "伏せ字の設定はすでに初期化されています".to_string())
+}
+
+// 初期化前は既定の設定を使う
+pub fn get() -> &'static Redactor {
+    REDACTOR.get_or_init(|| Redactor::compile(&RedactConfig::default()).expect("既定の伏せ字の設定が不正です"))
+}
+
+fn alternation<'a>(names: impl Iterator<Item = &'a str>) -> String {
+    let mut names: Vec<String> = names.map(|n| regex::escape(&n.to_ascii_lowercase())).collect();
+    names.sort();
+    names.dedup();
+    names.join("|")
+}
+
+impl Redactor {
+    fn compile(config: &RedactConfig) -> Result<Redactor, String> {
+        let headers: HashSet<String> = DEFAULT_HEADERS
+            .iter()
+            .map(|h| h.to_string())
+            .chain(config.headers.iter().map(|h| h.to_ascii_lowercase()))
+            .collect();
+        let query_names = alternation(
+            DEFAULT_QUERY_PARAMS
+                .iter()
+                .copied()
+                .chain(config.query_params.iter().map(String::as_str)),
+        );
+        let query = Regex::new(&format!(r#"(?i)((?:^|[?&;])(?:{})=)([^&#\s"'\\<>]+)"#, query_names))
+            .map_err(|e| format!("query_params: {}", e))?;
+        let json_names = alternation(
+            DEFAULT_JSON_FIELDS
+                .iter()
+                .copied()
+                .chain(config.json_fields.iter().map(String::as_str))
+                .chain(headers.iter().map(String::as_str)),
+        );
+        let json = Regex::new(&format!(r#"(?i)("(?:{})"\s*:\s*")((?:[^"\\]|\\.)*)""#, json_names))
+            .map_err(|e| format!("json_fields: {}", e))?;
+        let patterns = DEFAULT_PATTERNS
+            .iter()
+            .copied()
+            .chain(config.patterns.iter().map(String::as_str))
+            .map(|p| Regex::new(p).map_err(|e| format!("patterns: {}: {}", p, e)))
+            .collect::<Result<_, _>>()?;
+        Ok(Redactor {
+            enabled: config.enabled,
+            headers,
+            query,
+            json,
+            patterns,
+        })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    // ログの行やURIを伏せる（クエリパラメーター、"name": "value"、パターン）
+    pub fn text<'a>(&self, text: &'a str) -> Cow<'a, str> {
+        if !self.enabled {
+            return Cow::Borrowed(text);
+        }
+        let mut out = Cow::Borrowed(text);
+        if let Cow::Owned(replaced) = self.query.replace_all(&out, |c: &Captures| format!("{}{}", &c[1], MASK)) {
+            out = Cow::Owned(replaced);
+        }
+        if let Cow::Owned(replaced) = self.json.replace_all(&out, |c: &Captures| format!("{}{}\"", &c[1], MASK)) {
+            out = Cow::Owned(replaced);
+        }
+        for pattern in &self.patterns {
+            if let Cow::Owned(replaced) = mask_pattern(pattern, &out) {
+                out = Cow::Owned(replaced);
+            }
+        }
+        out
+    }
+
+    // 伏せる対象のヘッダーなら値を伏せる（認証方式や Cookie の名前は残す）
+    pub fn header<'a>(&self, name: &str, value: &'a str) -> Cow<'a, str> {
+        if !self.enabled {
+            return Cow::Borrowed(value);
+        }
+        let name = name.to_ascii_lowercase();
+        if !self.headers.contains(&name) {
+            return self.text(value);
+        }
+        Cow::Owned(match name.as_str() {
+            "authorization" | "proxy-authorization" => match value.split_once(' ') {
+                Some((scheme, _)) => format!("{} {}", scheme, MASK),
+                None => MASK.to_string(),
+            },
+            "cookie" => value
+                .split(';')
+                .map(|pair| match pair.split_once('=') {
+                    Some((name, _)) => format!("{}={}", name, MASK),
+                    None => pair.to_string(),
+                })
+                .collect::<Vec<_>>()
+                .join(";"),
+            "set-cookie" => match value.split_once(';') {
+                Some((pair, attributes)) => format!("{};{}", mask_cookie_pair(pair), attributes),
+                None => mask_cookie_pair(value),
+            },
+            _ => MASK.to_string(),
+        })
+    }
+
+    // (名前, 値) の組をまとめて伏せる
+    pub fn header_pairs(&self, pairs: &mut [(String, String)]) {
+        for (name, value) in pairs.iter_mut() {
+            if let Cow::Owned(redacted) = self.header(name, value) {
+                *value = redacted;
+            }
+        }
+    }
+
+    // 記録したボディを伏せる（上限で途中の文字が切れていても、UTF-8として読める部分を対象にする）
+    pub fn body(&self, data: &mut Vec<u8>) {
+        if !self.enabled {
+            return;
+        }
+        let valid = match std::str::from_utf8(data) {
+            Ok(text) => text.len(),
+            Err(err) => err.valid_up_to(),
+        };
+        let text = std::str::from_utf8(&data[..valid]).unwrap_or_default();
+        if let Cow::Owned(redacted) = self.text(text) {
+            let mut out = redacted.into_bytes();
+            out.extend_from_slice(&data[valid..]);
+            *data = out;
+        }
+    }
+}
+
+fn mask_cookie_pair(pair: &str) -> String {
+    match pair.split_once('=') {
+        Some((name, _)) => format!("{}={}", name, MASK),
+        None => pair.to_string(),
+    }
+}
+
+// パターンに一致した部分（キャプチャグループがあればグループの部分だけ）を伏せる
+fn mask_pattern<'a>(pattern: &Regex, text: &'a str) -> Cow<'a, str> {
+    let mut spans = Vec::new();
+    for captures in pattern.captures_iter(text) {
+        let groups: Vec<_> = captures.iter().skip(1).flatten().collect();
+        if groups.is_empty() {
+            spans.extend(captures.get(0).map(|m| m.range()));
+        } else {
+            spans.extend(groups.iter().map(|m| m.range()));
+        }
+    }
+    if spans.is_empty() {
+        return Cow::Borrowed(text);
+    }
+    let mut out = String::with_capacity(text.len());
+    let mut last = 0;
+    for span in spans {
+        if span.start < last {
+            continue;
+        }
+        out.push_str(&text[last..span.start]);
+        out.push_str(MASK);
+        last = span.end;
+    }
+    out.push_str(&text[last..]);
+    Cow::Owned(out)
+}
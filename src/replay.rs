@@ -0,0 +1,621 @@
+// 記録と再生（バックエンドなしでのフロントエンド開発向け）
+//
+// record モードではバックエンドのレスポンスをフィクスチャ（1リクエスト1ファイルのJSON）としてディレクトリに保存し、
+// replay モードではバックエンドに接続せずフィクスチャから返す。
+// replay モードではディレクトリ内のHARファイルもフィクスチャとして読み込む。
+
+use axum::{
+    body::Body,
+    extract::{Request, State},
+    http::{HeaderName, HeaderValue, StatusCode},
+    response::{IntoResponse, Json, Response},
+};
+use http_body_util::BodyExt;
+use hyper::body::{Body as HttpBody, Bytes, Frame, SizeHint};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs,
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+};
+use tracing::{debug, info, warn};
+
+use crate::{har, redact, AppState, Config, Target};
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct ReplayConfig {
+    #[serde(default)]
+    pub mode: ReplayMode,
+    // フィクスチャを保存するディレクトリ
+    pub dir: String,
+    // クエリ文字列も一致条件にする
+    #[serde(default = "default_true")]
+    pub match_query: bool,
+    // リクエストボディのハッシュも一致条件にする
+    #[serde(default)]
+    pub match_body: bool,
+    // リクエストヘッダーも一致条件にする（ignore_headers と既定の除外ヘッダーを除く）
+    #[serde(default)]
+    pub match_headers: bool,
+    // 一致条件から除外するヘッダー
+    #[serde(default)]
+    pub ignore_headers: Vec<String>,
+    // 記録するレスポンスボディの上限（KB）。超えた分は保存せず、フィクスチャに truncated を付ける
+    #[serde(default = "default_max_body_kb")]
+    pub max_body_kb: usize,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_max_body_kb() -> usize {
+    10 * 1024
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ReplayMode {
+    // 通常どおりバックエンドへ転送する
+    #[default]
+    Off,
+    Record,
+    Replay,
+}
+
+// プロキシ自身が付けるヘッダーや毎回変わるヘッダーは常に一致条件から外す
+const VOLATILE_HEADERS: [&str; 10] = [
+    "host",
+    "connection",
+    "content-length",
+    "x-request-id",
+    "x-forwarded-for",
+    "x-forwarded-proto",
+    "x-forwarded-host",
+    "x-forwarded-port",
+    "x-forwarded-prefix",
+    "forwarded",
+];
+
+// 保存・再生しないレスポンスヘッダー
+const SKIPPED_RESPONSE_HEADERS: [&str; 4] = ["connection", "keep-alive", "transfer-encoding", "content-length"];
+
+// --record / --replay が指定されていれば、replay 設定のあるターゲットのモードを上書きする
+pub fn apply_cli_mode(config: &mut Config) {
+    let mode = std::env::args().skip(1).find_map(|arg| match arg.as_str() {
+        "--record" => Some(ReplayMode::Record),
+        "--replay" => Some(ReplayMode::Replay),
+        _ => None,
+    });
+    let Some(mode) = mode else {
+        return;
+    };
+    for target in &mut config.targets {
+        if let Some(replay) = &mut target.replay {
+            replay.mode = mode;
+        }
+    }
+}
+
+pub type ReplayMap = Arc<HashMap<String, Arc<Replayer>>>;
+
+// record / replay モードのターゲットごとに Replayer を作る
+pub fn replayers_for(config: &Config) -> Result<ReplayMap, String> {
+    let mut map = HashMap::new();
+    for target in config.targets.iter().filter(|t| t.is_http()) {
+        let Some(replay) = &target.replay else {
+            continue;
+        };
+        if replay.mode == ReplayMode::Off {
+            continue;
+        }
+        map.insert(target.name.clone(), Arc::new(Replayer::new(target, replay)?));
+    }
+    Ok(Arc::new(map))
+}
+
+// フィクスチャ1件（1リクエスト分）
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct Fixture {
+    request: FixtureRequest,
+    response: FixtureResponse,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct FixtureRequest {
+    method: String,
+    path: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    query: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    body_hash: Option<String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    headers: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct FixtureResponse {
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: String,
+    // "base64"（UTF-8でないボディ）
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    body_encoding: Option<String>,
+    // 記録時に max_body_kb で切り詰めたボディ
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    truncated: bool,
+}
+
+impl FixtureResponse {
+    fn new(status: u16, headers: Vec<(String, String)>, body: &[u8]) -> FixtureResponse {
+        let headers = headers
+            .into_iter()
+            .filter(|(name, _)| !SKIPPED_RESPONSE_HEADERS.contains(&name.to_ascii_lowercase().as_str()))
+            .collect();
+        let (body, body_encoding) = match std::str::from_utf8(body) {
+            Ok(text) => (text.to_string(), None),
+            Err(_) => (har::base64_encode(body), Some("base64".to_string())),
+        };
+        FixtureResponse {
+            status,
+            headers,
+            body,
+            body_encoding,
+            truncated: false,
+        }
+    }
+
+    fn into_response(self) -> Response {
+        let body = if self.body_encoding.as_deref() == Some("base64") {
+            har::base64_decode(&self.body).unwrap_or_default()
+        } else {
+            self.body.into_bytes()
+        };
+        let mut response = Response::new(Body::from(body));
+        *response.status_mut() = StatusCode::from_u16(self.status).unwrap_or(StatusCode::OK);
+        for (name, value) in self.headers {
+            if let (Ok(name), Ok(value)) = (HeaderName::try_from(name), HeaderValue::try_from(value)) {
+                response.headers_mut().append(name, value);
+            }
+        }
+        response
+    }
+}
+
+impl FixtureRequest {
+    // 一致判定に使うキー
+    fn key(&self) -> String {
+        let mut key = format!("{} {}", self.method, self.path);
+        if let Some(query) = &self.query {
+            key.push('?');
+            key.push_str(query);
+        }
+        for (name, value) in &self.headers {
+            key.push_str(&format!("\n{}: {}", name, value));
+        }
+        if let Some(hash) = &self.body_hash {
+            key.push_str(&format!("\nbody: {}", hash));
+        }
+        key
+    }
+
+    // キーから決まるファイル名（例: GET-api-users-1a2b3c4d5e6f7a8b.json）
+    fn file_name(&self) -> String {
+        let slug: String = self
+            .path
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+            .collect::<String>()
+            .split('-')
+            .filter(|part| !part.is_empty())
+            .collect::<Vec<_>>()
+            .join("-");
+        let slug: String = slug.chars().take(60).collect();
+        format!("{}-{}-{:016x}.json", self.method, if slug.is_empty() { "root" } else { &slug }, fnv1a(self.key().as_bytes()))
+    }
+}
+
+pub struct Replayer {
+    target: String,
+    config: ReplayConfig,
+    dir: PathBuf,
+    // replay モードでHARから読み込んだフィクスチャ（ファイル名 -> フィクスチャ）
+    har_fixtures: HashMap<String, Fixture>,
+    // 一致しなかったリクエスト（キー -> 回数）
+    unmatched: Mutex<BTreeMap<String, u64>>,
+}
+
+impl Replayer {
+    fn new(target: &Target, config: &ReplayConfig) -> Result<Replayer, String> {
+        let dir = PathBuf::from(&config.dir);
+        let mut replayer = Replayer {
+            target: target.name.clone(),
+            config: config.clone(),
+            dir,
+            har_fixtures: HashMap::new(),
+            unmatched: Mutex::new(BTreeMap::new()),
+        };
+        match config.mode {
+            ReplayMode::Record => {
+                fs::create_dir_all(&replayer.dir).map_err(|e| format!("{}: {}", config.dir, e))?;
+                info!("記録モード: {} -> {}", target.name, config.dir);
+            }
+            ReplayMode::Replay => {
+                if !replayer.dir.is_dir() {
+                    return Err(format!("フィクスチャのディレクトリがありません: {}", config.dir));
+                }
+                replayer.load_har_files(target)?;
+                info!("再生モード: {} <- {}（バックエンドには接続しません）", target.name, config.dir);
+            }
+            ReplayMode::Off => {}
+        }
+        Ok(replayer)
+    }
+
+    // ディレクトリ内の .har ファイルをフィクスチャとして読み込む
+    fn load_har_files(&mut self, target: &Target) -> Result<(), String> {
+        // インスペクターの記録はヘッダーを書き換える前の値なので、書き換えた後のリクエストとは一致しないことがある
+        if self.config.match_headers && !target.request_headers.is_empty() {
+            warn!(
+                "{}: match_headers では、HARのヘッダーは request_headers で書き換える前の値と比べられます",
+                target.name
+            );
+        }
+        let entries = fs::read_dir(&self.dir).map_err(|e| format!("{}: {}", self.config.dir, e))?;
+        let prefix = format!("/proxy/{}", urlencoding::encode(&self.target));
+        for path in entries.flatten().map(|e| e.path()) {
+            if path.extension().and_then(|e| e.to_str()) != Some("har") {
+                continue;
+            }
+            let bytes = fs::read(&path).map_err(|e| format!("{}: {}", path.display(), e))?;
+            let exchanges = har::parse(&bytes).map_err(|e| format!("{}: {}", path.display(), e))?;
+            let mut count = 0;
+            let mut skipped = 0;
+            for exchange in exchanges {
+                if exchange.target.as_ref().is_some_and(|t| t != &self.target) {
+                    continue;
+                }
+                // 伏せ字や保存上限で元の値が分からない記録は、生のリクエストと一致しないので使わない
+                let masked = |text: &str| text.contains(redact::MASK);
+                let unusable_query = self.config.match_query && masked(&exchange.uri);
+                let unusable_body = self.config.match_body
+                    && (exchange.request_body.truncated || masked(&String::from_utf8_lossy(&exchange.request_body.data)));
+                let unusable_headers =
+                    self.config.match_headers && exchange.request_headers.iter().any(|(_, value)| masked(value));
+                if unusable_query || unusable_body || unusable_headers {
+                    skipped += 1;
+                    continue;
+                }
+                // インスペクターの記録は /proxy/{name} 付きなので、バックエンドに送るパスに戻す
+                let uri = exchange.uri.strip_prefix(&prefix).unwrap_or(&exchange.uri);
+                let (path, query) = match uri.split_once('?') {
+                    Some((path, query)) => (path, Some(query)),
+                    None => (uri, None),
+                };
+                let request = self.request_key(
+                    &exchange.method,
+                    if path.is_empty() { "/" } else { path },
+                    query,
+                    exchange.request_headers.iter().map(|(n, v)| (n.as_str(), v.as_str())),
+                    fnv1a(&exchange.request_body.data),
+                );
+                let response = FixtureResponse::new(exchange.status, exchange.response_headers, &exchange.response_body.data);
+                self.har_fixtures.insert(request.file_name(), Fixture { request, response });
+                count += 1;
+            }
+            if skipped > 0 {
+                warn!(
+                    "{}: 伏せ字または保存上限で切り詰めた記録 {}件は match_query / match_body / match_headers で比べられないため読み込みません",
+                    path.display(),
+                    skipped
+                );
+            }
+            debug!("HARを読み込みました: {} ({}件)", path.display(), count);
+        }
+        Ok(())
+    }
+
+    // 設定された一致条件でリクエストのキーを作る
+    fn request_key<'a>(
+        &self,
+        method: &str,
+        path: &str,
+        query: Option<&str>,
+        headers: impl Iterator<Item = (&'a str, &'a str)>,
+        body_hash: u64,
+    ) -> FixtureRequest {
+        let query = if self.config.match_query {
+            query.filter(|q| !q.is_empty()).map(normalize_query)
+        } else {
+            None
+        };
+        let headers = if self.config.match_headers {
+            headers
+                .map(|(name, value)| (name.to_ascii_lowercase(), value.to_string()))
+                .filter(|(name, _)| {
+                    !VOLATILE_HEADERS.contains(&name.as_str())
+                        && !self.config.ignore_headers.iter().any(|i| i.eq_ignore_ascii_case(name))
+                })
+                .collect()
+        } else {
+            BTreeMap::new()
+        };
+        FixtureRequest {
+            method: method.to_string(),
+            path: path.to_string(),
+            query,
+            body_hash: self.config.match_body.then(|| format!("{:016x}", body_hash)),
+            headers,
+        }
+    }
+
+    // record / replay モードでバックエンドへの送信を置き換える
+    pub async fn send(&self, state: &AppState, target: &Target, req: Request) -> Result<Response, hyper_util::client::legacy::Error> {
+        let (parts, body) = req.into_parts();
+        let request = |body_hash| {
+            self.request_key(
+                parts.method.as_str(),
+                parts.uri.path(),
+                parts.uri.query(),
+                parts
+                    .headers
+                    .iter()
+                    .filter_map(|(name, value)| Some((name.as_str(), value.to_str().ok()?))),
+                body_hash,
+            )
+        };
+
+        if self.config.mode == ReplayMode::Replay {
+            // ボディのハッシュが必要なときだけ読み流す（集めはしない）
+            let mut hash = FNV_OFFSET;
+            if self.config.match_body {
+                let mut body = body;
+                while let Some(frame) = body.frame().await {
+                    if let Some(data) = frame.ok().and_then(|f| f.into_data().ok()) {
+                        hash = fnv1a_update(hash, &data);
+                    }
+                }
+            }
+            return Ok(self.replay(request(hash)));
+        }
+
+        // 記録ではボディを流しながらハッシュを計算し、レスポンスを受け取り終えた時点の値でキーを決める
+        let request = request(FNV_OFFSET);
+        let (body, body_hash) = if self.config.match_body {
+            let hash = Arc::new(Mutex::new(FNV_OFFSET));
+            (
+                Body::new(HashingBody {
+                    inner: body,
+                    hash: hash.clone(),
+                }),
+                Some(hash),
+            )
+        } else {
+            (body, None)
+        };
+        let req = Request::from_parts(parts, body);
+        let response = state.request_backend(target, req).await?;
+        let status = response.status().as_u16();
+        let headers = response
+            .headers()
+            .iter()
+            .map(|(name, value)| (name.to_string(), String::from_utf8_lossy(value.as_bytes()).to_string()))
+            .collect();
+        let dir = self.dir.clone();
+        let limit = self.config.max_body_kb.saturating_mul(1024);
+        Ok(response.map(|body| {
+            Body::new(RecordingBody {
+                inner: Body::new(body),
+                buf: Vec::new(),
+                limit,
+                truncated: false,
+                pending: Some((dir, request, body_hash, status, headers)),
+            })
+        }))
+    }
+
+    fn replay(&self, request: FixtureRequest) -> Response {
+        let file_name = request.file_name();
+        let path = self.dir.join(&file_name);
+        let fixture = match fs::read(&path) {
+            Ok(bytes) => serde_json::from_slice::<Fixture>(&bytes).map_err(|e| format!("{}: {}", path.display(), e)),
+            Err(_) => self
+                .har_fixtures
+                .get(&file_name)
+                .cloned()
+                .ok_or_else(|| "一致するフィクスチャがありません".to_string()),
+        };
+        match fixture {
+            Ok(fixture) => {
+                if fixture.response.truncated {
+                    warn!("再生: {} は記録時に max_body_kb で切り詰めたボディです", file_name);
+                }
+                debug!("再生: {} {}", request.key(), file_name);
+                let mut response = fixture.response.into_response();
+                response.headers_mut().insert(HeaderName::from_static("x-portrooter-replay"), HeaderValue::from_static("hit"));
+                response
+            }
+            Err(reason) => {
+                let key = request.key();
+                warn!("再生できません: {} [{}] {} -> {}", self.target, key.replace('\n', " | "), file_name, reason);
+                *self.unmatched.lock().unwrap().entry(key.clone()).or_default() += 1;
+                let body = format!(
+                    "再生モード: {} に一致するフィクスチャがありません\n{}\n\n期待したファイル: {}\n原因: {}\n一覧: /__portrooter/replay",
+                    self.target,
+                    key,
+                    path.display(),
+                    reason
+                );
+                let mut response = (StatusCode::NOT_FOUND, body).into_response();
+                response.headers_mut().insert(HeaderName::from_static("x-portrooter-replay"), HeaderValue::from_static("miss"));
+                response
+            }
+        }
+    }
+}
+
+// レスポンスボディを最後まで受け取ったらフィクスチャとして保存するボディ
+type PendingFixture = (PathBuf, FixtureRequest, Option<Arc<Mutex<u64>>>, u16, Vec<(String, String)>);
+
+struct RecordingBody {
+    inner: Body,
+    buf: Vec<u8>,
+    // buf に集める上限（超えた分は捨てる）
+    limit: usize,
+    truncated: bool,
+    pending: Option<PendingFixture>,
+}
+
+impl RecordingBody {
+    fn finish(&mut self) {
+        let Some((dir, mut request, body_hash, status, headers)) = self.pending.take() else {
+            return;
+        };
+        if let Some(hash) = body_hash {
+            request.body_hash = Some(format!("{:016x}", *hash.lock().unwrap()));
+        }
+        let path = dir.join(request.file_name());
+        if self.truncated {
+            warn!("記録: レスポンスボディが max_body_kb を超えたため切り詰めて保存します: {}", path.display());
+        }
+        let mut response = FixtureResponse::new(status, headers, &self.buf);
+        response.truncated = self.truncated;
+        let fixture = Fixture { request, response };
+        tokio::task::spawn_blocking(move || save(&path, &fixture));
+    }
+}
+
+impl HttpBody for RecordingBody {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let poll = Pin::new(&mut self.inner).poll_frame(cx);
+        match &poll {
+            Poll::Ready(Some(Ok(frame))) => {
+                if let Some(data) = frame.data_ref() {
+                    let room = self.limit.saturating_sub(self.buf.len());
+                    if data.len() > room {
+                        self.truncated = true;
+                    }
+                    let data = &data[..data.len().min(room)];
+                    self.buf.extend_from_slice(data);
+                }
+                if self.inner.is_end_stream() {
+                    self.finish();
+                }
+            }
+            Poll::Ready(None) => self.finish(),
+            _ => {}
+        }
+        poll
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+// 流れたリクエストボディでハッシュを更新するボディ
+struct HashingBody {
+    inner: Body,
+    hash: Arc<Mutex<u64>>,
+}
+
+impl HttpBody for HashingBody {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let poll = Pin::new(&mut self.inner).poll_frame(cx);
+        if let Poll::Ready(Some(Ok(frame))) = &poll {
+            if let Some(data) = frame.data_ref() {
+                let mut hash = self.hash.lock().unwrap();
+                *hash = fnv1a_update(*hash, data);
+            }
+        }
+        poll
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+fn save(path: &Path, fixture: &Fixture) {
+    let json = match serde_json::to_string_pretty(fixture) {
+        Ok(json) => json,
+        Err(err) => {
+            warn!("フィクスチャを作れませんでした: {} -> {}", path.display(), err);
+            return;
+        }
+    };
+    match fs::write(path, json) {
+        Ok(()) => debug!("記録: {}", path.display()),
+        Err(err) => warn!("フィクスチャを保存できませんでした: {} -> {}", path.display(), err),
+    }
+}
+
+// 再生モードで一致しなかったリクエストの一覧
+pub async fn report_handler(State(state): State<AppState>) -> Json<serde_json::Value> {
+    let targets: serde_json::Map<String, serde_json::Value> = state
+        .replayers
+        .iter()
+        .map(|(name, replayer)| {
+            let unmatched: Vec<_> = replayer
+                .unmatched
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|(key, count)| json!({ "request": key, "count": count }))
+                .collect();
+            let mode = match replayer.config.mode {
+                ReplayMode::Off => "off",
+                ReplayMode::Record => "record",
+                ReplayMode::Replay => "replay",
+            };
+            (
+                name.clone(),
+                json!({ "mode": mode, "dir": replayer.config.dir, "unmatched": unmatched }),
+            )
+        })
+        .collect();
+    Json(json!({ "targets": targets }))
+}
+
+// クエリのパラメーター順序の違いを無視する
+fn normalize_query(query: &str) -> String {
+    let mut pairs: Vec<&str> = query.split('&').filter(|p| !p.is_empty()).collect();
+    pairs.sort_unstable();
+    pairs.join("&")
+}
+
+const FNV_OFFSET: u64 = 0xcbf29ce484222325;
+
+// 64ビットFNV-1aハッシュ（ファイル名とボディの比較用）
+fn fnv1a(data: &[u8]) -> u64 {
+    fnv1a_update(FNV_OFFSET, data)
+}
+
+// 続きのデータでハッシュを更新する（ボディを集めずに流しながら計算する）
+fn fnv1a_update(hash: u64, data: &[u8]) -> u64 {
+    data.iter().fold(hash, |hash, byte| (hash ^ *byte as u64).wrapping_mul(0x100000001b3))
+}
@@ -0,0 +1,244 @@
+// リクエストボディの上限と転送状況
+//
+// ボディは集めずにバックエンドへそのまま流す。Content-Length が上限を超えていれば送る前に、
+// 長さの分からないボディは流れたバイト数が上限を超えた時点で転送を打ち切り、413 を返す。
+
+use axum::{
+    body::Body,
+    extract::Request,
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+};
+use hyper::body::{Body as HttpBody, Bytes, Frame, SizeHint};
+use std::{
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+use tracing::warn;
+
+use crate::{forward, Target};
+
+// ルールに一致したリクエストの上限（バイト）。routes がリクエストの拡張に入れる
+#[derive(Debug, Clone, Copy)]
+pub struct RouteLimit(pub u64);
+
+// リクエストボディの転送状況（リクエストの拡張にも入れる）
+#[derive(Debug, Clone)]
+pub struct Upload(Arc<UploadState>);
+
+#[derive(Debug)]
+struct UploadState {
+    limit: AtomicU64,
+    received: AtomicU64,
+    exceeded: AtomicBool,
+    // 最後にボディのデータが流れた時刻（なければリクエストを受け取った時刻）
+    last_activity: Mutex<Instant>,
+}
+
+impl Upload {
+    // ボディが最後に流れてからの時間
+    pub fn idle(&self) -> Duration {
+        self.0.last_activity.lock().unwrap().elapsed()
+    }
+
+    // 上限を超えたためにボディの転送を打ち切ったか
+    pub fn exceeded(&self) -> bool {
+        self.0.exceeded.load(Ordering::Relaxed)
+    }
+
+    fn set_limit(&self, limit: u64) {
+        self.0.limit.store(limit, Ordering::Relaxed);
+    }
+
+    fn limit(&self) -> u64 {
+        self.0.limit.load(Ordering::Relaxed)
+    }
+}
+
+// ボディの転送状況を追えるようにする（すでに追っていればそれを返す）
+pub fn track(req: &mut Request) -> Upload {
+    if let Some(upload) = req.extensions().get::<Upload>() {
+        return upload.clone();
+    }
+    let upload = Upload(Arc::new(UploadState {
+        limit: AtomicU64::new(u64::MAX),
+        received: AtomicU64::new(0),
+        exceeded: AtomicBool::new(false),
+        last_activity: Mutex::new(Instant::now()),
+    }));
+    let body = std::mem::take(req.body_mut());
+    *req.body_mut() = Body::new(TrackedBody {
+        inner: body,
+        upload: upload.clone(),
+    });
+    req.extensions_mut().insert(upload.clone());
+    upload
+}
+
+// ルールまたはターゲットの上限を適用する。Content-Length で上限を超えると分かれば送る前に上限を Err で返す
+pub fn enforce(req: &mut Request, target: &Target) -> Result<Upload, u64> {
+    let upload = track(req);
+    let limit = req
+        .extensions()
+        .get::<RouteLimit>()
+        .map(|RouteLimit(limit)| *limit)
+        .or(target.max_request_body_kb.map(|kb| kb.saturating_mul(1024)));
+    let Some(limit) = limit else {
+        return Ok(upload);
+    };
+    let length = req
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok());
+    if let Some(length) = length.filter(|length| *length > limit) {
+        warn!(
+            "リクエストボディが上限を超えています: {} ({} > {})",
+            target.name,
+            forward::format_bytes(length),
+            forward::format_bytes(limit)
+        );
+        return Err(limit);
+    }
+    upload.set_limit(limit);
+    Ok(upload)
+}
+
+// 上限を超えたときのレスポンス
+pub fn too_large(target: &str, limit: u64) -> Response {
+    (
+        StatusCode::PAYLOAD_TOO_LARGE,
+        format!(
+            "リクエストボディが大きすぎます: バックエンドサーバー {} へ送れるボディは {} までです（max_request_body_kb）",
+            target,
+            forward::format_bytes(limit)
+        ),
+    )
+        .into_response()
+}
+
+// 転送打ち切り後にバックエンドへの送信が失敗した場合のレスポンス
+pub fn exceeded_response(upload: &Upload, target: &str) -> Response {
+    too_large(target, upload.limit())
+}
+
+// 流れたバイト数を数え、上限を超えたらエラーにするボディ
+struct TrackedBody {
+    inner: Body,
+    upload: Upload,
+}
+
+impl HttpBody for TrackedBody {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let poll = Pin::new(&mut self.inner).poll_frame(cx);
+        if let Poll::Ready(Some(Ok(frame))) = &poll {
+            let state = &self.upload.0;
+            *state.last_activity.lock().unwrap() = Instant::now();
+            if let Some(data) = frame.data_ref() {
+                let received = state.received.fetch_add(data.len() as u64, Ordering::Relaxed) + data.len() as u64;
+                let limit = state.limit.load(Ordering::Relaxed);
+                if received > limit {
+                    state.exceeded.store(true, Ordering::Relaxed);
+                    warn!("リクエストボディが上限（{}）を超えたため転送を打ち切りました", forward::format_bytes(limit));
+                    return Poll::Ready(Some(Err(axum::Error::new("リクエストボディが上限を超えました"))));
+                }
+            }
+        }
+        if let Poll::Ready(None) = &poll {
+            *self.upload.0.last_activity.lock().unwrap() = Instant::now();
+        }
+        poll
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+// ボディの先頭を上限まで読む。上限内で読み終えれば Ok(全体)、超えれば読んだ分を先頭に戻したボディを Err で返す
+pub async fn read_prefix(body: Body, limit: usize) -> Result<Bytes, Body> {
+    use http_body_util::BodyExt;
+
+    let mut body = body;
+    let mut buf = Vec::new();
+    while let Some(frame) = body.frame().await {
+        let data = match frame.map(Frame::into_data) {
+            Ok(Ok(data)) => data,
+            // トレーラーは複製しない
+            Ok(Err(_)) => continue,
+            // 読み取りのエラーは読んだ分の後でそのまま伝える
+            Err(err) => {
+                return Err(Body::new(PrefixedBody {
+                    prefix: Some(Bytes::from(buf)),
+                    error: Some(err),
+                    inner: Body::empty(),
+                }))
+            }
+        };
+        buf.extend_from_slice(&data);
+        if buf.len() > limit {
+            return Err(Body::new(PrefixedBody {
+                prefix: Some(Bytes::from(buf)),
+                error: None,
+                inner: body,
+            }));
+        }
+    }
+    Ok(Bytes::from(buf))
+}
+
+// 先に読んだデータを最初に流してから残りを流すボディ
+struct PrefixedBody {
+    prefix: Option<Bytes>,
+    error: Option<axum::Error>,
+    inner: Body,
+}
+
+impl HttpBody for PrefixedBody {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        if let Some(prefix) = self.prefix.take().filter(|p| !p.is_empty()) {
+            return Poll::Ready(Some(Ok(Frame::data(prefix))));
+        }
+        if let Some(err) = self.error.take() {
+            return Poll::Ready(Some(Err(err)));
+        }
+        Pin::new(&mut self.inner).poll_frame(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.prefix.is_none() && self.error.is_none() && self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        let mut hint = self.inner.size_hint();
+        if let Some(prefix) = &self.prefix {
+            let len = prefix.len() as u64;
+            hint.set_lower(hint.lower() + len);
+            if let Some(upper) = hint.upper() {
+                hint.set_upper(upper + len);
+            }
+        }
+        hint
+    }
+}
@@ -0,0 +1,300 @@
+// パスベースのルーティング（APIゲートウェイモード）
+//
+// [[routes]] に書いた順に評価し、最初に一致したルールのターゲットへ /proxy/{name} なしで転送する。
+// 一致しなければ従来の選択画面・プロキシ・フォールバックの各ハンドラーに任せる。
+
+use axum::{
+    body::Body,
+    extract::{Request, State},
+    http::{header, HeaderName, Method, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use regex::Regex;
+use serde::Deserialize;
+use std::{collections::HashMap, time::Instant};
+use tracing::{debug, warn};
+
+use crate::{access_log::AccessNote, header_rules, logging, request_body, streaming, AppState, Config};
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct RouteConfig {
+    // 転送先ターゲット名
+    pub target: String,
+    // パスの一致条件（prefix / glob / regex のいずれか一つ）
+    #[serde(default)]
+    pub prefix: Option<String>,
+    #[serde(default)]
+    pub glob: Option<String>,
+    #[serde(default)]
+    pub regex: Option<String>,
+    // 対象とするHTTPメソッド（省略時はすべて）
+    #[serde(default)]
+    pub methods: Vec<String>,
+    // 一致が必要なリクエストヘッダー（値が "*" の場合は存在のみ確認）
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    // 転送前にパスの先頭から取り除く文字列
+    #[serde(default)]
+    pub strip_prefix: Option<String>,
+    // 転送前にパスの先頭に付け加える文字列
+    #[serde(default)]
+    pub add_prefix: Option<String>,
+    // リクエストボディの上限（KB、ターゲットの max_request_body_kb より優先）
+    #[serde(default)]
+    pub max_request_body_kb: Option<u64>,
+}
+
+impl RouteConfig {
+    // 起動時の表示用
+    pub fn pattern(&self) -> &str {
+        self.prefix
+            .as_deref()
+            .or(self.glob.as_deref())
+            .or(self.regex.as_deref())
+            .unwrap_or("")
+    }
+}
+
+#[derive(Debug)]
+enum PathMatcher {
+    Prefix(String),
+    Pattern(Regex),
+}
+
+#[derive(Debug)]
+pub struct Route {
+    target: String,
+    matcher: PathMatcher,
+    methods: Vec<Method>,
+    headers: Vec<(HeaderName, String)>,
+    strip_prefix: Option<String>,
+    add_prefix: Option<String>,
+    max_request_body_kb: Option<u64>,
+}
+
+// 設定からルールを組み立てる。ターゲット名やパターンの誤りは起動時にエラーにする
+pub fn compile(config: &Config) -> Result<Vec<Route>, String> {
+    config
+        .routes
+        .iter()
+        .enumerate()
+        .map(|(i, route)| {
+            let describe = |msg: String| format!("routes[{}]: {}", i, msg);
+
+            if config.http_target(&route.target).is_none() {
+                return Err(describe(format!("HTTPターゲット '{}' が見つかりません", route.target)));
+            }
+
+            let matcher = match (&route.prefix, &route.glob, &route.regex) {
+                (Some(prefix), None, None) => PathMatcher::Prefix(prefix.clone()),
+                (None, Some(glob), None) => PathMatcher::Pattern(
+                    Regex::new(&glob_to_regex(glob)).map_err(|e| describe(e.to_string()))?,
+                ),
+                (None, None, Some(pattern)) => {
+                    PathMatcher::Pattern(Regex::new(pattern).map_err(|e| describe(e.to_string()))?)
+                }
+                _ => return Err(describe("prefix / glob / regex のいずれか一つを指定してください".to_string())),
+            };
+
+            let methods = route
+                .methods
+                .iter()
+                .map(|m| Method::from_bytes(m.to_ascii_uppercase().as_bytes()))
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|e| describe(e.to_string()))?;
+
+            let headers = route
+                .headers
+                .iter()
+                .map(|(name, value)| {
+                    HeaderName::from_bytes(name.as_bytes())
+                        .map(|name| (name, value.clone()))
+                        .map_err(|e| describe(e.to_string()))
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+
+            Ok(Route {
+                target: route.target.clone(),
+                matcher,
+                methods,
+                headers,
+                strip_prefix: route.strip_prefix.clone(),
+                add_prefix: route.add_prefix.clone(),
+                max_request_body_kb: route.max_request_body_kb,
+            })
+        })
+        .collect()
+}
+
+// グロブをパス全体に一致する正規表現に変換する（** は / を含む任意の文字列、* と ? は / を含まない）
+pub fn glob_to_regex(glob: &str) -> String {
+    let mut pattern = String::from("^");
+    let mut chars = glob.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                pattern.push_str(".*");
+            }
+            '*' => pattern.push_str("[^/]*"),
+            '?' => pattern.push_str("[^/]"),
+            c => pattern.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    pattern.push('$');
+    pattern
+}
+
+// パスの先頭がセグメント単位で prefix に一致すれば残りを返す（"/api" は "/api" と "/api/x" に一致し、"/apiary" には一致しない）
+fn strip_path_prefix<'a>(path: &'a str, prefix: &str) -> Option<&'a str> {
+    let rest = path.strip_prefix(prefix)?;
+    if prefix.ends_with('/') || rest.is_empty() || rest.starts_with('/') {
+        Some(rest)
+    } else {
+        None
+    }
+}
+
+impl Route {
+    fn matches(&self, req: &Request) -> bool {
+        let path = req.uri().path();
+        let path_matches = match &self.matcher {
+            PathMatcher::Prefix(prefix) => strip_path_prefix(path, prefix).is_some(),
+            PathMatcher::Pattern(regex) => regex.is_match(path),
+        };
+        if !path_matches {
+            return false;
+        }
+        if !self.methods.is_empty() && !self.methods.contains(req.method()) {
+            return false;
+        }
+        self.headers.iter().all(|(name, expected)| {
+            match req.headers().get(name).and_then(|v| v.to_str().ok()) {
+                Some(_) if expected == "*" => true,
+                Some(value) => value == expected,
+                None => false,
+            }
+        })
+    }
+
+    // strip_prefix / add_prefix を適用した転送先のパス
+    fn rewrite_path(&self, path: &str) -> String {
+        let mut path = path.to_string();
+        if let Some(strip) = &self.strip_prefix {
+            if let Some(rest) = strip_path_prefix(&path, strip) {
+                path = if rest.starts_with('/') {
+                    rest.to_string()
+                } else {
+                    format!("/{}", rest)
+                };
+            }
+        }
+        if let Some(add) = &self.add_prefix {
+            path = format!("{}{}", add.trim_end_matches('/'), path);
+        }
+        path
+    }
+}
+
+// リクエストが一致するルールの転送先ターゲット名（route_middleware と同じ判定）
+pub fn target_for<'a>(routes: &'a [Route], req: &Request) -> Option<&'a str> {
+    if req.uri().path().starts_with("/proxy/") || req.uri().path().starts_with("/__portrooter/") {
+        return None;
+    }
+    routes.iter().find(|route| route.matches(req)).map(|route| route.target.as_str())
+}
+
+// ルールに一致したリクエストをターゲットへ転送するミドルウェア
+pub async fn route_middleware(State(state): State<AppState>, req: Request, next: Next) -> Response {
+    // /proxy/ 以下は従来どおりターゲット名で振り分ける。/__portrooter/ 以下は管理用
+    if req.uri().path().starts_with("/proxy/") || req.uri().path().starts_with("/__portrooter/") {
+        return next.run(req).await;
+    }
+    match state.routes.iter().find(|route| route.matches(&req)) {
+        Some(route) => forward(&state, route, req).await,
+        None => next.run(req).await,
+    }
+}
+
+// 書き換えを行わずにそのまま転送する（本番のnginxと同じ構成なのでパスの変換は不要）
+async fn forward(state: &AppState, route: &Route, mut req: Request) -> Response {
+    let Some(target) = state.config.http_target(&route.target) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+
+    let path = route.rewrite_path(req.uri().path());
+    // strip_prefix で取り除いたパスをプレフィックスとして伝える
+    let stripped = route
+        .strip_prefix
+        .as_deref()
+        .filter(|strip| !strip.is_empty() && strip_path_prefix(req.uri().path(), strip).is_some())
+        .map(|strip| strip.trim_end_matches('/').to_string());
+    let query = req.uri().query().map(|q| format!("?{}", q)).unwrap_or_default();
+    let proxy_uri = format!("http://localhost:{}{}{}", target.port, path, query);
+
+    logging::record_target(&target.name);
+    AccessNote::from_request(&req).upstream(&target.name, &proxy_uri);
+    debug!("ルート: {} {} -> {}", req.method(), req.uri(), proxy_uri);
+
+    let original_host = req
+        .headers()
+        .get(header::HOST)
+        .and_then(|h| h.to_str().ok())
+        .or_else(|| req.uri().authority().map(|a| a.as_str()))
+        .unwrap_or("localhost")
+        .to_string();
+    let method = req.method().to_string();
+    let rule_context = header_rules::RuleContext {
+        target: &target.name,
+        port: target.port,
+        host: &original_host,
+        method: &method,
+        path: &path,
+    };
+    let request_rules = state.header_rules.request(&target.name);
+    let kept = request_rules.keep(req.headers());
+
+    *req.uri_mut() = match proxy_uri.parse() {
+        Ok(uri) => uri,
+        Err(_) => return StatusCode::BAD_REQUEST.into_response(),
+    };
+
+    state.forwarding.apply(&mut req, &original_host, stripped.as_deref());
+    if let Ok(host) = format!("localhost:{}", target.port).parse() {
+        req.headers_mut().insert(header::HOST, host);
+    }
+    request_rules.apply(req.headers_mut(), kept, &rule_context);
+    if let Some(kb) = route.max_request_body_kb {
+        req.extensions_mut().insert(request_body::RouteLimit(kb.saturating_mul(1024)));
+    }
+
+    let wants_stream = streaming::accepts_event_stream(req.headers());
+    let upload = request_body::track(&mut req);
+    let upstream_started = Instant::now();
+    match streaming::wait_for_head(wants_stream, &upload, state.send_upstream(target, req)).await {
+        Ok(Ok(response)) => {
+            state.metrics.observe_upstream(&target.name, upstream_started.elapsed());
+            debug!("ルート転送成功: ステータス {}", response.status());
+            let (mut parts, body) = response.into_parts();
+            let response_rules = state.header_rules.response(&target.name);
+            let kept = response_rules.keep(&parts.headers);
+            response_rules.apply(&mut parts.headers, kept, &rule_context);
+            Response::from_parts(parts, body)
+        }
+        Ok(Err(err)) => {
+            state.metrics.upstream_connect_error(&target.name);
+            warn!(error = ?err, "ルート転送エラー: {} -> {}", proxy_uri, err);
+            let error_body = format!("プロキシエラー: バックエンドサーバー {}:{} に接続できません\n詳細: {}",
+                target.name, target.port, err);
+            (StatusCode::BAD_GATEWAY, Body::from(error_body)).into_response()
+        }
+        Err(_) => {
+            state.metrics.upstream_timeout(&target.name);
+            warn!("ルート転送タイムアウト: {} (90秒)", proxy_uri);
+            let error_body = format!("タイムアウト: バックエンドサーバー {}:{} が応答しません（90秒）",
+                target.name, target.port);
+            (StatusCode::GATEWAY_TIMEOUT, Body::from(error_body)).into_response()
+        }
+    }
+}
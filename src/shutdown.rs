@@ -0,0 +1,162 @@
+// グレースフルシャットダウン
+//
+// SIGINT / SIGTERM を受け取ったら新しい接続の受け付けを止め、処理中のリクエストが終わるのを
+// [shutdown] drain_timeout_secs まで待つ。WebSocket などのアップグレード済みの接続は
+// クローズフレームを送って閉じる（upgrade.rs）。
+// 終了コードは、すべて閉じられたら 0、期限切れや2回目のシグナルで切断したら 1（待ち受けを開始できなければ main が 2 を返す）。
+
+use serde::Deserialize;
+use std::{process::ExitCode, sync::Arc, time::Duration};
+use tokio::{sync::watch, task::JoinHandle};
+use tracing::{info, warn};
+
+use crate::metrics::Metrics;
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct ShutdownConfig {
+    // 処理中のリクエストとアップグレード済みの接続を待つ秒数
+    #[serde(default = "default_drain_timeout_secs")]
+    pub drain_timeout_secs: u64,
+}
+
+impl Default for ShutdownConfig {
+    fn default() -> Self {
+        ShutdownConfig {
+            drain_timeout_secs: default_drain_timeout_secs(),
+        }
+    }
+}
+
+fn default_drain_timeout_secs() -> u64 {
+    30
+}
+
+// シャットダウンの開始を知らせ、アップグレード済みの接続の数を数える
+#[derive(Clone)]
+pub struct Shutdown {
+    started: Arc<watch::Sender<bool>>,
+    upgrades: Arc<watch::Sender<usize>>,
+}
+
+impl Shutdown {
+    pub fn new() -> Shutdown {
+        Shutdown {
+            started: Arc::new(watch::channel(false).0),
+            upgrades: Arc::new(watch::channel(0).0),
+        }
+    }
+
+    fn trigger(&self) {
+        self.started.send_replace(true);
+    }
+
+    // シャットダウンが始まるまで待つ
+    pub async fn started(&self) {
+        let mut started = self.started.subscribe();
+        let _ = started.wait_for(|started| *started).await;
+    }
+
+    // アップグレード済みの接続が続いている間、保持する
+    pub fn track_upgrade(&self) -> UpgradeGuard {
+        self.upgrades.send_modify(|count| *count += 1);
+        UpgradeGuard(self.upgrades.clone())
+    }
+
+    fn upgrades(&self) -> usize {
+        *self.upgrades.borrow()
+    }
+
+    async fn upgrades_closed(&self) {
+        let mut upgrades = self.upgrades.subscribe();
+        let _ = upgrades.wait_for(|count| *count == 0).await;
+    }
+}
+
+pub struct UpgradeGuard(Arc<watch::Sender<usize>>);
+
+impl Drop for UpgradeGuard {
+    fn drop(&mut self) {
+        self.0.send_modify(|count| *count -= 1);
+    }
+}
+
+// SIGINT（Ctrl-C）と SIGTERM の受け口（同じシグナルを二重に受け取らないよう、最初に一度だけ登録する）
+pub struct Signals {
+    #[cfg(unix)]
+    interrupt: tokio::signal::unix::Signal,
+    #[cfg(unix)]
+    terminate: tokio::signal::unix::Signal,
+}
+
+impl Signals {
+    pub fn new() -> Signals {
+        #[cfg(unix)]
+        {
+            use tokio::signal::unix::{signal, SignalKind};
+            Signals {
+                interrupt: signal(SignalKind::interrupt()).expect("SIGINT を待ち受けられませんでした"),
+                terminate: signal(SignalKind::terminate()).expect("SIGTERM を待ち受けられませんでした"),
+            }
+        }
+        #[cfg(not(unix))]
+        Signals {}
+    }
+
+    // 次のシグナルを待ち、受け取ったシグナルの名前を返す
+    async fn recv(&mut self) -> &'static str {
+        #[cfg(unix)]
+        {
+            tokio::select! {
+                _ = self.interrupt.recv() => "SIGINT",
+                _ = self.terminate.recv() => "SIGTERM",
+            }
+        }
+        #[cfg(not(unix))]
+        {
+            let _ = tokio::signal::ctrl_c().await;
+            "Ctrl-C"
+        }
+    }
+}
+
+// シグナルを待ってシャットダウンを始め、待ち受けがすべて閉じるか期限が来たら終了コードを返す
+pub async fn run(
+    mut signals: Signals,
+    shutdown: Shutdown,
+    config: &ShutdownConfig,
+    servers: Vec<JoinHandle<()>>,
+    metrics: &Metrics,
+) -> ExitCode {
+    let received = signals.recv().await;
+    info!(
+        "{} を受け取りました。新しい接続の受け付けを止め、処理中のリクエストを最大{}秒待ちます",
+        received, config.drain_timeout_secs
+    );
+    shutdown.trigger();
+
+    let drain = async {
+        for server in servers {
+            let _ = server.await;
+        }
+        shutdown.upgrades_closed().await;
+    };
+    let drained = tokio::select! {
+        result = tokio::time::timeout(Duration::from_secs(config.drain_timeout_secs), drain) => result.is_ok(),
+        again = signals.recv() => {
+            warn!("{} をもう一度受け取りました。待たずに終了します", again);
+            false
+        }
+    };
+
+    if drained {
+        info!("すべての接続を閉じました。終了します");
+        ExitCode::SUCCESS
+    } else {
+        warn!(
+            "閉じきれなかった接続を切断して終了します（処理中のリクエスト: {}、アップグレード済みの接続: {}）",
+            metrics.in_flight(),
+            shutdown.upgrades()
+        );
+        ExitCode::FAILURE
+    }
+}
@@ -0,0 +1,75 @@
+// SSEや長時間のストリーミングレスポンスの判定
+//
+// これらは書き換えのためにボディを集めると届かなくなるため、バッファリングせずにそのまま流す。
+
+use axum::http::{header, HeaderMap, HeaderName};
+use std::{future::Future, time::Duration};
+use tokio::time::timeout;
+
+use crate::{request_body::Upload, Target};
+
+// 通常のリクエストでレスポンスヘッダーを待つ時間
+const RESPONSE_HEAD_TIMEOUT: Duration = Duration::from_secs(90);
+
+// クライアントがSSEを要求しているか
+pub fn accepts_event_stream(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(header::ACCEPT)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .any(|v| v.to_ascii_lowercase().contains("text/event-stream"))
+}
+
+// レスポンスヘッダーの待ち時間を超えた
+#[derive(Debug)]
+pub struct HeadTimeout;
+
+// レスポンスヘッダーを待つ。待ち時間はリクエストボディを最後に送った時点から数える（大きなアップロードでも途中で切れない）。
+// SSEは最初のイベントまでヘッダーを送らないサーバーもあるため待ち続ける
+pub async fn wait_for_head<F: Future>(wants_stream: bool, upload: &Upload, future: F) -> Result<F::Output, HeadTimeout> {
+    if wants_stream {
+        return Ok(future.await);
+    }
+    tokio::pin!(future);
+    loop {
+        let remaining = RESPONSE_HEAD_TIMEOUT.saturating_sub(upload.idle());
+        if remaining.is_zero() {
+            return Err(HeadTimeout);
+        }
+        if let Ok(output) = timeout(remaining, &mut future).await {
+            return Ok(output);
+        }
+    }
+}
+
+// バッファリングせずにそのまま流すべきレスポンスか
+pub fn is_streaming_response(headers: &HeaderMap, target: &Target) -> bool {
+    let header_str = |name: &HeaderName| {
+        headers
+            .get(name)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or("")
+            .to_ascii_lowercase()
+    };
+
+    let content_type = header_str(&header::CONTENT_TYPE);
+    if content_type.starts_with("text/event-stream")
+        || content_type.contains("ndjson")
+        || content_type.starts_with("multipart/x-mixed-replace")
+    {
+        return true;
+    }
+
+    // 中間者による変換を禁止している
+    if header_str(&header::CACHE_CONTROL).contains("no-transform") {
+        return true;
+    }
+
+    // nginxと同じくバッファリング無効を明示している
+    if header_str(&HeaderName::from_static("x-accel-buffering")) == "no" {
+        return true;
+    }
+
+    // チャンク転送をストリームとして扱うターゲット
+    target.stream_chunked && header_str(&header::TRANSFER_ENCODING).contains("chunked")
+}
@@ -0,0 +1,353 @@
+// 生TCPフォワーディング（PostgreSQL、Redis、gRPCなどHTTP以外のサービス向け）
+//
+// ターゲットごとの待ち受けポート、またはSNIで振り分ける共有TLSポートで接続を受け付け、
+// バイト列をそのままバックエンドへ転送する。
+
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+        TcpListener, TcpStream,
+    },
+    time::{sleep, timeout, Instant},
+};
+use tracing::{debug, error, warn};
+
+use crate::{
+    forward::{ForwardStats, ForwardStatsMap},
+    Config,
+};
+
+// 接続の受け付けに失敗したときに待つ時間（ファイルディスクリプタが尽きたときに空回りしないように）
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(100);
+// ClientHelloを待つ最大時間
+const CLIENT_HELLO_TIMEOUT: Duration = Duration::from_secs(5);
+// ClientHelloとして受け付ける最大サイズ
+const CLIENT_HELLO_MAX: usize = 16 * 1024;
+
+// TCPターゲットの待ち受けを開始する
+pub async fn spawn(config: &Config, stats: &ForwardStatsMap) {
+    // ターゲットごとの専用ポート
+    for target in config.targets.iter().filter(|t| t.is_tcp()) {
+        if target.sni.is_some() && config.tls_sni_port.is_none() {
+            warn!("tls_sni_port がないため、TCPターゲットの sni は使われません: {}", target.name);
+        }
+        let Some(listen_port) = target.listen_port else {
+            // sni だけで受けるターゲットは共有TLSポートで待ち受ける
+            if target.sni.is_none() || config.tls_sni_port.is_none() {
+                warn!("TCPターゲットに listen_port がありません（sni と tls_sni_port でも受けられません）: {}", target.name);
+            }
+            continue;
+        };
+        let listener = match TcpListener::bind(("127.0.0.1", listen_port)).await {
+            Ok(listener) => listener,
+            Err(err) => {
+                error!("TCP待ち受けに失敗しました: {} (127.0.0.1:{}) -> {}", target.name, listen_port, err);
+                continue;
+            }
+        };
+        let target = target.clone();
+        let target_stats = stats[&target.name].clone();
+        tokio::spawn(async move {
+            loop {
+                let (inbound, peer) = match listener.accept().await {
+                    Ok(accepted) => accepted,
+                    Err(err) => {
+                        error!("TCP接続の受け付けに失敗しました: {} -> {}", target.name, err);
+                        sleep(ACCEPT_RETRY_DELAY).await;
+                        continue;
+                    }
+                };
+                debug!("TCP接続: {} -> {} (localhost:{})", peer, target.name, target.port);
+                tokio::spawn(forward(inbound, target.port, target_stats.clone()));
+            }
+        });
+    }
+
+    // SNIで振り分ける共有TLSポート
+    if let Some(sni_port) = config.tls_sni_port {
+        let routes: HashMap<String, (u16, Arc<ForwardStats>)> = config
+            .targets
+            .iter()
+            .filter(|t| t.is_tcp())
+            .filter_map(|t| {
+                t.sni
+                    .as_ref()
+                    .map(|sni| (sni.to_ascii_lowercase(), (t.port, stats[&t.name].clone())))
+            })
+            .collect();
+        match TcpListener::bind(("127.0.0.1", sni_port)).await {
+            Ok(listener) => {
+                let routes = Arc::new(routes);
+                tokio::spawn(async move {
+                    loop {
+                        let (inbound, peer) = match listener.accept().await {
+                            Ok(accepted) => accepted,
+                            Err(err) => {
+                                error!("SNI接続の受け付けに失敗しました: {}", err);
+                                sleep(ACCEPT_RETRY_DELAY).await;
+                                continue;
+                            }
+                        };
+                        let routes = routes.clone();
+                        tokio::spawn(async move {
+                            let server_name = match read_sni(&inbound).await {
+                                Some(name) => name,
+                                None => {
+                                    warn!("SNIを取得できませんでした: {}", peer);
+                                    return;
+                                }
+                            };
+                            match routes.get(&server_name) {
+                                Some((port, target_stats)) => {
+                                    debug!("SNI接続: {} ({}) -> localhost:{}", peer, server_name, port);
+                                    forward(inbound, *port, target_stats.clone()).await;
+                                }
+                                None => warn!("SNIに対応するターゲットがありません: {}", server_name),
+                            }
+                        });
+                    }
+                });
+            }
+            Err(err) => error!("SNI待ち受けに失敗しました: 127.0.0.1:{} -> {}", sni_port, err),
+        }
+    }
+}
+
+// 接続をバックエンドへ転送し、終了するまで統計を更新する
+async fn forward(inbound: TcpStream, port: u16, stats: Arc<ForwardStats>) {
+    let outbound = match TcpStream::connect(("127.0.0.1", port)).await {
+        Ok(stream) => stream,
+        Err(err) => {
+            error!("TCPバックエンドに接続できません: localhost:{} -> {}", port, err);
+            return;
+        }
+    };
+
+    stats.active.fetch_add(1, Ordering::Relaxed);
+    stats.total.fetch_add(1, Ordering::Relaxed);
+
+    let (client_read, client_write) = inbound.into_split();
+    let (server_read, server_write) = outbound.into_split();
+    tokio::join!(
+        pipe(client_read, server_write, &stats.bytes_in),
+        pipe(server_read, client_write, &stats.bytes_out),
+    );
+
+    stats.active.fetch_sub(1, Ordering::Relaxed);
+}
+
+// 片方向のコピー。転送したバイト数を逐次加算する
+async fn pipe(mut reader: OwnedReadHalf, mut writer: OwnedWriteHalf, counter: &AtomicU64) {
+    let mut buf = vec![0u8; 16 * 1024];
+    loop {
+        let n = match reader.read(&mut buf).await {
+            Ok(0) | Err(_) => break,
+            Ok(n) => n,
+        };
+        if writer.write_all(&buf[..n]).await.is_err() {
+            break;
+        }
+        counter.fetch_add(n as u64, Ordering::Relaxed);
+    }
+    let _ = writer.shutdown().await;
+}
+
+// ClientHelloをpeekしてSNIを取り出す（バイト列はソケットに残るのでそのまま転送できる）
+async fn read_sni(stream: &TcpStream) -> Option<String> {
+    let deadline = Instant::now() + CLIENT_HELLO_TIMEOUT;
+    let mut buf = vec![0u8; CLIENT_HELLO_MAX];
+    let mut last_len = 0;
+    loop {
+        let remaining = deadline.checked_duration_since(Instant::now())?;
+        let n = timeout(remaining, stream.peek(&mut buf)).await.ok()?.ok()?;
+        if n == 0 {
+            return None;
+        }
+        match parse_sni(&buf[..n]) {
+            SniResult::Found(name) => return Some(name.to_ascii_lowercase()),
+            SniResult::Missing => return None,
+            SniResult::Incomplete if n >= CLIENT_HELLO_MAX => return None,
+            SniResult::Incomplete => {
+                // peekは新しいデータが届くまで同じ内容を返すため少し待つ
+                if n == last_len {
+                    sleep(Duration::from_millis(10)).await;
+                }
+                last_len = n;
+            }
+        }
+    }
+}
+
+enum SniResult {
+    Found(String),
+    Missing,
+    Incomplete,
+}
+
+// TLSレコードからserver_name拡張を探す
+fn parse_sni(data: &[u8]) -> SniResult {
+    // TLSレコードヘッダー: type(1) version(2) length(2)
+    if data.len() < 5 {
+        return SniResult::Incomplete;
+    }
+    if data[0] != 0x16 {
+        return SniResult::Missing;
+    }
+    let record_len = u16::from_be_bytes([data[3], data[4]]) as usize;
+    if data.len() < 5 + record_len {
+        return SniResult::Incomplete;
+    }
+    let hello = &data[5..5 + record_len];
+
+    let mut reader = ByteReader { data: hello, pos: 0 };
+    let parsed = (|| {
+        // Handshake: type(1) length(3)
+        if reader.u8()? != 0x01 {
+            return None;
+        }
+        reader.skip(3)?;
+        // client_version(2) random(32)
+        reader.skip(2 + 32)?;
+        let session_id_len = reader.u8()? as usize;
+        reader.skip(session_id_len)?;
+        let cipher_suites_len = reader.u16()? as usize;
+        reader.skip(cipher_suites_len)?;
+        let compression_len = reader.u8()? as usize;
+        reader.skip(compression_len)?;
+        let extensions_len = reader.u16()? as usize;
+        let extensions_end = reader.pos + extensions_len;
+        while reader.pos + 4 <= extensions_end {
+            let ext_type = reader.u16()?;
+            let ext_len = reader.u16()? as usize;
+            if ext_type != 0x0000 {
+                reader.skip(ext_len)?;
+                continue;
+            }
+            // server_name_list: length(2) [name_type(1) length(2) name]*
+            reader.skip(2)?;
+            let name_type = reader.u8()?;
+            let name_len = reader.u16()? as usize;
+            let name = reader.take(name_len)?;
+            if name_type != 0 {
+                return None;
+            }
+            return String::from_utf8(name.to_vec()).ok();
+        }
+        None
+    })();
+
+    match parsed {
+        Some(name) => SniResult::Found(name),
+        None => SniResult::Missing,
+    }
+}
+
+struct ByteReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> ByteReader<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        let slice = self.data.get(self.pos..self.pos + n)?;
+        self.pos += n;
+        Some(slice)
+    }
+
+    fn skip(&mut self, n: usize) -> Option<()> {
+        self.take(n).map(|_| ())
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|b| b[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.take(2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 拡張を並べた ClientHello のTLSレコードを作る
+    fn client_hello(extensions: &[(u16, Vec<u8>)]) -> Vec<u8> {
+        let mut body = vec![0x03, 0x03];
+        body.extend_from_slice(&[0u8; 32]);
+        // session_id（空）、cipher_suites（1つ）、compression_methods（null）
+        body.extend_from_slice(&[0x00, 0x00, 0x02, 0x13, 0x01, 0x01, 0x00]);
+        let extensions: Vec<u8> = extensions
+            .iter()
+            .flat_map(|(ext_type, data)| {
+                let mut ext = ext_type.to_be_bytes().to_vec();
+                ext.extend_from_slice(&(data.len() as u16).to_be_bytes());
+                ext.extend_from_slice(data);
+                ext
+            })
+            .collect();
+        body.extend_from_slice(&(extensions.len() as u16).to_be_bytes());
+        body.extend_from_slice(&extensions);
+
+        let mut handshake = vec![0x01];
+        handshake.extend_from_slice(&(body.len() as u32).to_be_bytes()[1..]);
+        handshake.extend_from_slice(&body);
+        let mut record = vec![0x16, 0x03, 0x01];
+        record.extend_from_slice(&(handshake.len() as u16).to_be_bytes());
+        record.extend_from_slice(&handshake);
+        record
+    }
+
+    fn server_name(name: &str) -> (u16, Vec<u8>) {
+        let mut entry = vec![0x00];
+        entry.extend_from_slice(&(name.len() as u16).to_be_bytes());
+        entry.extend_from_slice(name.as_bytes());
+        let mut data = (entry.len() as u16).to_be_bytes().to_vec();
+        data.extend_from_slice(&entry);
+        (0x0000, data)
+    }
+
+    #[test]
+    fn finds_server_name_after_other_extensions() {
+        let hello = client_hello(&[
+            (0x000a, vec![0x00, 0x02, 0x00, 0x1d]),
+            (0x0010, vec![0x00, 0x03, 0x02, b'h', b'2']),
+            server_name("db.localhost"),
+            (0x002b, vec![0x02, 0x03, 0x04]),
+        ]);
+        assert!(matches!(parse_sni(&hello), SniResult::Found(name) if name == "db.localhost"));
+    }
+
+    #[test]
+    fn missing_without_server_name() {
+        let hello = client_hello(&[(0x000a, vec![0x00, 0x02, 0x00, 0x1d]), (0x0010, vec![0x00, 0x03, 0x02, b'h', b'2'])]);
+        assert!(matches!(parse_sni(&hello), SniResult::Missing));
+        assert!(matches!(parse_sni(&client_hello(&[])), SniResult::Missing));
+        // TLSのハンドシェイクでない
+        assert!(matches!(parse_sni(b"GET / HTTP/1.1\r\n\r\n"), SniResult::Missing));
+    }
+
+    #[test]
+    fn incomplete_until_the_whole_record_arrives() {
+        let hello = client_hello(&[(0x0010, vec![0x00, 0x03, 0x02, b'h', b'2']), server_name("db.localhost")]);
+        for len in 0..hello.len() {
+            assert!(matches!(parse_sni(&hello[..len]), SniResult::Incomplete), "{} バイト", len);
+        }
+    }
+
+    #[test]
+    fn missing_when_server_name_is_cut_short() {
+        // レコードは揃っているが、名前の長さが拡張の中に収まっていない
+        let hello = client_hello(&[(0x0000, vec![0x00, 0x0f, 0x00, 0x00, 0x0c, b'd', b'b'])]);
+        assert!(matches!(parse_sni(&hello), SniResult::Missing));
+    }
+}
@@ -0,0 +1,124 @@
+// HTTPS待ち受け（ブラウザからのHTTP/2はTLS上のALPNでのみ利用できる）
+
+use axum::{extract::ConnectInfo, Extension, Router};
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo},
+    server::{conn::auto::Builder, graceful::GracefulShutdown},
+    service::TowerToHyperService,
+};
+use serde::Deserialize;
+use std::{fs::File, io::BufReader, sync::Arc, time::Duration};
+use tokio::{
+    net::TcpListener,
+    task::JoinHandle,
+    time::{sleep, timeout},
+};
+use tokio_rustls::{rustls, TlsAcceptor};
+use tracing::{debug, error, info, warn};
+
+use crate::{forwarded, shutdown::Shutdown};
+
+// ClientHello を送らないまま居座る接続を閉じるまでの時間
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+// 接続の受け付けに失敗したときに待つ時間（ファイルディスクリプタが尽きたときに空回りしないように）
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct TlsConfig {
+    // HTTPSで待ち受けるポート
+    pub port: u16,
+    // PEM形式の証明書チェーン
+    pub cert_path: String,
+    // PEM形式の秘密鍵
+    pub key_path: String,
+}
+
+// 証明書と秘密鍵を読み込み、h2とhttp/1.1をALPNで提示するTLS設定を作る
+fn load_server_config(config: &TlsConfig) -> Result<rustls::ServerConfig, String> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(
+        File::open(&config.cert_path).map_err(|e| format!("{}: {}", config.cert_path, e))?,
+    ))
+    .collect::<Result<Vec<_>, _>>()
+    .map_err(|e| format!("{}: {}", config.cert_path, e))?;
+    let key = rustls_pemfile::private_key(&mut BufReader::new(
+        File::open(&config.key_path).map_err(|e| format!("{}: {}", config.key_path, e))?,
+    ))
+    .map_err(|e| format!("{}: {}", config.key_path, e))?
+    .ok_or_else(|| format!("{}: 秘密鍵が見つかりません", config.key_path))?;
+
+    let mut server_config = rustls::ServerConfig::builder_with_provider(Arc::new(
+        rustls::crypto::ring::default_provider(),
+    ))
+    .with_safe_default_protocol_versions()
+    .map_err(|e| e.to_string())?
+    .with_no_client_auth()
+    .with_single_cert(certs, key)
+    .map_err(|e| e.to_string())?;
+    server_config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok(server_config)
+}
+
+// HTTPSの待ち受けを開始する（シャットダウンが始まったら受け付けを止め、すべての接続が閉じたら終わる）
+pub async fn spawn(config: &TlsConfig, app: Router, shutdown: Shutdown) -> Option<JoinHandle<()>> {
+    let server_config = match load_server_config(config) {
+        Ok(server_config) => server_config,
+        Err(err) => {
+            error!("TLS設定の読み込みに失敗しました: {}", err);
+            return None;
+        }
+    };
+    let acceptor = TlsAcceptor::from(Arc::new(server_config));
+    let listener = match TcpListener::bind(("127.0.0.1", config.port)).await {
+        Ok(listener) => listener,
+        Err(err) => {
+            error!("HTTPS待ち受けに失敗しました: 127.0.0.1:{} -> {}", config.port, err);
+            return None;
+        }
+    };
+
+    info!("https://localhost:{} でも待ち受けています（HTTP/2対応）", config.port);
+
+    Some(tokio::spawn(async move {
+        let graceful = GracefulShutdown::new();
+        loop {
+            let accepted = tokio::select! {
+                accepted = listener.accept() => accepted,
+                _ = shutdown.started() => break,
+            };
+            let (stream, peer) = match accepted {
+                Ok(accepted) => accepted,
+                Err(err) => {
+                    error!("HTTPS接続の受け付けに失敗しました: {}", err);
+                    sleep(ACCEPT_RETRY_DELAY).await;
+                    continue;
+                }
+            };
+            let acceptor = acceptor.clone();
+            let app = app.clone();
+            let watcher = graceful.watcher();
+            tokio::spawn(async move {
+                let stream = match timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                    Ok(Ok(stream)) => stream,
+                    Ok(Err(err)) => {
+                        warn!("TLSハンドシェイクに失敗しました: {} -> {}", peer, err);
+                        return;
+                    }
+                    Err(_) => {
+                        debug!("TLSハンドシェイクがタイムアウトしました: {}", peer);
+                        return;
+                    }
+                };
+                // 通常の待ち受けと同じく接続元アドレスを参照できるようにする
+                let app = app.layer(Extension(ConnectInfo(peer))).layer(Extension(forwarded::Tls));
+                let service = TowerToHyperService::new(app);
+                let builder = Builder::new(TokioExecutor::new());
+                let connection = builder.serve_connection_with_upgrades(TokioIo::new(stream), service);
+                if let Err(err) = watcher.watch(connection.into_owned()).await {
+                    debug!("HTTPS接続エラー: {} -> {}", peer, err);
+                }
+            });
+        }
+        drop(listener);
+        graceful.shutdown().await;
+    }))
+}
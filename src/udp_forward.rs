@@ -0,0 +1,185 @@
+// UDPフォワーディング（DNSスタブ、statsd、TURNサーバーなど向け）
+//
+// クライアントのアドレスごとにセッションを作り、専用のソケットでバックエンドと通信する。
+// 一定時間やり取りのないセッションは破棄する。
+
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{atomic::Ordering, Arc, Mutex},
+    time::Duration,
+};
+use tokio::{
+    net::UdpSocket,
+    time::{timeout, Instant},
+};
+use tracing::{debug, error, warn};
+
+use crate::{
+    forward::{ForwardStats, ForwardStatsMap},
+    Config,
+};
+
+// セッションのアイドルタイムアウト（秒）の既定値
+const DEFAULT_IDLE_TIMEOUT_SECS: u64 = 60;
+// UDPデータグラムの最大サイズ
+const MAX_DATAGRAM: usize = 65535;
+// ターゲットごとのセッション数の上限（送信元を偽ったデータグラムでソケットを使い切らないように）
+const MAX_SESSIONS: usize = 1024;
+
+struct Session {
+    upstream: Arc<UdpSocket>,
+    last_active: Mutex<Instant>,
+}
+
+impl Session {
+    fn touch(&self) {
+        *self.last_active.lock().unwrap() = Instant::now();
+    }
+
+    fn idle_for(&self) -> Duration {
+        self.last_active.lock().unwrap().elapsed()
+    }
+}
+
+type Sessions = Arc<Mutex<HashMap<SocketAddr, Arc<Session>>>>;
+
+// UDPターゲットの待ち受けを開始する。idle_timeout_secs = 0 は起動時にエラーにする
+pub async fn spawn(config: &Config, stats: &ForwardStatsMap) -> Result<(), String> {
+    let udp_targets = || config.targets.iter().filter(|t| t.is_udp());
+    if let Some(target) = udp_targets().find(|t| t.idle_timeout_secs == Some(0)) {
+        return Err(format!("idle_timeout_secs は1以上にしてください: {}", target.name));
+    }
+    for target in udp_targets() {
+        let Some(listen_port) = target.listen_port else {
+            warn!("UDPターゲットに listen_port がありません: {}", target.name);
+            continue;
+        };
+        let listener = match UdpSocket::bind(("127.0.0.1", listen_port)).await {
+            Ok(socket) => Arc::new(socket),
+            Err(err) => {
+                error!("UDP待ち受けに失敗しました: {} (127.0.0.1:{}) -> {}", target.name, listen_port, err);
+                continue;
+            }
+        };
+        let idle = Duration::from_secs(target.idle_timeout_secs.unwrap_or(DEFAULT_IDLE_TIMEOUT_SECS));
+        tokio::spawn(run(
+            listener,
+            target.name.clone(),
+            target.port,
+            idle,
+            stats[&target.name].clone(),
+        ));
+    }
+    Ok(())
+}
+
+// クライアントからのデータグラムを受け取り、セッションごとにバックエンドへ送る
+async fn run(listener: Arc<UdpSocket>, name: String, port: u16, idle: Duration, stats: Arc<ForwardStats>) {
+    let sessions: Sessions = Arc::new(Mutex::new(HashMap::new()));
+    let mut buf = vec![0u8; MAX_DATAGRAM];
+    // 上限に達している間の警告は一度だけにする
+    let mut full = false;
+    loop {
+        let (n, peer) = match listener.recv_from(&mut buf).await {
+            Ok(received) => received,
+            Err(err) => {
+                error!("UDP受信エラー: {} -> {}", name, err);
+                continue;
+            }
+        };
+
+        // 取り出しと同じロックの中で使用中にする（relay_replies がアイドルで破棄しようとしているセッションを使わないように）
+        let existing = sessions.lock().unwrap().get(&peer).inspect(|session| session.touch()).cloned();
+        let session = match existing {
+            Some(session) => session,
+            None => {
+                if sessions.lock().unwrap().len() >= MAX_SESSIONS {
+                    if !full {
+                        warn!("UDPセッション数が上限（{}）に達したため、新しいクライアントを破棄します: {}", MAX_SESSIONS, name);
+                        full = true;
+                    }
+                    continue;
+                }
+                full = false;
+                let upstream = match connect_upstream(port).await {
+                    Ok(socket) => Arc::new(socket),
+                    Err(err) => {
+                        error!("UDPバックエンドに接続できません: localhost:{} -> {}", port, err);
+                        continue;
+                    }
+                };
+                let session = Arc::new(Session {
+                    upstream,
+                    last_active: Mutex::new(Instant::now()),
+                });
+                sessions.lock().unwrap().insert(peer, session.clone());
+                stats.active.fetch_add(1, Ordering::Relaxed);
+                stats.total.fetch_add(1, Ordering::Relaxed);
+                debug!("UDPセッション開始: {} -> {} (localhost:{})", peer, name, port);
+                tokio::spawn(relay_replies(
+                    listener.clone(),
+                    session.clone(),
+                    peer,
+                    idle,
+                    sessions.clone(),
+                    stats.clone(),
+                ));
+                session
+            }
+        };
+
+        session.touch();
+        match session.upstream.send(&buf[..n]).await {
+            Ok(sent) => {
+                stats.bytes_in.fetch_add(sent as u64, Ordering::Relaxed);
+            }
+            Err(err) => error!("UDP送信エラー: {} -> localhost:{} -> {}", peer, port, err),
+        }
+    }
+}
+
+async fn connect_upstream(port: u16) -> std::io::Result<UdpSocket> {
+    let socket = UdpSocket::bind(("127.0.0.1", 0)).await?;
+    socket.connect(("127.0.0.1", port)).await?;
+    Ok(socket)
+}
+
+// バックエンドからの応答をクライアントへ返す。アイドルタイムアウトでセッションを終了する
+async fn relay_replies(
+    listener: Arc<UdpSocket>,
+    session: Arc<Session>,
+    peer: SocketAddr,
+    idle: Duration,
+    sessions: Sessions,
+    stats: Arc<ForwardStats>,
+) {
+    let mut buf = vec![0u8; MAX_DATAGRAM];
+    loop {
+        let remaining = idle.saturating_sub(session.idle_for());
+        if remaining.is_zero() {
+            // run が同じロックの中で使用中にしていなければ、まだアイドルのまま破棄できる
+            let mut sessions = sessions.lock().unwrap();
+            if session.idle_for() >= idle {
+                sessions.remove(&peer);
+                break;
+            }
+            continue;
+        }
+        match timeout(remaining, session.upstream.recv(&mut buf)).await {
+            Ok(Ok(n)) => {
+                session.touch();
+                if listener.send_to(&buf[..n], peer).await.is_ok() {
+                    stats.bytes_out.fetch_add(n as u64, Ordering::Relaxed);
+                }
+            }
+            // バックエンド未起動時のICMPエラーなど。セッションは維持する
+            Ok(Err(_)) => continue,
+            // クライアント側の送信で last_active が更新されている可能性があるので再計算する
+            Err(_) => continue,
+        }
+    }
+
+    stats.active.fetch_sub(1, Ordering::Relaxed);
+    debug!("UDPセッション終了（アイドル）: {}", peer);
+}
@@ -0,0 +1,207 @@
+// アップグレードした接続（WebSocket など）の中継
+//
+// バックエンドが 101 Switching Protocols を返したら、クライアントとバックエンドの接続をそのままつなぐ。
+// シャットダウン時、WebSocket はフレームの切れ目でクローズフレーム（1001 Going Away）を双方に送ってから閉じる。
+// それ以外のプロトコルは中身を解釈できないため、そのまま接続を閉じる。
+
+use axum::{
+    extract::Request,
+    http::{header, HeaderMap, Response, StatusCode},
+};
+use hyper::upgrade::OnUpgrade;
+use hyper_util::rt::TokioIo;
+use ring::rand::{SecureRandom, SystemRandom};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tracing::{debug, warn};
+
+use crate::shutdown::Shutdown;
+
+// クローズフレームを送ってから相手の応答を待つ時間
+const CLOSE_WAIT: Duration = Duration::from_secs(2);
+const CLOSE_GOING_AWAY: u16 = 1001;
+const CLOSE_REASON: &str = "proxy shutting down";
+
+// 101 が返ったときに使う、クライアント側の接続
+pub struct PendingUpgrade {
+    client: OnUpgrade,
+    websocket: bool,
+}
+
+fn wants_upgrade(headers: &HeaderMap) -> bool {
+    headers.contains_key(header::UPGRADE)
+        && headers
+            .get_all(header::CONNECTION)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .any(|token| token.trim().eq_ignore_ascii_case("upgrade"))
+}
+
+// アップグレードを求めるリクエストから、クライアント側の接続を取り出しておく
+pub fn take(req: &mut Request) -> Option<PendingUpgrade> {
+    if !wants_upgrade(req.headers()) {
+        return None;
+    }
+    let websocket = req
+        .headers()
+        .get(header::UPGRADE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.eq_ignore_ascii_case("websocket"));
+    let client = req.extensions_mut().remove::<OnUpgrade>()?;
+    Some(PendingUpgrade { client, websocket })
+}
+
+impl PendingUpgrade {
+    // バックエンドが 101 を返していれば、クライアントへのレスポンスを送った後に双方をつなぐ
+    pub fn tunnel<B>(self, target: &str, response: &mut Response<B>, shutdown: &Shutdown) {
+        if response.status() != StatusCode::SWITCHING_PROTOCOLS {
+            return;
+        }
+        let backend = hyper::upgrade::on(response);
+        let guard = shutdown.track_upgrade();
+        let shutdown = shutdown.clone();
+        let target = target.to_string();
+        tokio::spawn(async move {
+            let _guard = guard;
+            let (client, backend) = match tokio::try_join!(self.client, backend) {
+                Ok(upgraded) => upgraded,
+                Err(err) => {
+                    warn!("アップグレードした接続をつなげませんでした: {} -> {}", target, err);
+                    return;
+                }
+            };
+            debug!("アップグレードした接続の中継を開始しました: {}", target);
+            let (client_read, client_write) = tokio::io::split(TokioIo::new(client));
+            let (backend_read, backend_write) = tokio::io::split(TokioIo::new(backend));
+            let _ = tokio::join!(
+                // クライアントからバックエンドへ送るフレームはマスクする
+                relay(client_read, backend_write, self.websocket.then(|| close_frame(true)), shutdown.clone()),
+                relay(backend_read, client_write, self.websocket.then(|| close_frame(false)), shutdown),
+            );
+            debug!("アップグレードした接続を閉じました: {}", target);
+        });
+    }
+}
+
+// 一方向の中継。シャットダウンが始まったらフレームの切れ目でクローズフレームを送り、相手の応答を待って閉じる
+async fn relay<R, W>(mut from: R, mut to: W, close: Option<Vec<u8>>, shutdown: Shutdown) -> std::io::Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut frames = Frames::default();
+    let mut buf = vec![0u8; 16 * 1024];
+    let mut closing = false;
+    loop {
+        if closing && (close.is_none() || frames.at_boundary()) {
+            break;
+        }
+        tokio::select! {
+            read = from.read(&mut buf) => {
+                let n = read?;
+                if n == 0 {
+                    return to.shutdown().await;
+                }
+                to.write_all(&buf[..n]).await?;
+                if close.is_some() {
+                    frames.advance(&buf[..n]);
+                }
+            }
+            _ = shutdown.started(), if !closing => closing = true,
+        }
+    }
+
+    if let Some(close) = close {
+        to.write_all(&close).await?;
+        to.flush().await?;
+        // 相手からのクローズフレームは転送せずに読み捨てる
+        let mut reply = Frames::default();
+        let _ = tokio::time::timeout(CLOSE_WAIT, async {
+            while !(reply.closed && reply.at_boundary()) {
+                match from.read(&mut buf).await {
+                    Ok(0) | Err(_) => break,
+                    Ok(n) => reply.advance(&buf[..n]),
+                }
+            }
+        })
+        .await;
+    }
+    to.shutdown().await
+}
+
+// WebSocket のフレームの切れ目を追いかける
+#[derive(Default)]
+struct Frames {
+    header: Vec<u8>,
+    remaining: u64,
+    // クローズフレームを読んだ
+    closed: bool,
+}
+
+impl Frames {
+    fn at_boundary(&self) -> bool {
+        self.header.is_empty() && self.remaining == 0
+    }
+
+    fn advance(&mut self, mut data: &[u8]) {
+        while !data.is_empty() {
+            if self.remaining > 0 {
+                let n = self.remaining.min(data.len() as u64) as usize;
+                self.remaining -= n as u64;
+                data = &data[n..];
+                continue;
+            }
+            self.header.push(data[0]);
+            data = &data[1..];
+            if let Some(length) = payload_length(&self.header) {
+                if self.header[0] & 0x0f == 0x8 {
+                    self.closed = true;
+                }
+                self.remaining = length;
+                self.header.clear();
+            }
+        }
+    }
+}
+
+// ヘッダーがそろっていればペイロードの長さを返す
+fn payload_length(header: &[u8]) -> Option<u64> {
+    if header.len() < 2 {
+        return None;
+    }
+    let (extended, length) = match header[1] & 0x7f {
+        126 => (2, None),
+        127 => (8, None),
+        length => (0, Some(length as u64)),
+    };
+    let mask = if header[1] & 0x80 != 0 { 4 } else { 0 };
+    if header.len() < 2 + extended + mask {
+        return None;
+    }
+    length.or_else(|| {
+        Some(
+            header[2..2 + extended]
+                .iter()
+                .fold(0u64, |acc, byte| (acc << 8) | *byte as u64),
+        )
+    })
+}
+
+// 1001 Going Away のクローズフレーム（クライアントとして送る場合はマスクする）
+fn close_frame(masked: bool) -> Vec<u8> {
+    let mut payload = CLOSE_GOING_AWAY.to_be_bytes().to_vec();
+    payload.extend_from_slice(CLOSE_REASON.as_bytes());
+    let mut frame = vec![0x88, payload.len() as u8];
+    if masked {
+        let mut key = [0u8; 4];
+        SystemRandom::new().fill(&mut key).expect("乱数を生成できませんでした");
+        frame[1] |= 0x80;
+        frame.extend_from_slice(&key);
+        for (i, byte) in payload.iter_mut().enumerate() {
+            *byte ^= key[i % 4];
+        }
+    }
+    frame.extend_from_slice(&payload);
+    frame
+}
@@ -0,0 +1,55 @@
+// [targets.auth] だけを書いた場合でも、管理画面が LAN から認証なしで開けないこと、
+// Unix ドメインソケットからの接続がループバックとして扱われないことを確認する
+
+mod common;
+
+use common::{free_port, read_status_line, target_toml, Router};
+use tokio::{io::AsyncWriteExt, net::TcpStream};
+
+// 前段のプロキシ（127.0.0.1）が X-Forwarded-For で LAN のクライアントを伝える構成
+fn config_toml() -> String {
+    format!(
+        "[forwarded]\ntrusted_proxies = [\"127.0.0.1\"]\n\n{}",
+        target_toml("api", free_port(), "[targets.auth]\nlogin_token = \"0123456789abcdef0123\"")
+    )
+}
+
+async fn get(port: u16, path: &str, forwarded_for: Option<&str>) -> String {
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+    let forwarded = forwarded_for
+        .map(|ip| format!("X-Forwarded-For: {}\r\n", ip))
+        .unwrap_or_default();
+    let request = format!(
+        "GET {} HTTP/1.1\r\nHost: localhost:{}\r\n{}Connection: close\r\n\r\n",
+        path, port, forwarded
+    );
+    stream.write_all(request.as_bytes()).await.unwrap();
+    read_status_line(&mut stream).await
+}
+
+#[tokio::test]
+async fn admin_endpoints_require_target_auth_without_global_auth() {
+    let router = Router::start(&config_toml()).await;
+
+    let status = get(router.port, "/__portrooter/inspect/har", Some("192.168.1.50")).await;
+    assert_eq!(status, "HTTP/1.1 401 Unauthorized");
+    let status = get(router.port, "/", Some("192.168.1.50")).await;
+    assert_eq!(status, "HTTP/1.1 401 Unauthorized");
+
+    // ループバックからは従来どおりログインなしで開ける
+    let status = get(router.port, "/__portrooter/inspect/har", None).await;
+    assert_ne!(status, "HTTP/1.1 401 Unauthorized");
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn unix_socket_peer_is_not_trusted_as_loopback() {
+    let router = Router::start(&format!("listen = [\"127.0.0.1\", \"./portrooter.sock\"]\n\n{}", config_toml())).await;
+
+    let mut stream = tokio::net::UnixStream::connect(router.dir.join("portrooter.sock")).await.unwrap();
+    stream
+        .write_all(b"GET /proxy/api/ HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+        .await
+        .unwrap();
+    assert_eq!(read_status_line(&mut stream).await, "HTTP/1.1 401 Unauthorized");
+}
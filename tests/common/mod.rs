@@ -0,0 +1,177 @@
+// 結合テスト用のヘルパー
+//
+// 一時ディレクトリに config.toml を書き出し、ビルド済みの PortRooter を起動する。
+// バックエンドの代役とクライアントが生の HTTP/1.1 を読み書きするための関数もここに置く。
+
+use std::{
+    net::TcpListener as StdTcpListener,
+    path::PathBuf,
+    process::{Child, Command, Stdio},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt},
+    net::TcpStream,
+    time::{sleep, timeout},
+};
+
+pub struct Router {
+    child: Child,
+    // config.toml を置いた作業ディレクトリ
+    pub dir: PathBuf,
+    pub port: u16,
+}
+
+impl Router {
+    // targets_toml には [[targets]] などの設定を渡す
+    pub async fn start(targets_toml: &str) -> Router {
+        let router = Router::spawn(targets_toml, Stdio::null());
+        let port = router.port;
+        for _ in 0..100 {
+            if TcpStream::connect(("127.0.0.1", port)).await.is_ok() {
+                return router;
+            }
+            sleep(Duration::from_millis(50)).await;
+        }
+        panic!("PortRouterが起動しませんでした: {}", port);
+    }
+
+    fn spawn(targets_toml: &str, stdout: Stdio) -> Router {
+        let port = free_port();
+        let dir = std::env::temp_dir().join(format!("portrooter-test-{}-{}", std::process::id(), port));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(
+            dir.join("config.toml"),
+            format!("router_port = {}\n\n{}", port, targets_toml),
+        )
+        .unwrap();
+
+        let child = Command::new(env!("CARGO_BIN_EXE_portrooter"))
+            .current_dir(&dir)
+            .stdout(stdout)
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap();
+        Router { child, dir, port }
+    }
+
+    // 起動に失敗するはずの設定で実行し、終了コードとログを返す
+    #[allow(dead_code)]
+    pub async fn start_and_fail(targets_toml: &str) -> (Option<i32>, String) {
+        let mut router = Router::spawn(targets_toml, Stdio::piped());
+        let code = router.exit_code(Duration::from_secs(5)).await;
+        let mut output = String::new();
+        if let Some(mut stdout) = router.child.stdout.take() {
+            let _ = std::io::Read::read_to_string(&mut stdout, &mut output);
+        }
+        (code, output)
+    }
+
+    // シグナル（TERM / INT）を送る
+    #[allow(dead_code)]
+    pub fn signal(&self, name: &str) {
+        let status = Command::new("kill")
+            .arg(format!("-{}", name))
+            .arg(self.child.id().to_string())
+            .status()
+            .unwrap();
+        assert!(status.success(), "シグナルを送れませんでした: {}", name);
+    }
+
+    // 終了するまで待ち、終了コードを返す（時間内に終わらなければ None）
+    #[allow(dead_code)]
+    pub async fn exit_code(&mut self, within: Duration) -> Option<i32> {
+        let started = std::time::Instant::now();
+        while started.elapsed() < within {
+            if let Some(status) = self.child.try_wait().unwrap() {
+                return status.code();
+            }
+            sleep(Duration::from_millis(50)).await;
+        }
+        None
+    }
+}
+
+impl Drop for Router {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+// 空いているポートを取得する
+pub fn free_port() -> u16 {
+    StdTcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+// ターゲットを1つ登録する設定（extra はターゲットに追加する行やサブテーブル）
+#[allow(dead_code)]
+pub fn target_toml(name: &str, port: u16, extra: &str) -> String {
+    format!(
+        "[[targets]]\nname = \"{}\"\nport = {}\ndescription = \"バックエンドの代役\"\n{}\n",
+        name, port, extra
+    )
+}
+
+// リクエストまたはレスポンスのヘッダー部分（空行まで）を読む。途中で閉じられたら None
+#[allow(dead_code)]
+pub async fn read_head<S: AsyncRead + Unpin>(stream: &mut S) -> Option<String> {
+    let mut head = Vec::new();
+    let mut byte = [0u8; 1];
+    while !head.ends_with(b"\r\n\r\n") {
+        match stream.read(&mut byte).await {
+            Ok(0) | Err(_) => return None,
+            Ok(_) => head.push(byte[0]),
+        }
+    }
+    Some(String::from_utf8_lossy(&head).to_string())
+}
+
+// ステータス行だけ読む（keep-alive の接続でも閉じられるのを待たない）
+#[allow(dead_code)]
+pub async fn read_status_line<S: AsyncRead + Unpin>(stream: &mut S) -> String {
+    let mut line = Vec::new();
+    let mut byte = [0u8; 1];
+    let _ = timeout(Duration::from_secs(5), async {
+        while !line.ends_with(b"\r\n") {
+            match stream.read(&mut byte).await {
+                Ok(0) | Err(_) => break,
+                Ok(_) => line.push(byte[0]),
+            }
+        }
+    })
+    .await;
+    String::from_utf8_lossy(&line).trim_end().to_string()
+}
+
+// レスポンスを Content-Length の分か、閉じられるまで読む（途中で切られても、それまでに届いた分を返す）
+#[allow(dead_code)]
+pub async fn read_response<S: AsyncRead + Unpin>(stream: &mut S) -> String {
+    let mut received = Vec::new();
+    let mut buf = vec![0u8; 4096];
+    let _ = timeout(Duration::from_secs(10), async {
+        loop {
+            match stream.read(&mut buf).await {
+                Ok(0) | Err(_) => break,
+                Ok(n) => received.extend_from_slice(&buf[..n]),
+            }
+            let text = String::from_utf8_lossy(&received);
+            if let Some((head, body)) = text.split_once("\r\n\r\n") {
+                let length = head
+                    .lines()
+                    .find_map(|l| l.to_ascii_lowercase().strip_prefix("content-length:").map(|v| v.trim().to_string()))
+                    .and_then(|v| v.parse::<usize>().ok());
+                if length.is_some_and(|length| body.len() >= length) {
+                    break;
+                }
+            }
+        }
+    })
+    .await;
+    String::from_utf8_lossy(&received).to_string()
+}
@@ -0,0 +1,70 @@
+// csp = "rewrite" で、書き換えたインラインスクリプトのうち元のポリシーが許可していたものにだけ nonce が付くことを確認する
+
+mod common;
+
+use common::{read_head, read_response, target_toml, Router};
+use tokio::{
+    io::AsyncWriteExt,
+    net::{TcpListener, TcpStream},
+};
+
+// sha256 のハッシュで許可されたスクリプト（'self' だけでは止められる）
+const ALLOWED: &str = "fetch('/api/a')";
+const ALLOWED_HASH: &str = "'sha256-Iy1oXJU/Il9BoGxlvT3JemN/PhcD20R7HudC9qvF3EE='";
+// ポリシーが許可していないスクリプト
+const BLOCKED: &str = "fetch('/api/b')";
+
+// 指定したCSPで2つのインラインスクリプトを含むHTMLを返すバックエンドの代役
+async fn spawn_html_stand_in(policy: String) -> u16 {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let policy = policy.clone();
+            tokio::spawn(async move {
+                if read_head(&mut stream).await.is_none() {
+                    return;
+                }
+                let body = format!("<html><head></head><body><script>{}</script><script>{}</script></body></html>", ALLOWED, BLOCKED);
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: text/html\r\nContent-Security-Policy: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    policy,
+                    body.len(),
+                    body
+                );
+                let _ = stream.write_all(response.as_bytes()).await;
+            });
+        }
+    });
+    port
+}
+
+async fn get(port: u16) -> String {
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+    let request = format!("GET /proxy/app/index.html HTTP/1.1\r\nHost: localhost:{}\r\nConnection: close\r\n\r\n", port);
+    stream.write_all(request.as_bytes()).await.unwrap();
+    read_response(&mut stream).await
+}
+
+#[tokio::test]
+async fn nonce_is_added_only_to_scripts_the_original_policy_allowed() {
+    let upstream = spawn_html_stand_in(format!("script-src 'self' {}", ALLOWED_HASH)).await;
+    let router = Router::start(&target_toml("app", upstream, "csp = \"rewrite\"")).await;
+
+    let response = get(router.port).await;
+    let allowed = response.find("fetch('/proxy/app/api/a')").unwrap();
+    let blocked = response.find("fetch('/proxy/app/api/b')").unwrap();
+    assert!(response[..allowed].contains("<script nonce="), "{}", response);
+    assert!(response[allowed..blocked].ends_with("</script><script>"), "{}", response);
+}
+
+#[tokio::test]
+async fn inline_scripts_stay_blocked_without_hash_or_unsafe_inline() {
+    let upstream = spawn_html_stand_in("script-src 'self'".to_string()).await;
+    let router = Router::start(&target_toml("app", upstream, "csp = \"rewrite\"")).await;
+
+    let response = get(router.port).await;
+    assert!(response.contains("fetch('/proxy/app/api/a')"), "{}", response);
+    assert!(!response.contains("<script nonce="), "{}", response);
+}
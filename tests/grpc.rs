@@ -0,0 +1,89 @@
+// HTTP/2 のクライアントから h2c = true のバックエンドへ、gRPC のトレーラーがそのまま届くことを確認する
+
+mod common;
+
+use axum::body::Bytes;
+use common::{target_toml, Router};
+use http_body_util::{BodyExt, Full};
+use hyper::{
+    body::{Body, Frame, Incoming},
+    header::HeaderValue,
+    service::service_fn,
+    HeaderMap, Request, Response,
+};
+use hyper_util::{
+    client::legacy::Client,
+    rt::{TokioExecutor, TokioIo},
+};
+use std::{
+    collections::VecDeque,
+    convert::Infallible,
+    pin::Pin,
+    task::{Context, Poll},
+};
+use tokio::net::TcpListener;
+
+// 決まったフレームを順に返すボディ（データの後にトレーラー）
+struct Frames(VecDeque<Frame<Bytes>>);
+
+impl Body for Frames {
+    type Data = Bytes;
+    type Error = Infallible;
+
+    fn poll_frame(mut self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Option<Result<Frame<Bytes>, Infallible>>> {
+        Poll::Ready(self.0.pop_front().map(Ok))
+    }
+}
+
+// gRPC のサーバーの代役（h2c）。受け取ったメッセージをそのまま返し、grpc-status をトレーラーで付ける
+async fn spawn_grpc_stand_in() -> u16 {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            tokio::spawn(async move {
+                let service = service_fn(|req: Request<Incoming>| async move {
+                    let message = req.into_body().collect().await.unwrap().to_bytes();
+                    let mut trailers = HeaderMap::new();
+                    trailers.insert("grpc-status", HeaderValue::from_static("0"));
+                    trailers.insert("grpc-message", HeaderValue::from_static("ok"));
+                    let body = Frames(VecDeque::from([Frame::data(message), Frame::trailers(trailers)]));
+                    let response = Response::builder()
+                        .header("content-type", "application/grpc")
+                        .body(body)
+                        .unwrap();
+                    Ok::<_, Infallible>(response)
+                });
+                let _ = hyper::server::conn::http2::Builder::new(TokioExecutor::new())
+                    .serve_connection(TokioIo::new(stream), service)
+                    .await;
+            });
+        }
+    });
+    port
+}
+
+#[tokio::test]
+async fn grpc_trailers_are_forwarded_over_http2() {
+    let upstream = spawn_grpc_stand_in().await;
+    let router = Router::start(&target_toml("grpc", upstream, "h2c = true")).await;
+
+    // 集約ポートへ h2c（prior knowledge）で接続する
+    let client = Client::builder(TokioExecutor::new()).http2_only(true).build_http::<Full<Bytes>>();
+    let message = Bytes::from_static(b"\x00\x00\x00\x00\x05hello");
+    let request = Request::post(format!("http://127.0.0.1:{}/proxy/grpc/echo.Echo/Say", router.port))
+        .header("content-type", "application/grpc")
+        .header("te", "trailers")
+        .body(Full::new(message.clone()))
+        .unwrap();
+    let response = client.request(request).await.unwrap();
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.version(), hyper::Version::HTTP_2);
+
+    let collected = response.into_body().collect().await.unwrap();
+    let trailers = collected.trailers().cloned().expect("トレーラーが届いていません");
+    assert_eq!(trailers.get("grpc-status").unwrap(), "0");
+    assert_eq!(trailers.get("grpc-message").unwrap(), "ok");
+    assert_eq!(collected.to_bytes(), message);
+}
@@ -0,0 +1,56 @@
+// listen で指定した複数のアドレスと Unix ドメインソケットで待ち受けること、
+// ポートが使われているときに使っているプロセスを示して終了することを確認する
+
+#![cfg(unix)]
+
+mod common;
+
+use common::{free_port, read_status_line, Router};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, AsyncWriteExt},
+    net::{TcpStream, UnixStream},
+};
+
+// 選択画面を要求し、ステータス行を返す
+async fn status_line<S: AsyncRead + AsyncWrite + Unpin>(mut stream: S) -> String {
+    stream
+        .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+        .await
+        .unwrap();
+    read_status_line(&mut stream).await
+}
+
+#[tokio::test]
+async fn serves_on_every_listen_address() {
+    let extra = free_port();
+    let router = Router::start(&format!(
+        "listen = [\"127.0.0.1\", \"127.0.0.1:{}\", \"./portrooter.sock\"]\n\n[[targets]]\nname = \"web\"\nport = {}\ndescription = \"未起動のターゲット\"\n",
+        extra,
+        free_port()
+    ))
+    .await;
+
+    let main = TcpStream::connect(("127.0.0.1", router.port)).await.unwrap();
+    assert_eq!(status_line(main).await, "HTTP/1.1 200 OK");
+    let second = TcpStream::connect(("127.0.0.1", extra)).await.unwrap();
+    assert_eq!(status_line(second).await, "HTTP/1.1 200 OK");
+    let unix = UnixStream::connect(router.dir.join("portrooter.sock")).await.unwrap();
+    assert_eq!(status_line(unix).await, "HTTP/1.1 200 OK");
+}
+
+#[tokio::test]
+async fn port_in_use_names_the_holder() {
+    let taken = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = taken.local_addr().unwrap().port();
+    let (code, output) = Router::start_and_fail(&format!(
+        "listen = [\"127.0.0.1:{}\"]\n\n[[targets]]\nname = \"web\"\nport = {}\ndescription = \"未起動のターゲット\"\n",
+        port,
+        free_port()
+    ))
+    .await;
+
+    assert_eq!(code, Some(2), "{}", output);
+    assert!(output.contains("はすでに使われています"), "{}", output);
+    assert!(output.contains(&format!("pid {} ", std::process::id())), "{}", output);
+    drop(taken);
+}
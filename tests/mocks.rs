@@ -0,0 +1,42 @@
+// モックのボディに埋め込むパス・クエリの値が、ボディの種類に合わせてエスケープされることを確認する
+
+mod common;
+
+use common::{free_port, read_response, target_toml, Router};
+use tokio::{io::AsyncWriteExt, net::TcpStream};
+
+const MOCKS: &str = r#"[[mocks]]
+path = "/users/:name"
+body = '{"name": "{{name}}", "raw": {{{query.raw}}}}'
+
+[[mocks]]
+path = "/search"
+headers = { "content-type" = "text/html; charset=utf-8" }
+body = '<p title="{{query.q}}">{{query.q}}</p>'
+"#;
+
+async fn body(port: u16, path: &str) -> String {
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+    let request = format!("GET {} HTTP/1.1\r\nHost: localhost:{}\r\nConnection: close\r\n\r\n", path, port);
+    stream.write_all(request.as_bytes()).await.unwrap();
+    let response = read_response(&mut stream).await;
+    response.split_once("\r\n\r\n").unwrap().1.to_string()
+}
+
+#[tokio::test]
+async fn template_values_are_escaped_for_the_content_type() {
+    let router = Router::start(&format!("{}\n{}", MOCKS, target_toml("api", free_port(), ""))).await;
+
+    // JSON の文字列として壊れない（{{{...}}} はそのまま埋め込む）
+    let json = body(router.port, "/users/a%22b%5C?raw=%5B1%2C2%5D").await;
+    let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+    assert_eq!(value["name"], "a\"b\\");
+    assert_eq!(value["raw"], serde_json::json!([1, 2]));
+
+    // HTML ではタグや属性を閉じられない
+    let html = body(router.port, "/search?q=%22%3E%3Cscript%3Ealert(1)%3C%2Fscript%3E").await;
+    assert_eq!(
+        html,
+        "<p title=\"&quot;&gt;&lt;script&gt;alert(1)&lt;/script&gt;\">&quot;&gt;&lt;script&gt;alert(1)&lt;/script&gt;</p>"
+    );
+}
@@ -0,0 +1,97 @@
+// 再利用した接続がバックエンド側で閉じられていても冪等なリクエストは送り直されること、
+// keep_alive = false のターゲットでは接続を使い回さないことを確認する
+
+mod common;
+
+use common::{read_head, read_status_line, target_toml, Router};
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc, Mutex,
+};
+use tokio::{
+    io::AsyncWriteExt,
+    net::{TcpListener, TcpStream},
+};
+
+#[derive(Default)]
+struct Seen {
+    // リクエストを受け取った接続の数
+    connections: AtomicUsize,
+    // 受け取ったリクエストのヘッダー（小文字）
+    heads: Mutex<Vec<String>>,
+}
+
+// 接続ごとに最初のリクエストにだけ keep-alive で答え、同じ接続の2つ目のリクエストは答えずに閉じる
+// （再起動直後の開発サーバーのように、使い回した接続が途中で切れる状況を作る）
+async fn spawn_flaky_stand_in() -> (u16, Arc<Seen>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let seen = Arc::new(Seen::default());
+    let shared = seen.clone();
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let seen = shared.clone();
+            tokio::spawn(async move {
+                let Some(head) = read_head(&mut stream).await else {
+                    return;
+                };
+                seen.connections.fetch_add(1, Ordering::Relaxed);
+                seen.heads.lock().unwrap().push(head.to_ascii_lowercase());
+                let _ = stream
+                    .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: keep-alive\r\n\r\nok")
+                    .await;
+                if let Some(head) = read_head(&mut stream).await {
+                    seen.heads.lock().unwrap().push(head.to_ascii_lowercase());
+                }
+            });
+        }
+    });
+    (port, seen)
+}
+
+async fn request(port: u16, method: &str) -> String {
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+    // Connection: close を付けるとバックエンドにも転送されてしまうので、ステータス行だけ読む
+    let request = format!(
+        "{} /proxy/flaky/items HTTP/1.1\r\nHost: localhost:{}\r\nContent-Length: 0\r\n\r\n",
+        method, port
+    );
+    stream.write_all(request.as_bytes()).await.unwrap();
+    read_status_line(&mut stream).await
+}
+
+#[tokio::test]
+async fn idempotent_request_is_retried_on_stale_connection() {
+    let (upstream, seen) = spawn_flaky_stand_in().await;
+    let router = Router::start(&target_toml("flaky", upstream, "")).await;
+
+    assert_eq!(request(router.port, "GET").await, "HTTP/1.1 200 OK");
+    // 2つ目は使い回した接続で失敗し、新しい接続で送り直される
+    assert_eq!(request(router.port, "GET").await, "HTTP/1.1 200 OK");
+    assert_eq!(seen.connections.load(Ordering::Relaxed), 2);
+    assert_eq!(seen.heads.lock().unwrap().len(), 3);
+}
+
+#[tokio::test]
+async fn non_idempotent_request_is_not_retried() {
+    let (upstream, seen) = spawn_flaky_stand_in().await;
+    let router = Router::start(&target_toml("flaky", upstream, "")).await;
+
+    assert_eq!(request(router.port, "GET").await, "HTTP/1.1 200 OK");
+    assert_eq!(request(router.port, "POST").await, "HTTP/1.1 502 Bad Gateway");
+    assert_eq!(seen.connections.load(Ordering::Relaxed), 1);
+}
+
+#[tokio::test]
+async fn keep_alive_off_uses_a_new_connection_per_request() {
+    let (upstream, seen) = spawn_flaky_stand_in().await;
+    let router = Router::start(&target_toml("flaky", upstream, "[targets.pool]\nkeep_alive = false")).await;
+
+    for _ in 0..3 {
+        assert_eq!(request(router.port, "POST").await, "HTTP/1.1 200 OK");
+    }
+    assert_eq!(seen.connections.load(Ordering::Relaxed), 3);
+    let heads = seen.heads.lock().unwrap();
+    assert!(heads.iter().all(|head| head.contains("connection: close")), "{:?}", heads);
+}
@@ -0,0 +1,72 @@
+// [[routes]] の prefix と strip_prefix がパスのセグメント単位で働くこと、
+// X-Forwarded-Prefix は実際に取り除いたときだけ付くことを確認する
+
+mod common;
+
+use common::{read_head, read_response, target_toml, Router};
+use tokio::{
+    io::AsyncWriteExt,
+    net::{TcpListener, TcpStream},
+};
+
+// 受け取ったリクエストのパスと X-Forwarded-Prefix を本文で返すバックエンドの代役
+async fn spawn_echo_stand_in() -> u16 {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            tokio::spawn(async move {
+                let Some(head) = read_head(&mut stream).await else {
+                    return;
+                };
+                let path = head.split(' ').nth(1).unwrap_or_default().to_string();
+                let prefix = head
+                    .lines()
+                    .find_map(|l| l.to_ascii_lowercase().strip_prefix("x-forwarded-prefix:").map(|v| v.trim().to_string()))
+                    .unwrap_or_default();
+                let body = format!("prefix={} path={}", prefix, path);
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                );
+                let _ = stream.write_all(response.as_bytes()).await;
+            });
+        }
+    });
+    port
+}
+
+async fn get(port: u16, path: &str) -> String {
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+    let request = format!("GET {} HTTP/1.1\r\nHost: localhost:{}\r\nConnection: close\r\n\r\n", path, port);
+    stream.write_all(request.as_bytes()).await.unwrap();
+    read_response(&mut stream).await
+}
+
+#[tokio::test]
+async fn prefix_matches_whole_segments_only() {
+    let upstream = spawn_echo_stand_in().await;
+    let routes = "[[routes]]\nprefix = \"/api\"\nstrip_prefix = \"/api\"\ntarget = \"echo\"\n";
+    let router = Router::start(&format!("{}\n{}", routes, target_toml("echo", upstream, ""))).await;
+
+    assert!(get(router.port, "/api/users").await.ends_with("path=/users"));
+    assert!(get(router.port, "/api").await.ends_with("path=/"));
+    // セグメントの途中では一致しないので、ルールを通らない
+    let response = get(router.port, "/apiary").await;
+    assert!(!response.contains("path="), "{}", response);
+    let response = get(router.port, "/apiv2/x").await;
+    assert!(!response.contains("path="), "{}", response);
+}
+
+#[tokio::test]
+async fn forwarded_prefix_is_sent_only_when_stripped() {
+    let upstream = spawn_echo_stand_in().await;
+    let routes = "[[routes]]\nregex = \"^/api\"\nstrip_prefix = \"/api\"\ntarget = \"echo\"\n";
+    let router = Router::start(&format!("{}\n{}", routes, target_toml("echo", upstream, ""))).await;
+
+    assert!(get(router.port, "/api/users").await.ends_with("prefix=/api path=/users"));
+    // /apiary は regex には一致するが、strip_prefix はセグメントの途中なので取り除かない
+    assert!(get(router.port, "/apiary").await.ends_with("prefix= path=/apiary"));
+}
@@ -0,0 +1,146 @@
+// SIGTERM で処理中のリクエストを終えてから終了すること、WebSocket にクローズフレームを送ることを確認する
+
+#![cfg(unix)]
+
+mod common;
+
+use common::{read_head, read_response, target_toml, Router};
+use std::time::Duration;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::mpsc,
+    time::{sleep, timeout},
+};
+
+// リクエストを受け取ったら通知し、少し待ってから応答するバックエンドの代役
+async fn spawn_slow_stand_in(delay: Duration) -> (u16, mpsc::UnboundedReceiver<()>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let (tx, rx) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let tx = tx.clone();
+            tokio::spawn(async move {
+                let _ = read_head(&mut stream).await;
+                let _ = tx.send(());
+                sleep(delay).await;
+                let _ = stream
+                    .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 4\r\nConnection: close\r\n\r\ndone")
+                    .await;
+            });
+        }
+    });
+    (port, rx)
+}
+
+// ハンドシェイクに 101 で答え、その後に届いたバイト列を返す WebSocket サーバーの代役
+async fn spawn_websocket_stand_in() -> (u16, mpsc::UnboundedReceiver<Vec<u8>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let (tx, rx) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let tx = tx.clone();
+            tokio::spawn(async move {
+                // ヘルスチェックなどのアップグレードしないリクエストは無視する
+                let head = read_head(&mut stream).await.unwrap_or_default();
+                if !head.to_ascii_lowercase().contains("upgrade: websocket") {
+                    return;
+                }
+                stream
+                    .write_all(b"HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\r\n")
+                    .await
+                    .unwrap();
+                let mut received = Vec::new();
+                let mut buf = [0u8; 1024];
+                while let Ok(n) = stream.read(&mut buf).await {
+                    if n == 0 {
+                        break;
+                    }
+                    received.extend_from_slice(&buf[..n]);
+                    let _ = tx.send(received.clone());
+                }
+            });
+        }
+    });
+    (port, rx)
+}
+
+fn config_toml(port: u16) -> String {
+    format!("[shutdown]\ndrain_timeout_secs = 5\n\n{}", target_toml("backend", port, ""))
+}
+
+#[tokio::test]
+async fn in_flight_request_completes_after_sigterm() {
+    let (upstream, mut arrived) = spawn_slow_stand_in(Duration::from_millis(800)).await;
+    let mut router = Router::start(&config_toml(upstream)).await;
+
+    let mut stream = TcpStream::connect(("127.0.0.1", router.port)).await.unwrap();
+    let request = format!("GET /proxy/backend/slow HTTP/1.1\r\nHost: localhost:{}\r\n\r\n", router.port);
+    stream.write_all(request.as_bytes()).await.unwrap();
+    timeout(Duration::from_secs(5), arrived.recv()).await.unwrap();
+
+    router.signal("TERM");
+    sleep(Duration::from_millis(200)).await;
+    assert!(
+        TcpStream::connect(("127.0.0.1", router.port)).await.is_err(),
+        "シャットダウン中に新しい接続を受け付けました"
+    );
+
+    let response = read_response(&mut stream).await;
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+    assert!(response.ends_with("done"), "{}", response);
+    assert_eq!(router.exit_code(Duration::from_secs(5)).await, Some(0));
+}
+
+#[tokio::test]
+async fn websocket_receives_close_frame_on_shutdown() {
+    let (upstream, mut backend_received) = spawn_websocket_stand_in().await;
+    let mut router = Router::start(&config_toml(upstream)).await;
+
+    let mut stream = TcpStream::connect(("127.0.0.1", router.port)).await.unwrap();
+    let handshake = format!(
+        "GET /proxy/backend/socket HTTP/1.1\r\nHost: localhost:{}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n",
+        router.port
+    );
+    stream.write_all(handshake.as_bytes()).await.unwrap();
+    let head = timeout(Duration::from_secs(5), read_head(&mut stream)).await.unwrap().unwrap_or_default();
+    assert!(head.starts_with("HTTP/1.1 101"), "{}", head);
+
+    // 中継されていることを確かめる（マスクした "hi" のテキストフレーム）
+    let key = [1u8, 2, 3, 4];
+    let frame = [&[0x81, 0x82][..], &key, &[b'h' ^ key[0], b'i' ^ key[1]]].concat();
+    stream.write_all(&frame).await.unwrap();
+    let relayed = timeout(Duration::from_secs(5), backend_received.recv()).await.unwrap().unwrap();
+    assert_eq!(relayed, frame);
+
+    router.signal("TERM");
+
+    // クライアントには 1001 Going Away のクローズフレームが届く（サーバーからのフレームはマスクしない）
+    let mut close = [0u8; 4];
+    timeout(Duration::from_secs(5), stream.read_exact(&mut close)).await.unwrap().unwrap();
+    assert_eq!(close[0], 0x88);
+    assert_eq!(close[1] & 0x80, 0);
+    assert_eq!(u16::from_be_bytes([close[2], close[3]]), 1001);
+    let reply = [&[0x88, 0x82][..], &key, &[0x03 ^ key[0], 0xe9 ^ key[1]]].concat();
+    let _ = stream.write_all(&reply).await;
+
+    // バックエンドにもマスクしたクローズフレームが届く
+    let mut received = relayed.clone();
+    while received.len() < frame.len() + 8 {
+        match timeout(Duration::from_secs(5), backend_received.recv()).await {
+            Ok(Some(bytes)) => received = bytes,
+            _ => break,
+        }
+    }
+    let to_backend = &received[frame.len()..];
+    assert_eq!(to_backend[0], 0x88);
+    assert_eq!(to_backend[1] & 0x80, 0x80);
+    let mask = &to_backend[2..6];
+    assert_eq!(u16::from_be_bytes([to_backend[6] ^ mask[0], to_backend[7] ^ mask[1]]), 1001);
+
+    assert_eq!(router.exit_code(Duration::from_secs(5)).await, Some(0));
+}
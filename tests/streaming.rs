@@ -0,0 +1,88 @@
+// SSEなどのストリーミングレスポンスがバッファリングされずに届くことを確認する
+
+mod common;
+
+use common::{read_head, target_toml, Router};
+use std::time::Duration;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    time::{sleep, timeout},
+};
+
+// 最初のイベントを送ったあと接続を開いたままにするSSEサーバーの代役
+async fn spawn_sse_stand_in(extra_headers: &'static str) -> u16 {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            tokio::spawn(async move {
+                if read_head(&mut stream).await.is_none() {
+                    return;
+                }
+                let event = "data: hello\n\n";
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\n{}Transfer-Encoding: chunked\r\n\r\n{:x}\r\n{}\r\n",
+                    extra_headers,
+                    event.len(),
+                    event
+                );
+                stream.write_all(response.as_bytes()).await.unwrap();
+                // ストリームは終わらせない
+                sleep(Duration::from_secs(30)).await;
+            });
+        }
+    });
+    port
+}
+
+// ルーター経由でリクエストし、最初のイベントが届くまで読み続ける
+async fn read_first_event(router_port: u16, path: &str, accept: &str) -> String {
+    let mut stream = TcpStream::connect(("127.0.0.1", router_port)).await.unwrap();
+    let request = format!(
+        "GET {} HTTP/1.1\r\nHost: localhost:{}\r\nAccept: {}\r\n\r\n",
+        path, router_port, accept
+    );
+    stream.write_all(request.as_bytes()).await.unwrap();
+
+    let mut received = Vec::new();
+    let mut buf = vec![0u8; 4096];
+    let result = timeout(Duration::from_secs(5), async {
+        while !String::from_utf8_lossy(&received).contains("data: hello") {
+            let n = stream.read(&mut buf).await.unwrap();
+            assert!(n > 0, "イベントの前に接続が閉じられました");
+            received.extend_from_slice(&buf[..n]);
+        }
+    })
+    .await;
+    assert!(result.is_ok(), "イベントが届きません（バッファリングされています）");
+    String::from_utf8_lossy(&received).to_string()
+}
+
+#[tokio::test]
+async fn event_stream_on_js_path_is_not_buffered() {
+    let upstream = spawn_sse_stand_in("Content-Type: text/event-stream\r\n").await;
+    let router = Router::start(&target_toml("sse", upstream, "")).await;
+
+    let response = read_first_event(router.port, "/proxy/sse/events.js", "*/*").await;
+    assert!(response.starts_with("HTTP/1.1 200"));
+}
+
+#[tokio::test]
+async fn no_transform_html_is_not_buffered() {
+    let upstream =
+        spawn_sse_stand_in("Content-Type: text/html\r\nCache-Control: no-cache, no-transform\r\n").await;
+    let router = Router::start(&target_toml("sse", upstream, "")).await;
+
+    read_first_event(router.port, "/proxy/sse/stream", "*/*").await;
+}
+
+#[tokio::test]
+async fn event_stream_accept_header_is_not_buffered() {
+    // Content-Typeが間違っていても、クライアントがSSEを要求していればそのまま流す
+    let upstream = spawn_sse_stand_in("Content-Type: application/javascript\r\n").await;
+    let router = Router::start(&target_toml("sse", upstream, "")).await;
+
+    read_first_event(router.port, "/proxy/sse/stream", "text/event-stream").await;
+}
@@ -0,0 +1,192 @@
+// 大きなリクエストボディがバッファリングされずにバックエンドへ流れること、上限を超えると 413 になることを確認する
+
+mod common;
+
+use common::{read_response, target_toml, Router};
+use std::time::Duration;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+    sync::mpsc,
+    time::timeout,
+};
+
+const MIB: usize = 1024 * 1024;
+
+// 受け取ったボディのバイト数を返すアップロード先の代役。ボディの最初のデータが届いたら通知する
+async fn spawn_upload_stand_in() -> (u16, mpsc::UnboundedReceiver<()>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let (tx, rx) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            let tx = tx.clone();
+            tokio::spawn(async move {
+                let mut stream = BufReader::new(stream);
+                let mut content_length = None;
+                let mut chunked = false;
+                loop {
+                    let mut line = String::new();
+                    if stream.read_line(&mut line).await.unwrap_or(0) == 0 {
+                        return;
+                    }
+                    let line = line.trim_end().to_ascii_lowercase();
+                    if line.is_empty() {
+                        break;
+                    }
+                    if let Some(value) = line.strip_prefix("content-length:") {
+                        content_length = value.trim().parse::<usize>().ok();
+                    }
+                    if line.starts_with("transfer-encoding:") && line.contains("chunked") {
+                        chunked = true;
+                    }
+                }
+
+                // ボディは保持せずに数えるだけ
+                let mut received = 0;
+                let mut buf = vec![0u8; 64 * 1024];
+                let mut notified = false;
+                let mut consume = |n: usize, received: &mut usize| {
+                    *received += n;
+                    if n > 0 && !notified {
+                        notified = true;
+                        let _ = tx.send(());
+                    }
+                };
+                if chunked {
+                    loop {
+                        let mut size = String::new();
+                        if stream.read_line(&mut size).await.unwrap_or(0) == 0 {
+                            return;
+                        }
+                        let Ok(mut remaining) = usize::from_str_radix(size.trim(), 16) else {
+                            return;
+                        };
+                        if remaining == 0 {
+                            let mut end = String::new();
+                            let _ = stream.read_line(&mut end).await;
+                            break;
+                        }
+                        while remaining > 0 {
+                            let n = match stream.read(&mut buf[..remaining.min(64 * 1024)]).await {
+                                Ok(0) | Err(_) => return,
+                                Ok(n) => n,
+                            };
+                            consume(n, &mut received);
+                            remaining -= n;
+                        }
+                        let mut crlf = String::new();
+                        let _ = stream.read_line(&mut crlf).await;
+                    }
+                } else {
+                    let mut remaining = content_length.unwrap_or(0);
+                    while remaining > 0 {
+                        let n = match stream.read(&mut buf[..remaining.min(64 * 1024)]).await {
+                            Ok(0) | Err(_) => return,
+                            Ok(n) => n,
+                        };
+                        consume(n, &mut received);
+                        remaining -= n;
+                    }
+                }
+
+                let body = format!("received {}", received);
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: {}\r\n\r\n{}",
+                    body.len(),
+                    body
+                );
+                let _ = stream.get_mut().write_all(response.as_bytes()).await;
+            });
+        }
+    });
+    (port, rx)
+}
+
+#[tokio::test]
+async fn large_upload_is_streamed_to_backend() {
+    let (upstream, mut arrived) = spawn_upload_stand_in().await;
+    let router = Router::start(&target_toml("upload", upstream, "max_request_body_kb = 1048576")).await;
+
+    let total = 256 * MIB;
+    let chunk = vec![b'x'; MIB];
+    let mut stream = TcpStream::connect(("127.0.0.1", router.port)).await.unwrap();
+    let head = format!(
+        "POST /proxy/upload/files HTTP/1.1\r\nHost: localhost:{}\r\nContent-Type: application/octet-stream\r\nContent-Length: {}\r\n\r\n",
+        router.port, total
+    );
+    stream.write_all(head.as_bytes()).await.unwrap();
+    stream.write_all(&chunk).await.unwrap();
+
+    // 残りを送る前にバックエンドへ届いていれば、ボディを集めずに流している
+    let first = timeout(Duration::from_secs(5), arrived.recv()).await;
+    assert!(first.is_ok(), "ボディの先頭がバックエンドに届きません（バッファリングされています）");
+
+    for _ in 1..total / MIB {
+        stream.write_all(&chunk).await.unwrap();
+    }
+    let response = read_response(&mut stream).await;
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+    assert!(response.ends_with(&format!("received {}", total)), "{}", response);
+}
+
+#[tokio::test]
+async fn content_length_over_target_limit_is_rejected_before_sending() {
+    let (upstream, mut arrived) = spawn_upload_stand_in().await;
+    let router = Router::start(&target_toml("upload", upstream, "max_request_body_kb = 1024")).await;
+
+    let mut stream = TcpStream::connect(("127.0.0.1", router.port)).await.unwrap();
+    let head = format!(
+        "POST /proxy/upload/files HTTP/1.1\r\nHost: localhost:{}\r\nContent-Length: {}\r\n\r\n",
+        router.port,
+        2 * MIB
+    );
+    stream.write_all(head.as_bytes()).await.unwrap();
+
+    let response = read_response(&mut stream).await;
+    assert!(response.starts_with("HTTP/1.1 413"), "{}", response);
+    assert!(response.contains("max_request_body_kb"), "{}", response);
+    assert!(arrived.try_recv().is_err(), "上限を超えたボディがバックエンドに届きました");
+}
+
+#[tokio::test]
+async fn chunked_upload_over_route_limit_is_rejected() {
+    let (upstream, _arrived) = spawn_upload_stand_in().await;
+    let routes = "[[routes]]\nprefix = \"/upload\"\ntarget = \"upload\"\nmax_request_body_kb = 64\n";
+    let router = Router::start(&format!("{}\n{}", routes, target_toml("upload", upstream, ""))).await;
+
+    let mut stream = TcpStream::connect(("127.0.0.1", router.port)).await.unwrap();
+    let head = format!(
+        "POST /upload/files HTTP/1.1\r\nHost: localhost:{}\r\nTransfer-Encoding: chunked\r\n\r\n",
+        router.port
+    );
+    stream.write_all(head.as_bytes()).await.unwrap();
+    let (mut reader, mut writer) = stream.into_split();
+    // 上限で打ち切られると書き込みが失敗するため、送信と受信を並行させる
+    let sender = tokio::spawn(async move {
+        let chunk = vec![b'y'; 16 * 1024];
+        for _ in 0..16 {
+            let frame = [format!("{:x}\r\n", chunk.len()).as_bytes(), &chunk, b"\r\n"].concat();
+            if writer.write_all(&frame).await.is_err() {
+                return;
+            }
+        }
+        let _ = writer.write_all(b"0\r\n\r\n").await;
+    });
+
+    let mut received = Vec::new();
+    let mut buf = vec![0u8; 4096];
+    let _ = timeout(Duration::from_secs(10), async {
+        while !String::from_utf8_lossy(&received).contains("max_request_body_kb") {
+            match reader.read(&mut buf).await {
+                Ok(0) | Err(_) => break,
+                Ok(n) => received.extend_from_slice(&buf[..n]),
+            }
+        }
+    })
+    .await;
+    let _ = sender.await;
+    let response = String::from_utf8_lossy(&received);
+    assert!(response.starts_with("HTTP/1.1 413"), "{}", response);
+}